@@ -4,12 +4,12 @@ use std::ffi::CString;
 use log::warn;
 use sgx_types::*;
 
-use crate::enclave::ecall::api::ecall_api_server_start;
+use crate::enclave::ecall::api::{ecall_api_server_shutdown, ecall_api_server_start, ecall_reload_tls};
 use crate::ENCLAVE_DOORBELL;
 
 const THREAD_NUM: u8 = 8;
 
-pub(crate) fn start_api_service(addr: String) {
+pub(crate) fn start_api_service(addr: String, cert_path: String, key_path: String) {
     let mut children = vec![];
     let thread_count = std::cmp::min(std::cmp::min(THREAD_NUM,
                                                    ENCLAVE_DOORBELL.capacity()),
@@ -17,17 +17,38 @@ pub(crate) fn start_api_service(addr: String) {
 
     for _ in 0..thread_count {
         let addr = addr.clone();
+        let cert_path = cert_path.clone();
+        let key_path = key_path.clone();
 
         children.push(thread::spawn(move || {
-            let enclave_access_token = ENCLAVE_DOORBELL
-                .get_access(false) // This can never be recursive
-                .unwrap();
-            let enclave = enclave_access_token.unwrap();
+            // The listener lives inside the enclave, so a worker that can't get
+            // a TCS slot just doesn't serve. Other workers keep accepting.
+            let enclave_access_token = match ENCLAVE_DOORBELL
+                .get_access(false) { // This can never be recursive
+                Some(token) => token,
+                None => {
+                    warn!("enclave doorbell saturated, API worker not started");
+                    return;
+                }
+            };
+            let enclave = match *enclave_access_token {
+                Ok(enclave) => enclave,
+                Err(err) => {
+                    warn!("enclave unavailable, API worker not started: {}", err);
+                    return;
+                }
+            };
 
             let c_addr: CString = CString::new(addr).unwrap();
+            let c_cert_path: CString = CString::new(cert_path).unwrap();
+            let c_key_path: CString = CString::new(key_path).unwrap();
+            let mut retval = sgx_status_t::SGX_SUCCESS;
             let result = unsafe {
                 ecall_api_server_start(enclave.geteid(),
-                                       c_addr.as_bytes_with_nul().as_ptr() as *const c_char)
+                                       &mut retval,
+                                       c_addr.as_bytes_with_nul().as_ptr() as *const c_char,
+                                       c_cert_path.as_bytes_with_nul().as_ptr() as *const c_char,
+                                       c_key_path.as_bytes_with_nul().as_ptr() as *const c_char)
             };
 
             match result {
@@ -37,6 +58,10 @@ pub(crate) fn start_api_service(addr: String) {
                     return;
                 }
             }
+
+            if retval != sgx_status_t::SGX_SUCCESS {
+                warn!("ECALL [ecall_api_service_start] returned {}!", retval);
+            }
         }));
     }
 
@@ -44,4 +69,68 @@ pub(crate) fn start_api_service(addr: String) {
         // Wait for the thread to finish. Returns a result.
         let _ = child.join();
     }
-}
\ No newline at end of file
+}
+
+/// Swaps the TLS certificate/key used for new connections on all API servers.
+/// Enters through the doorbell's control slot, as `shutdown_api_service`.
+pub(crate) fn reload_tls(cert_path: String, key_path: String) -> Result<(), sgx_status_t> {
+    let enclave_access_token = match ENCLAVE_DOORBELL.get_control_access() {
+        Some(token) => token,
+        None => {
+            warn!("enclave doorbell saturated, cannot reload TLS");
+            return Err(sgx_status_t::SGX_ERROR_OUT_OF_TCS);
+        }
+    };
+    let enclave = (*enclave_access_token)?;
+
+    let c_cert_path: CString = CString::new(cert_path).unwrap();
+    let c_key_path: CString = CString::new(key_path).unwrap();
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        ecall_reload_tls(enclave.geteid(),
+                         &mut retval,
+                         c_cert_path.as_bytes_with_nul().as_ptr() as *const c_char,
+                         c_key_path.as_bytes_with_nul().as_ptr() as *const c_char)
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        warn!("ECALL [ecall_reload_tls] failed {}!", result);
+        return Err(result);
+    }
+    if retval != sgx_status_t::SGX_SUCCESS {
+        warn!("ECALL [ecall_reload_tls] returned {}!", retval);
+        return Err(retval);
+    }
+
+    Ok(())
+}
+
+/// Stops the API servers accepting, they return once in-flight requests have
+/// finished or the drain timeout has passed. Enters through the doorbell's
+/// control slot, the workers hold the others.
+pub(crate) fn shutdown_api_service() -> Result<(), sgx_status_t> {
+    let enclave_access_token = match ENCLAVE_DOORBELL.get_control_access() {
+        Some(token) => token,
+        None => {
+            warn!("enclave doorbell saturated, cannot shut down API service");
+            return Err(sgx_status_t::SGX_ERROR_OUT_OF_TCS);
+        }
+    };
+    let enclave = (*enclave_access_token)?;
+
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let result = unsafe {
+        ecall_api_server_shutdown(enclave.geteid(), &mut retval)
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        warn!("ECALL [ecall_api_server_shutdown] failed {}!", result);
+        return Err(result);
+    }
+    if retval != sgx_status_t::SGX_SUCCESS {
+        warn!("ECALL [ecall_api_server_shutdown] returned {}!", retval);
+        return Err(retval);
+    }
+
+    Ok(())
+}
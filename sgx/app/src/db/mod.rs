@@ -1,5 +1,8 @@
-use super::traits::{Db, Result};
+use std::time::Duration;
 
+use super::traits::{Db, Result, SnapshotHandle};
+
+pub(crate) mod offload;
 pub(crate) mod rocksdb;
 
 lazy_static! {
@@ -23,14 +26,78 @@ impl <D: Db> Db for DbInstance<D> {
         self.db.get(key)
     }
 
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.multi_get(keys)
+    }
+
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
         self.db.put(key, value)
     }
 
+    fn put_durable(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.put_durable(key, value)
+    }
+
+    fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.db.put_with_ttl(key, value, ttl)
+    }
+
+    fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        self.db.compare_and_swap(key, expected, new)
+    }
+
     fn delete(&self, key: &[u8]) -> Result<()> {
         self.db.delete(key)
     }
 
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_cf(cf, key)
+    }
+
+    fn put_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.put_cf(cf, key, value)
+    }
+
+    fn put_durable_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.db.put_durable_cf(cf, key, value)
+    }
+
+    fn compare_and_swap_cf(&self, cf: &str, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        self.db.compare_and_swap_cf(cf, key, expected, new)
+    }
+
+    fn delete_cf(&self, cf: &str, key: &[u8]) -> Result<()> {
+        self.db.delete_cf(cf, key)
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        self.db.count_prefix(prefix)
+    }
+
+    fn count_prefix_cf(&self, cf: &str, prefix: &[u8]) -> Result<u64> {
+        self.db.count_prefix_cf(cf, prefix)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8], from: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.db.scan_prefix(prefix, from, limit)
+    }
+
+    fn snapshot(&self) -> Result<SnapshotHandle> {
+        self.db.snapshot()
+    }
+
+    fn get_at(&self, snap: &SnapshotHandle, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_at(snap, key)
+    }
+
+    fn get_at_cf(&self, snap: &SnapshotHandle, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get_at_cf(snap, cf, key)
+    }
+
+    fn release_snapshot(&self, snap: SnapshotHandle) -> Result<()> {
+        self.db.release_snapshot(snap)
+    }
+
     fn flush(&self) -> Result<()> {
         self.db.flush()
     }
@@ -0,0 +1,125 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Once;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::db::GLOBAL_DB;
+use crate::traits::{Db, Result};
+
+// Host threads doing the reads, they never enter the enclave.
+const OFFLOAD_WORKERS: usize = 4;
+// Submitted but not yet collected, further submits are refused.
+const MAX_PENDING: usize = 1024;
+
+lazy_static! {
+    pub static ref DB_OFFLOAD: DbOffload = DbOffload::new();
+}
+
+static WORKERS_STARTED: Once = Once::new();
+
+pub type Ticket = u64;
+
+pub enum Completion {
+    Pending,
+    Done(Result<Option<Vec<u8>>>),
+}
+
+struct Job {
+    ticket: Ticket,
+    cf: Option<String>,
+    key: Vec<u8>,
+}
+
+/// Runs db reads on a small pool of host threads, so the enclave thread that
+/// asked can keep serving while the read is on disk. The enclave submits a
+/// read, then polls for it by ticket.
+pub struct DbOffload {
+    queue: Mutex<VecDeque<Job>>,
+    queued: Condvar,
+    // `None` until the read completes.
+    results: Mutex<HashMap<Ticket, Option<Result<Option<Vec<u8>>>>>>,
+    next_ticket: AtomicU64,
+}
+
+impl DbOffload {
+    fn new() -> Self {
+        Self {
+            queue: Mutex::new(VecDeque::new()),
+            queued: Condvar::new(),
+            results: Mutex::new(HashMap::new()),
+            next_ticket: AtomicU64::new(1),
+        }
+    }
+
+    /// Queues a get, `None` when too many are already pending.
+    pub fn submit_get(&'static self, cf: Option<&str>, key: &[u8]) -> Option<Ticket> {
+        WORKERS_STARTED.call_once(|| {
+            for _ in 0..OFFLOAD_WORKERS {
+                thread::spawn(move || self.work());
+            }
+        });
+
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut results = self.results.lock();
+            if results.len() >= MAX_PENDING {
+                return None;
+            }
+            results.insert(ticket, None);
+        }
+
+        self.queue.lock().push_back(Job {
+            ticket,
+            cf: cf.map(|cf| cf.to_string()),
+            key: key.to_vec(),
+        });
+        self.queued.notify_one();
+
+        Some(ticket)
+    }
+
+    /// Takes the result once it's there, `None` for an unknown ticket.
+    pub fn take(&self, ticket: Ticket) -> Option<Completion> {
+        let mut results = self.results.lock();
+        match results.get(&ticket) {
+            None => None,
+            Some(None) => Some(Completion::Pending),
+            Some(Some(_)) => results.remove(&ticket)
+                .and_then(|res| res)
+                .map(Completion::Done),
+        }
+    }
+
+    /// Forgets `ticket`, a read still in progress is discarded when it's done.
+    pub fn cancel(&self, ticket: Ticket) {
+        self.results.lock().remove(&ticket);
+    }
+
+    // private
+
+    fn work(&self) {
+        loop {
+            let job = {
+                let mut queue = self.queue.lock();
+                loop {
+                    if let Some(job) = queue.pop_front() {
+                        break job;
+                    }
+                    self.queued.wait(&mut queue);
+                }
+            };
+
+            let res = match job.cf.as_deref() {
+                Some(cf) => GLOBAL_DB.get_cf(cf, job.key.as_slice()),
+                None => GLOBAL_DB.get(job.key.as_slice()),
+            };
+
+            // Cancelled tickets are gone from the map, their result is dropped.
+            if let Some(slot) = self.results.lock().get_mut(&job.ticket) {
+                *slot = Some(res);
+            }
+        }
+    }
+}
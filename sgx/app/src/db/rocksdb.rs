@@ -1,52 +1,443 @@
-use rocksdb::{DB, DBCompactionStyle, Options};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::traits::{Db, Error, Result};
+use parking_lot::Mutex;
+use rocksdb::{BoundColumnFamily, DB, DEFAULT_COLUMN_FAMILY_NAME, DBCompactionStyle, DBRawIterator, Options, Snapshot, WriteBatch, WriteOptions};
+
+use crate::traits::{Db, Error, Result, SnapshotHandle};
+
+/// Column families opened alongside the default one.
+pub const COLUMN_FAMILIES: [&str; 3] = ["index", "feed", TTL_CF];
+
+/// Expiry (unix ms, big endian) of default column family keys written with
+/// `Db::put_with_ttl`. Without it TTL puts are refused and gets don't expire.
+pub const TTL_CF: &str = "ttl";
+
+/// Bump when the on-disk layout changes and add the step to `migrate`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+const SCHEMA_VERSION_KEY: &[u8] = b"__schema_version__";
+
+// Live snapshots pin old data, past this new ones are refused (most likely
+// something isn't releasing them).
+const MAX_SNAPSHOTS: usize = 1024;
+
+/// Upgrades the data written by schema version `from` to `to`.
+pub type MigrateFn = fn(db: &RocksDb, from: u32, to: u32) -> Result<()>;
+
+const DEFAULT_PATH: &str = "./rocks.db";
+
+/// Tuning for the RocksDB `Options`, defaults suit the index workload.
+#[derive(Debug, Clone)]
+pub struct RocksDbConfig {
+    pub write_buffer_size: usize,
+    pub max_write_buffer_number: i32,
+    pub target_file_size_base: u64,
+    pub level_zero_file_num_compaction_trigger: i32,
+    pub level_zero_slowdown_writes_trigger: i32,
+    pub level_zero_stop_writes_trigger: i32,
+    pub num_levels: i32,
+    pub max_bytes_for_level_base: u64,
+    pub max_bytes_for_level_multiplier: f64,
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> Self {
+        Self {
+            write_buffer_size: 67_108_864, // 64mb
+            max_write_buffer_number: 3,
+            target_file_size_base: 67_108_864, // 64mb
+            level_zero_file_num_compaction_trigger: 8,
+            level_zero_slowdown_writes_trigger: 17,
+            level_zero_stop_writes_trigger: 24,
+            num_levels: 4,
+            max_bytes_for_level_base: 536_870_912, // 512mb
+            max_bytes_for_level_multiplier: 8.0,
+        }
+    }
+}
+
+impl RocksDbConfig {
+    pub fn options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.set_compaction_style(DBCompactionStyle::Level);
+        opts.set_write_buffer_size(self.write_buffer_size);
+        opts.set_max_write_buffer_number(self.max_write_buffer_number);
+        opts.set_target_file_size_base(self.target_file_size_base);
+        opts.set_level_zero_file_num_compaction_trigger(self.level_zero_file_num_compaction_trigger);
+        opts.set_level_zero_slowdown_writes_trigger(self.level_zero_slowdown_writes_trigger);
+        opts.set_level_zero_stop_writes_trigger(self.level_zero_stop_writes_trigger);
+        opts.set_num_levels(self.num_levels);
+        opts.set_max_bytes_for_level_base(self.max_bytes_for_level_base);
+        opts.set_max_bytes_for_level_multiplier(self.max_bytes_for_level_multiplier);
+        opts
+    }
+}
 
 pub struct RocksDb {
-    db: DB,
+    // Borrow `db`, so are declared (and dropped) before it. See `snapshot`.
+    snapshots: Mutex<HashMap<u64, Snapshot<'static>>>,
+    next_snapshot: AtomicU64,
+    // Boxed so snapshots' references stay valid if the RocksDb is moved.
+    db: Box<DB>,
+    // Held by every write (including removing an expired key), so a compare
+    // and swap's read and write can't interleave with another write, and a
+    // put racing an expiry isn't deleted with it.
+    write_lock: Mutex<()>,
 }
 
 impl RocksDb {
     pub fn new(db: DB) -> Self {
-        Self { db }
+        Self {
+            snapshots: Mutex::new(HashMap::new()),
+            next_snapshot: AtomicU64::new(1),
+            db: Box::new(db),
+            write_lock: Mutex::new(()),
+        }
     }
 
     pub fn default() -> Result<Self> {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_compaction_style(DBCompactionStyle::Level);
-        opts.set_write_buffer_size(67_108_864); // 64mb
-        opts.set_max_write_buffer_number(3);
-        opts.set_target_file_size_base(67_108_864); // 64mb
-        opts.set_level_zero_file_num_compaction_trigger(8);
-        opts.set_level_zero_slowdown_writes_trigger(17);
-        opts.set_level_zero_stop_writes_trigger(24);
-        opts.set_num_levels(4);
-        opts.set_max_bytes_for_level_base(536_870_912); // 512mb
-        opts.set_max_bytes_for_level_multiplier(8.0);
-
-        return match DB::open(&opts, "./rocks.db") {
-            Ok(db) => {
-                Ok(Self::new(db))
-            }
+        Self::default_with_cfs(&COLUMN_FAMILIES)
+    }
+
+    pub fn default_with_cfs(cfs: &[&str]) -> Result<Self> {
+        Self::open_with(DEFAULT_PATH, RocksDbConfig::default().options(), cfs)
+    }
+
+    pub fn open_with(path: &str, opts: Options, cfs: &[&str]) -> Result<Self> {
+        let db = match DB::open_cf(&opts, path, cfs) {
+            Ok(db) => Self::new(db),
             Err(err) => {
-                Err(map_rocks_err(err))
+                return Err(map_rocks_err(err));
             }
         };
+        db.ensure_schema(SCHEMA_VERSION, migrate)?;
+
+        Ok(db)
+    }
+
+    /// Records `version` on an empty database, otherwise runs `migrate` when
+    /// the stored version is older. Data with no recorded version predates
+    /// versioning, so is taken as version 1. A newer stored version (i.e. a
+    /// downgrade) is refused rather than risk misreading the data.
+    pub fn ensure_schema(&self, version: u32, migrate: MigrateFn) -> Result<()> {
+        let stored = match self.db.get(SCHEMA_VERSION_KEY).map_err(map_rocks_err)? {
+            Some(stored) => decode_schema_version(stored.as_slice())?,
+            None if self.is_empty()? => {
+                info!("initialising db schema version: {}", version);
+                return self.put_schema_version(version);
+            }
+            None => 1,
+        };
+
+        if stored == version {
+            return Ok(());
+        }
+        if stored > version {
+            return Err(Error::new(format!(
+                "db schema version {} is newer than supported version {}", stored, version)));
+        }
+
+        info!("migrating db schema from version {} to {}", stored, version);
+        migrate(self, stored, version)?;
+
+        self.put_schema_version(version)
+    }
+
+    /// Whether no column family holds any key.
+    fn is_empty(&self) -> Result<bool> {
+        if !is_empty(self.db.raw_iterator())? {
+            return Ok(false);
+        }
+        // Every column family on disk has to be opened, so each has a handle.
+        for name in DB::list_cf(&Options::default(), self.db.path()).map_err(map_rocks_err)? {
+            if name != DEFAULT_COLUMN_FAMILY_NAME && !is_empty(self.db.raw_iterator_cf(&self.cf(&name)?))? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    #[inline]
+    fn put_schema_version(&self, version: u32) -> Result<()> {
+        self.db.put(SCHEMA_VERSION_KEY, version.to_be_bytes())
+            .map_err(map_rocks_err)
+    }
+
+    fn cf(&self, name: &str) -> Result<Arc<BoundColumnFamily>> {
+        self.db.cf_handle(name)
+            .ok_or_else(|| Error::new(format!("unknown column family: {}", name)))
+    }
+
+    #[inline]
+    fn ttl_cf(&self) -> Option<Arc<BoundColumnFamily>> {
+        self.db.cf_handle(TTL_CF)
+    }
+
+    /// Writes `batch`, also clearing any TTL on `key` as it's being replaced
+    /// or deleted. The caller must hold `write_lock`.
+    fn write_clearing_ttl(&self, mut batch: WriteBatch, key: &[u8], opts: &WriteOptions) -> Result<()> {
+        let ttl_cf = match self.ttl_cf() {
+            Some(ttl_cf) => ttl_cf,
+            None => return self.db.write_opt(batch, opts).map_err(map_rocks_err),
+        };
+        batch.delete_cf(&ttl_cf, key);
+
+        self.db.write_opt(batch, opts).map_err(map_rocks_err)
+    }
+
+    /// Removes `key` if its TTL has passed, returning whether it did.
+    fn expire(&self, key: &[u8]) -> Result<bool> {
+        let ttl_cf = match self.ttl_cf() {
+            Some(ttl_cf) => ttl_cf,
+            None => return Ok(false),
+        };
+        if !is_expired(self.db.get_cf(&ttl_cf, key).map_err(map_rocks_err)?)? {
+            return Ok(false);
+        }
+
+        // Check again now writes are excluded, it may have been put since.
+        let _guard = self.write_lock.lock();
+        self.expire_locked(key)
+    }
+
+    /// As `expire`, but the caller must hold `write_lock`.
+    fn expire_locked(&self, key: &[u8]) -> Result<bool> {
+        let ttl_cf = match self.ttl_cf() {
+            Some(ttl_cf) => ttl_cf,
+            None => return Ok(false),
+        };
+        if !is_expired(self.db.get_cf(&ttl_cf, key).map_err(map_rocks_err)?)? {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+        batch.delete_cf(&ttl_cf, key);
+        self.db.write(batch).map_err(map_rocks_err)?;
+
+        Ok(true)
+    }
+
+    fn snapshot_err(snap: &SnapshotHandle) -> Error {
+        Error::new(format!("unknown snapshot: {}", snap.id()))
     }
 }
 
 impl Db for RocksDb {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.db.get(key).map_err(map_rocks_err)
+        let value = self.db.get(key).map_err(map_rocks_err)?;
+        if value.is_some() && self.expire(key)? {
+            return Ok(None);
+        }
+
+        Ok(value)
+    }
+
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for (key, value) in keys.iter().zip(self.db.multi_get(keys)) {
+            let value = value.map_err(map_rocks_err)?;
+            if value.is_some() && self.expire(key)? {
+                values.push(None);
+            } else {
+                values.push(value);
+            }
+        }
+
+        Ok(values)
     }
 
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
-        self.db.put(key, value).map_err(map_rocks_err)
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+
+        let _guard = self.write_lock.lock();
+        self.write_clearing_ttl(batch, key, &WriteOptions::default())
+    }
+
+    fn put_durable(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+
+        let _guard = self.write_lock.lock();
+        self.write_clearing_ttl(batch, key, &synced_write_opts())
+    }
+
+    fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let ttl_cf = self.ttl_cf()
+            .ok_or_else(|| Error::new(format!("unknown column family: {}", TTL_CF)))?;
+        let expires_at = now_ms()?.saturating_add(ttl.as_millis() as u64);
+
+        let mut batch = WriteBatch::default();
+        batch.put(key, value);
+        batch.put_cf(&ttl_cf, key, expires_at.to_be_bytes());
+
+        let _guard = self.write_lock.lock();
+        self.db.write(batch).map_err(map_rocks_err)
+    }
+
+    fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let _guard = self.write_lock.lock();
+
+        let mut current = self.db.get(key).map_err(map_rocks_err)?;
+        if current.is_some() && self.expire_locked(key)? {
+            current = None;
+        }
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+
+        // A plain put, so any TTL on the key is kept.
+        self.db.put(key, new).map_err(map_rocks_err)?;
+
+        Ok(true)
     }
 
     fn delete(&self, key: &[u8]) -> Result<()> {
-        self.db.delete(key).map_err(map_rocks_err)
+        let mut batch = WriteBatch::default();
+        batch.delete(key);
+
+        let _guard = self.write_lock.lock();
+        self.write_clearing_ttl(batch, key, &WriteOptions::default())
+    }
+
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if cf == DEFAULT_COLUMN_FAMILY_NAME {
+            return self.get(key);
+        }
+        self.db.get_cf(&self.cf(cf)?, key).map_err(map_rocks_err)
+    }
+
+    fn put_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let cf = self.cf(cf)?;
+
+        let _guard = self.write_lock.lock();
+        self.db.put_cf(&cf, key, value).map_err(map_rocks_err)
+    }
+
+    fn put_durable_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let cf = self.cf(cf)?;
+
+        let _guard = self.write_lock.lock();
+        self.db.put_cf_opt(&cf, key, value, &synced_write_opts()).map_err(map_rocks_err)
+    }
+
+    fn compare_and_swap_cf(&self, cf: &str, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let cf = self.cf(cf)?;
+        let _guard = self.write_lock.lock();
+
+        let current = self.db.get_cf(&cf, key).map_err(map_rocks_err)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+
+        self.db.put_cf(&cf, key, new).map_err(map_rocks_err)?;
+
+        Ok(true)
+    }
+
+    fn delete_cf(&self, cf: &str, key: &[u8]) -> Result<()> {
+        let cf = self.cf(cf)?;
+
+        let _guard = self.write_lock.lock();
+        self.db.delete_cf(&cf, key).map_err(map_rocks_err)
+    }
+
+    fn count_prefix(&self, prefix: &[u8]) -> Result<u64> {
+        count_prefix(self.db.raw_iterator(), prefix)
+    }
+
+    fn count_prefix_cf(&self, cf: &str, prefix: &[u8]) -> Result<u64> {
+        count_prefix(self.db.raw_iterator_cf(&self.cf(cf)?), prefix)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8], from: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let ttl_cf = self.ttl_cf();
+        let mut entries = Vec::new();
+
+        let mut iter = self.db.raw_iterator();
+        iter.seek(if from > prefix { from } else { prefix });
+        while iter.valid() && entries.len() < limit {
+            let (key, value) = match (iter.key(), iter.value()) {
+                (Some(key), Some(value)) if key.starts_with(prefix) => (key, value),
+                _ => break,
+            };
+
+            // Expired keys are skipped, but left for a live read to remove.
+            let expired = match &ttl_cf {
+                Some(ttl_cf) => is_expired(self.db.get_cf(ttl_cf, key).map_err(map_rocks_err)?)?,
+                None => false,
+            };
+            if !expired {
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+            iter.next();
+        }
+        iter.status().map_err(map_rocks_err)?;
+
+        Ok(entries)
+    }
+
+    fn snapshot(&self) -> Result<SnapshotHandle> {
+        let mut snapshots = self.snapshots.lock();
+        if snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(Error::new(format!("too many open snapshots (max: {})", MAX_SNAPSHOTS)));
+        }
+
+        // SAFETY: the snapshot borrows the boxed `db`, which doesn't move and
+        // outlives it: snapshots are released, or dropped with `snapshots`,
+        // before `db` is.
+        let snap: Snapshot<'static> = unsafe {
+            std::mem::transmute::<Snapshot<'_>, Snapshot<'static>>(self.db.snapshot())
+        };
+        let id = self.next_snapshot.fetch_add(1, Ordering::Relaxed);
+        snapshots.insert(id, snap);
+
+        Ok(SnapshotHandle::new(id))
+    }
+
+    fn get_at(&self, snap: &SnapshotHandle, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let ttl_cf = self.ttl_cf();
+        let snapshots = self.snapshots.lock();
+        let snapshot = snapshots.get(&snap.id())
+            .ok_or_else(|| Self::snapshot_err(snap))?;
+
+        let value = snapshot.get(key).map_err(map_rocks_err)?;
+        if let (Some(_), Some(ttl_cf)) = (&value, ttl_cf) {
+            // Expired keys read as absent, but are left for a live read to
+            // remove.
+            if is_expired(snapshot.get_cf(&ttl_cf, key).map_err(map_rocks_err)?)? {
+                return Ok(None);
+            }
+        }
+
+        Ok(value)
+    }
+
+    fn get_at_cf(&self, snap: &SnapshotHandle, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // TTLs are kept for default column family keys.
+        if cf == DEFAULT_COLUMN_FAMILY_NAME {
+            return self.get_at(snap, key);
+        }
+        let cf = self.cf(cf)?;
+        let snapshots = self.snapshots.lock();
+
+        snapshots.get(&snap.id())
+            .ok_or_else(|| Self::snapshot_err(snap))?
+            .get_cf(&cf, key).map_err(map_rocks_err)
+    }
+
+    fn release_snapshot(&self, snap: SnapshotHandle) -> Result<()> {
+        match self.snapshots.lock().remove(&snap.id()) {
+            Some(_) => Ok(()),
+            None => Err(Self::snapshot_err(&snap)),
+        }
     }
 
     fn flush(&self) -> Result<()> {
@@ -54,8 +445,96 @@ impl Db for RocksDb {
     }
 }
 
+// Migrations
+
+fn migrate(db: &RocksDb, from: u32, to: u32) -> Result<()> {
+    for version in from..to {
+        match version {
+            1 => migrate_v1_to_v2(db)?,
+            _ => return Err(Error::new(format!(
+                "no db migration from version {} to {}", version, version + 1))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 2 adds the ttl column family. Opening creates it (empty, so no
+/// key has a TTL), this only checks it's there.
+fn migrate_v1_to_v2(db: &RocksDb) -> Result<()> {
+    db.ttl_cf()
+        .map(|_| ())
+        .ok_or_else(|| Error::new(format!("unknown column family: {}", TTL_CF)))
+}
+
 // Util
 
+/// Walks the keys only, values are never copied out of RocksDB.
+fn count_prefix(mut iter: DBRawIterator, prefix: &[u8]) -> Result<u64> {
+    let mut count = 0u64;
+
+    iter.seek(prefix);
+    while iter.valid() {
+        match iter.key() {
+            Some(key) if key.starts_with(prefix) => count += 1,
+            _ => break,
+        }
+        iter.next();
+    }
+    iter.status().map_err(map_rocks_err)?;
+
+    Ok(count)
+}
+
+fn is_empty(mut iter: DBRawIterator) -> Result<bool> {
+    iter.seek_to_first();
+    if iter.valid() {
+        return Ok(false);
+    }
+    iter.status().map_err(map_rocks_err)?;
+
+    Ok(true)
+}
+
+#[inline]
+fn synced_write_opts() -> WriteOptions {
+    let mut opts = WriteOptions::default();
+    opts.set_sync(true);
+    opts
+}
+
+#[inline]
+fn now_ms() -> Result<u64> {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .map_err(|e| Error::new(format!("system clock before unix epoch: {}", e)))
+}
+
+fn is_expired(expires_at: Option<Vec<u8>>) -> Result<bool> {
+    let expires_at = match expires_at {
+        Some(expires_at) => expires_at,
+        None => return Ok(false),
+    };
+
+    let mut buf = [0u8; 8];
+    if expires_at.len() != buf.len() {
+        return Err(Error::new(format!("invalid ttl expiry: {:?}", expires_at)));
+    }
+    buf.copy_from_slice(expires_at.as_slice());
+
+    Ok(u64::from_be_bytes(buf) <= now_ms()?)
+}
+
+fn decode_schema_version(bytes: &[u8]) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    if bytes.len() != buf.len() {
+        return Err(Error::new(format!("invalid db schema version: {:?}", bytes)));
+    }
+    buf.copy_from_slice(bytes);
+
+    Ok(u32::from_be_bytes(buf))
+}
+
 fn map_rocks_err(err: rocksdb::Error) -> Error {
     Error::new(err.to_string())
 }
\ No newline at end of file
@@ -72,11 +72,14 @@ static ENCLAVE_FILE: &'static str = "enclave.signed.so";
 
 const ENCLAVE_LOCK_TIMEOUT: u64 = 6 * 5;
 const TCS_NUM: u8 = 12;
+// Kept out of the shared pool for control ecalls (shutdown, TLS reload), the
+// API workers hold their slots for as long as they serve.
+const CONTROL_TCS_NUM: u8 = 1;
 
 lazy_static! {
     pub static ref ENCLAVE_DOORBELL: EnclaveDoorbell = EnclaveDoorbell::new(
         ENCLAVE_FILE,
-        std::cmp::min(TCS_NUM, num_cpus::get() as u8)
+        std::cmp::min(TCS_NUM - CONTROL_TCS_NUM, num_cpus::get() as u8)
     );
 }
 
@@ -93,6 +96,9 @@ pub struct EnclaveDoorbell {
     condvar: Condvar,
     /// Amount of tasks allowed to use the enclave at the same time.
     count: Mutex<u8>,
+    control_condvar: Condvar,
+    /// Slots only `get_control_access` hands out.
+    control_count: Mutex<u8>,
 }
 
 impl EnclaveDoorbell {
@@ -102,13 +108,26 @@ impl EnclaveDoorbell {
             enclave: init_enclave(enclave_file),
             condvar: Condvar::new(),
             count: Mutex::new(count),
+            control_condvar: Condvar::new(),
+            control_count: Mutex::new(CONTROL_TCS_NUM),
         }
     }
 
-    fn wait_for(&'static self, duration: Duration, recursive: bool, reserve: u8) -> Option<EnclaveAccessToken> {
+    #[inline]
+    fn pool(&self, control: bool) -> (&Mutex<u8>, &Condvar) {
+        if control {
+            (&self.control_count, &self.control_condvar)
+        } else {
+            (&self.count, &self.condvar)
+        }
+    }
+
+    fn wait_for(&'static self, duration: Duration, recursive: bool, reserve: u8,
+                control: bool) -> Option<EnclaveAccessToken> {
         // eprintln!("Query Token creation. recursive: {}", recursive);
         if !recursive {
-            let mut count = self.count.lock();
+            let (count, condvar) = self.pool(control);
+            let mut count = count.lock();
             // eprintln!(
             //     "The current count of tasks is {}/{}, attempting to increase.",
             //     TCS_NUM - *count,
@@ -117,7 +136,7 @@ impl EnclaveDoorbell {
             if *count == 0 {
                 // eprintln!("Waiting for other tasks to complete");
                 // try to wait for other tasks to complete
-                let wait = self.condvar.wait_for(&mut count, duration);
+                let wait = condvar.wait_for(&mut count, duration);
                 // double check that the count is nonzero, so there's an available slot in the enclave.
                 if wait.timed_out() || *count == 0 {
                     return None;
@@ -126,7 +145,7 @@ impl EnclaveDoorbell {
             // eprintln!("Increasing available tasks");
             *count -= reserve;
         }
-        Some(EnclaveAccessToken::new(self, recursive, reserve))
+        Some(EnclaveAccessToken::new(self, recursive, reserve, control))
     }
 
     pub fn get_access(&'static self, recursive: bool) -> Option<EnclaveAccessToken> {
@@ -134,7 +153,13 @@ impl EnclaveDoorbell {
     }
 
     pub fn get_access_for(&'static self, recursive: bool, reserve: u8) -> Option<EnclaveAccessToken> {
-        self.wait_for(Duration::from_secs(ENCLAVE_LOCK_TIMEOUT), recursive, reserve)
+        self.wait_for(Duration::from_secs(ENCLAVE_LOCK_TIMEOUT), recursive, reserve, false)
+    }
+
+    /// Access through the reserved control slot, for short ecalls that must
+    /// get in even while the API workers hold every other slot.
+    pub fn get_control_access(&'static self) -> Option<EnclaveAccessToken> {
+        self.wait_for(Duration::from_secs(ENCLAVE_LOCK_TIMEOUT), false, 1, true)
     }
 
     pub fn capacity(&'static self) -> u8 {
@@ -150,16 +175,19 @@ pub struct EnclaveAccessToken {
     enclave: SgxResult<&'static SgxEnclave>,
     recursive: bool,
     reserve: u8,
+    control: bool,
 }
 
 impl EnclaveAccessToken {
-    fn new(doorbell: &'static EnclaveDoorbell, recursive: bool, reserve: u8) -> Self {
+    fn new(doorbell: &'static EnclaveDoorbell, recursive: bool, reserve: u8,
+           control: bool) -> Self {
         let enclave = doorbell.enclave.as_ref().map_err(|status| *status);
         Self {
             doorbell,
             enclave,
             recursive,
-            reserve
+            reserve,
+            control,
         }
     }
 }
@@ -176,7 +204,8 @@ impl Drop for EnclaveAccessToken {
     fn drop(&mut self) {
         // eprintln!("Query Token destruction. recursive: {}", self.recursive);
         if !self.recursive {
-            let mut count = self.doorbell.count.lock();
+            let (count, condvar) = self.doorbell.pool(self.control);
+            let mut count = count.lock();
             // eprintln!(
             //     "The current count of tasks is {}/{}, attempting to decrease.",
             //     TCS_NUM - *count,
@@ -184,7 +213,7 @@ impl Drop for EnclaveAccessToken {
             // );
             *count += self.reserve;
             drop(count);
-            self.doorbell.condvar.notify_one();
+            condvar.notify_one();
         }
     }
 }
@@ -2,5 +2,16 @@ use sgx_types::*;
 
 extern {
     pub(crate) fn ecall_api_server_start(eid: sgx_enclave_id_t,
-                                         addr: *const c_char) -> sgx_status_t;
-}
\ No newline at end of file
+                                         retval: *mut sgx_status_t,
+                                         addr: *const c_char,
+                                         cert_path: *const c_char,
+                                         key_path: *const c_char) -> sgx_status_t;
+
+    pub(crate) fn ecall_reload_tls(eid: sgx_enclave_id_t,
+                                   retval: *mut sgx_status_t,
+                                   cert_path: *const c_char,
+                                   key_path: *const c_char) -> sgx_status_t;
+
+    pub(crate) fn ecall_api_server_shutdown(eid: sgx_enclave_id_t,
+                                            retval: *mut sgx_status_t) -> sgx_status_t;
+}
@@ -1,50 +1,139 @@
 use std::ptr;
+use std::time::Duration;
 
 use log::warn;
 
 use enclave_ffi_types::{EnclaveBuffer, OcallReturn};
 
 use crate::db::GLOBAL_DB;
+use crate::db::offload::{Completion, DB_OFFLOAD};
 use crate::enclave::allocate::allocate_enclave_buffer;
-use crate::traits::Db;
+use crate::traits::{Db, SnapshotHandle};
 
+/// Values over `value_max_len` are refused with `TooBig` rather than copied
+/// into the enclave.
 #[no_mangle]
 pub extern "C"
 fn ocall_db_get(
     value: *mut EnclaveBuffer,
+    cf: *const u8,
+    cf_len: usize,
     key: *const u8,
     key_len: usize,
+    value_max_len: usize,
 ) -> OcallReturn {
-    let mut ret = OcallReturn::None;
-
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_get invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
     let key = unsafe { std::slice::from_raw_parts(key, key_len) };
 
-    match GLOBAL_DB.get(key) {
-        Ok(res) => {
-            if res.is_some() {
-                match allocate_enclave_buffer(res.unwrap().as_slice()) {
-                    Ok(enclave_buffer) => {
-                        unsafe { *value = enclave_buffer };
-                    }
-                    Err(e) => {
-                        warn!("ocall_db_get failed to allocate enclave buffer {:?}", e);
-                        ret = OcallReturn::Failure
-                    }
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.get_cf(cf, key),
+        None => GLOBAL_DB.get(key),
+    };
+
+    match res {
+        Ok(Some(res)) => {
+            if res.len() > value_max_len {
+                warn!("ocall_db_get fetch too big ({} vs {})", res.len(), value_max_len);
+                return OcallReturn::TooBig;
+            }
+
+            match allocate_enclave_buffer(res.as_slice()) {
+                Ok(enclave_buffer) => {
+                    unsafe { *value = enclave_buffer };
+                    OcallReturn::Success
+                }
+                Err(e) => {
+                    warn!("ocall_db_get failed to allocate enclave buffer {:?}", e);
+                    OcallReturn::Failure
                 }
             }
         }
+        Ok(None) => OcallReturn::None,
         Err(e) => {
             warn!("ocall_db_get failed {:?}", e);
-            ret = OcallReturn::Failure
+            OcallReturn::Failure
         }
     }
+}
 
-    ret
+/// Reads `keys` (each `key_len` bytes, concatenated) in one call. The values
+/// come back in one buffer, in key order, each as a presence byte followed
+/// (when present) by its u32 big endian length and the value. Any value over
+/// `value_max_len`, or the whole buffer over `total_max_len`, fails the call
+/// with `TooBig`. The buffer is allocated inside the enclave, so the total
+/// has to be bounded, not just each value.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_multi_get(
+    values: *mut EnclaveBuffer,
+    keys: *const u8,
+    keys_len: usize,
+    key_len: usize,
+    value_max_len: usize,
+    total_max_len: usize,
+) -> OcallReturn {
+    if key_len == 0 || keys_len % key_len != 0 {
+        warn!("ocall_db_multi_get invalid keys length ({} for keys of {})", keys_len, key_len);
+        return OcallReturn::Failure;
+    }
+    let keys: Vec<Vec<u8>> = unsafe { std::slice::from_raw_parts(keys, keys_len) }
+        .chunks(key_len)
+        .map(|key| key.to_vec())
+        .collect();
+
+    let res = match GLOBAL_DB.multi_get(keys.as_slice()) {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("ocall_db_multi_get failed {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+
+    let mut buf: Vec<u8> = Vec::new();
+    for value in res.iter() {
+        match value {
+            Some(value) => {
+                if value.len() > value_max_len {
+                    warn!("ocall_db_multi_get fetch too big ({} vs {})", value.len(), value_max_len);
+                    return OcallReturn::TooBig;
+                }
+
+                buf.push(1);
+                buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                buf.extend_from_slice(value.as_slice());
+            }
+            None => buf.push(0),
+        }
+
+        if buf.len() > total_max_len {
+            warn!("ocall_db_multi_get fetch too big (over {} in total)", total_max_len);
+            return OcallReturn::TooBig;
+        }
+    }
+
+    match allocate_enclave_buffer(buf.as_slice()) {
+        Ok(enclave_buffer) => {
+            unsafe { *values = enclave_buffer };
+            OcallReturn::Success
+        }
+        Err(e) => {
+            warn!("ocall_db_multi_get failed to allocate enclave buffer {:?}", e);
+            OcallReturn::Failure
+        }
+    }
 }
 
 #[no_mangle]
 pub extern "C"
 fn ocall_db_get_fixed(
+    cf: *const u8,
+    cf_len: usize,
     key: *const u8,
     key_len: usize,
     value: *mut u8,
@@ -53,24 +142,36 @@ fn ocall_db_get_fixed(
 ) -> OcallReturn {
     let mut ret = OcallReturn::Success;
 
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_get_fixed invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
     let key = unsafe { std::slice::from_raw_parts(key, key_len) };
 
-    match GLOBAL_DB.get(key) {
-        Ok(res) => {
-            if res.is_some() {
-                let res = res.unwrap();
-                if res.len() > value_max_len {
-                    warn!("ocall_db_get_fixed fetch too big ({} vs {})", res.len(), value_max_len);
-                    ret = OcallReturn::TooBig
-                } else {
-                    unsafe {
-                        ptr::copy_nonoverlapping(res.as_ptr(), value, res.len());
-
-                        *value_len = res.len();
-                    }
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.get_cf(cf, key),
+        None => GLOBAL_DB.get(key),
+    };
+
+    match res {
+        Ok(Some(res)) => {
+            if res.len() > value_max_len {
+                warn!("ocall_db_get_fixed fetch too big ({} vs {})", res.len(), value_max_len);
+                ret = OcallReturn::TooBig
+            } else {
+                unsafe {
+                    ptr::copy_nonoverlapping(res.as_ptr(), value, res.len());
+
+                    *value_len = res.len();
                 }
             }
         }
+        Ok(None) => {
+            ret = OcallReturn::None
+        }
         Err(e) => {
             warn!("ocall_db_get_fixed failed {:?}", e);
             ret = OcallReturn::Failure
@@ -83,14 +184,28 @@ fn ocall_db_get_fixed(
 #[no_mangle]
 pub extern "C"
 fn ocall_db_delete(
+    cf: *const u8,
+    cf_len: usize,
     key: *const u8,
     key_len: usize,
 ) -> OcallReturn {
     let mut ret = OcallReturn::Success;
 
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_delete invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
     let key = unsafe { std::slice::from_raw_parts(key, key_len) };
 
-    match GLOBAL_DB.delete(key) {
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.delete_cf(cf, key),
+        None => GLOBAL_DB.delete(key),
+    };
+
+    match res {
         Err(e) => {
             warn!("ocall_db_delete failed {:?}", e);
             ret = OcallReturn::Failure
@@ -104,6 +219,8 @@ fn ocall_db_delete(
 #[no_mangle]
 pub extern "C"
 fn ocall_db_put(
+    cf: *const u8,
+    cf_len: usize,
     key: *const u8,
     key_len: usize,
     value: *const u8,
@@ -111,10 +228,22 @@ fn ocall_db_put(
 ) -> OcallReturn {
     let mut ret = OcallReturn::Success;
 
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_put invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
     let key = unsafe { std::slice::from_raw_parts(key, key_len) };
     let value = unsafe { std::slice::from_raw_parts(value, value_len) };
 
-    match GLOBAL_DB.put(key, value) {
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.put_cf(cf, key, value),
+        None => GLOBAL_DB.put(key, value),
+    };
+
+    match res {
         Err(e) => {
             warn!("ocall_db_put failed {:?}", e);
             ret = OcallReturn::Failure
@@ -125,6 +254,221 @@ fn ocall_db_put(
     ret
 }
 
+/// As `ocall_db_put`, returning once the write is synced, see `Db::put_durable`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_put_sync(
+    cf: *const u8,
+    cf_len: usize,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> OcallReturn {
+    let mut ret = OcallReturn::Success;
+
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_put_sync invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+    let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+    let value = unsafe { std::slice::from_raw_parts(value, value_len) };
+
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.put_durable_cf(cf, key, value),
+        None => GLOBAL_DB.put_durable(key, value),
+    };
+
+    match res {
+        Err(e) => {
+            warn!("ocall_db_put_sync failed {:?}", e);
+            ret = OcallReturn::Failure
+        }
+        _ => {}
+    }
+
+    ret
+}
+
+/// As `ocall_db_put` (default column family only), the key expiring after
+/// `ttl_ms`, see `Db::put_with_ttl`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_put_ttl(
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+    ttl_ms: u64,
+) -> OcallReturn {
+    let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+    let value = unsafe { std::slice::from_raw_parts(value, value_len) };
+
+    match GLOBAL_DB.put_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
+        Ok(()) => OcallReturn::Success,
+        Err(e) => {
+            warn!("ocall_db_put_ttl failed {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_db_cas(
+    cf: *const u8,
+    cf_len: usize,
+    key: *const u8,
+    key_len: usize,
+    expected: *const u8,
+    expected_len: usize,
+    expected_exists: u8,
+    value: *const u8,
+    value_len: usize,
+    swapped: *mut u8,
+) -> OcallReturn {
+    let mut ret = OcallReturn::Success;
+
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_cas invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+    let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+    let expected = if expected_exists != 0 {
+        Some(unsafe { std::slice::from_raw_parts(expected, expected_len) })
+    } else {
+        None
+    };
+    let value = unsafe { std::slice::from_raw_parts(value, value_len) };
+
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.compare_and_swap_cf(cf, key, expected, value),
+        None => GLOBAL_DB.compare_and_swap(key, expected, value),
+    };
+
+    match res {
+        Ok(res) => {
+            unsafe { *swapped = res as u8 };
+        }
+        Err(e) => {
+            warn!("ocall_db_cas failed {:?}", e);
+            ret = OcallReturn::Failure
+        }
+    }
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_db_count_prefix(
+    cf: *const u8,
+    cf_len: usize,
+    prefix: *const u8,
+    prefix_len: usize,
+    count: *mut u64,
+) -> OcallReturn {
+    let mut ret = OcallReturn::Success;
+
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_count_prefix invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+    let prefix = unsafe { std::slice::from_raw_parts(prefix, prefix_len) };
+
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.count_prefix_cf(cf, prefix),
+        None => GLOBAL_DB.count_prefix(prefix),
+    };
+
+    match res {
+        Ok(res) => {
+            unsafe { *count = res };
+        }
+        Err(e) => {
+            warn!("ocall_db_count_prefix failed {:?}", e);
+            ret = OcallReturn::Failure
+        }
+    }
+
+    ret
+}
+
+/// Reads up to `max_entries` default column family entries starting with
+/// `prefix`, from key `from` on. The buffer starts with a byte set when more
+/// entries may follow, then each entry as its u32 big endian key length, the
+/// key, its u32 big endian value length and the value. Entries that would
+/// take the buffer over `total_max_len` are left for the next call, a value
+/// over `value_max_len` (or a first entry over `total_max_len`) fails the
+/// call with `TooBig`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_scan_prefix(
+    entries: *mut EnclaveBuffer,
+    prefix: *const u8,
+    prefix_len: usize,
+    from: *const u8,
+    from_len: usize,
+    max_entries: usize,
+    value_max_len: usize,
+    total_max_len: usize,
+) -> OcallReturn {
+    let prefix = unsafe { std::slice::from_raw_parts(prefix, prefix_len) };
+    let from = unsafe { std::slice::from_raw_parts(from, from_len) };
+
+    let res = match GLOBAL_DB.scan_prefix(prefix, from, max_entries) {
+        Ok(res) => res,
+        Err(e) => {
+            warn!("ocall_db_scan_prefix failed {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+
+    let mut more = res.len() >= max_entries;
+    let mut buf: Vec<u8> = vec![0];
+    for (idx, (key, value)) in res.iter().enumerate() {
+        if value.len() > value_max_len {
+            warn!("ocall_db_scan_prefix fetch too big ({} vs {})", value.len(), value_max_len);
+            return OcallReturn::TooBig;
+        }
+
+        if buf.len() + 8 + key.len() + value.len() > total_max_len {
+            if idx == 0 {
+                warn!("ocall_db_scan_prefix fetch too big (over {} in total)", total_max_len);
+                return OcallReturn::TooBig;
+            }
+            more = true;
+            break;
+        }
+
+        buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(key.as_slice());
+        buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        buf.extend_from_slice(value.as_slice());
+    }
+    buf[0] = more as u8;
+
+    match allocate_enclave_buffer(buf.as_slice()) {
+        Ok(enclave_buffer) => {
+            unsafe { *entries = enclave_buffer };
+            OcallReturn::Success
+        }
+        Err(e) => {
+            warn!("ocall_db_scan_prefix failed to allocate enclave buffer {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C"
 fn ocall_db_flush() -> OcallReturn
@@ -141,3 +485,177 @@ fn ocall_db_flush() -> OcallReturn
 
     ret
 }
+
+/// Queues a get on the offload pool, see `DbOffload`. `Failure` when too many
+/// are pending.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_get_submit(
+    cf: *const u8,
+    cf_len: usize,
+    key: *const u8,
+    key_len: usize,
+    ticket: *mut u64,
+) -> OcallReturn {
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_get_submit invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+    let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+
+    match DB_OFFLOAD.submit_get(cf, key) {
+        Some(res) => {
+            unsafe { *ticket = res };
+            OcallReturn::Success
+        }
+        None => {
+            warn!("ocall_db_get_submit too many pending gets");
+            OcallReturn::Failure
+        }
+    }
+}
+
+/// `Pending` until the get behind `ticket` is done, then as `ocall_db_get`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_get_poll(
+    value: *mut EnclaveBuffer,
+    ticket: u64,
+    value_max_len: usize,
+) -> OcallReturn {
+    let res = match DB_OFFLOAD.take(ticket) {
+        Some(Completion::Done(res)) => res,
+        Some(Completion::Pending) => return OcallReturn::Pending,
+        None => {
+            warn!("ocall_db_get_poll unknown ticket {}", ticket);
+            return OcallReturn::Failure;
+        }
+    };
+
+    match res {
+        Ok(Some(res)) => {
+            if res.len() > value_max_len {
+                warn!("ocall_db_get_poll fetch too big ({} vs {})", res.len(), value_max_len);
+                return OcallReturn::TooBig;
+            }
+
+            match allocate_enclave_buffer(res.as_slice()) {
+                Ok(enclave_buffer) => {
+                    unsafe { *value = enclave_buffer };
+                    OcallReturn::Success
+                }
+                Err(e) => {
+                    warn!("ocall_db_get_poll failed to allocate enclave buffer {:?}", e);
+                    OcallReturn::Failure
+                }
+            }
+        }
+        Ok(None) => OcallReturn::None,
+        Err(e) => {
+            warn!("ocall_db_get_poll failed {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_db_get_cancel(ticket: u64) -> OcallReturn {
+    DB_OFFLOAD.cancel(ticket);
+
+    OcallReturn::Success
+}
+
+/// Opens a snapshot, held until `ocall_db_release_snapshot`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_snapshot(snapshot: *mut u64) -> OcallReturn {
+    match GLOBAL_DB.snapshot() {
+        Ok(snap) => {
+            unsafe { *snapshot = snap.id() };
+            OcallReturn::Success
+        }
+        Err(e) => {
+            warn!("ocall_db_snapshot failed {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
+/// As `ocall_db_get`, reading through `snapshot`.
+#[no_mangle]
+pub extern "C"
+fn ocall_db_get_at(
+    value: *mut EnclaveBuffer,
+    snapshot: u64,
+    cf: *const u8,
+    cf_len: usize,
+    key: *const u8,
+    key_len: usize,
+    value_max_len: usize,
+) -> OcallReturn {
+    let cf = match cf_name(cf, cf_len) {
+        Ok(cf) => cf,
+        Err(e) => {
+            warn!("ocall_db_get_at invalid column family name {:?}", e);
+            return OcallReturn::Failure;
+        }
+    };
+    let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+    let snap = SnapshotHandle::new(snapshot);
+
+    let res = match cf {
+        Some(cf) => GLOBAL_DB.get_at_cf(&snap, cf, key),
+        None => GLOBAL_DB.get_at(&snap, key),
+    };
+
+    match res {
+        Ok(Some(res)) => {
+            if res.len() > value_max_len {
+                warn!("ocall_db_get_at fetch too big ({} vs {})", res.len(), value_max_len);
+                return OcallReturn::TooBig;
+            }
+
+            match allocate_enclave_buffer(res.as_slice()) {
+                Ok(enclave_buffer) => {
+                    unsafe { *value = enclave_buffer };
+                    OcallReturn::Success
+                }
+                Err(e) => {
+                    warn!("ocall_db_get_at failed to allocate enclave buffer {:?}", e);
+                    OcallReturn::Failure
+                }
+            }
+        }
+        Ok(None) => OcallReturn::None,
+        Err(e) => {
+            warn!("ocall_db_get_at failed {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C"
+fn ocall_db_release_snapshot(snapshot: u64) -> OcallReturn {
+    match GLOBAL_DB.release_snapshot(SnapshotHandle::new(snapshot)) {
+        Ok(()) => OcallReturn::Success,
+        Err(e) => {
+            warn!("ocall_db_release_snapshot failed {:?}", e);
+            OcallReturn::Failure
+        }
+    }
+}
+
+/// An empty column family name selects the default column family.
+fn cf_name<'a>(cf: *const u8, cf_len: usize) -> Result<Option<&'a str>, std::str::Utf8Error> {
+    if cf_len == 0 {
+        return Ok(None);
+    }
+
+    let cf = unsafe { std::slice::from_raw_parts(cf, cf_len) };
+    std::str::from_utf8(cf).map(Some)
+}
@@ -24,11 +24,15 @@ extern crate rocksdb;
 
 extern crate pretty_env_logger;
 #[macro_use] extern crate log;
+extern crate signal_hook;
 
+use std::thread;
 use std::time::SystemTime;
 
 use sgx_types::*;
-use api::server::{start_api_service};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use api::server::{reload_tls, shutdown_api_service, start_api_service};
 
 use enclave::doorbell::ENCLAVE_DOORBELL;
 
@@ -37,6 +41,11 @@ pub(crate) mod db;
 pub(crate) mod api;
 pub(crate) mod enclave;
 
+const DEFAULT_TLS_CERT_PATH: &str = "end.fullchain";
+const DEFAULT_TLS_KEY_PATH: &str = "end.rsa";
+// Comma separated, e.g. "0.0.0.0:8443,[::]:8443".
+const DEFAULT_LISTEN_ADDRS: &str = "0.0.0.0:8443";
+
 extern {
     #[allow(dead_code)]
     pub fn ecall_perform_test(
@@ -79,5 +88,51 @@ fn run_perform_test() {
 fn main() {
     pretty_env_logger::init();
 
-    start_api_service("0.0.0.0:8443".to_string())
+    let cert_path = std::env::var("TLS_CERT_PATH")
+        .unwrap_or_else(|_| DEFAULT_TLS_CERT_PATH.to_string());
+    let key_path = std::env::var("TLS_KEY_PATH")
+        .unwrap_or_else(|_| DEFAULT_TLS_KEY_PATH.to_string());
+
+    let addrs = std::env::var("LISTEN_ADDRS")
+        .unwrap_or_else(|_| DEFAULT_LISTEN_ADDRS.to_string());
+
+    spawn_signal_handler(cert_path.clone(), key_path.clone());
+
+    start_api_service(addrs, cert_path, key_path)
+}
+
+/// SIGTERM/SIGINT drain the API servers, `start_api_service` (and so the
+/// process) returns once they have. SIGHUP reloads the TLS certificate and key
+/// from `cert_path`/`key_path`, e.g. after renewal.
+fn spawn_signal_handler(cert_path: String, key_path: String) {
+    let mut signals = match Signals::new(&[SIGTERM, SIGINT, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!("failed to register signal handler: {:?}", err);
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGTERM | SIGINT => {
+                    info!("received signal {}, shutting down API service", signal);
+                    if let Err(err) = shutdown_api_service() {
+                        // Nothing else would stop the process now the signal is ours.
+                        error!("failed to shut down API service: {}", err);
+                        std::process::exit(1);
+                    }
+                }
+                SIGHUP => {
+                    info!("received SIGHUP, reloading TLS certificate");
+                    if let Err(err) = reload_tls(cert_path.clone(), key_path.clone()) {
+                        // The previous certificate stays in use.
+                        error!("failed to reload TLS certificate: {}", err);
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
 }
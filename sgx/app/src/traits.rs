@@ -1,4 +1,5 @@
 use std::fmt::{Debug};
+use std::time::Duration;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -29,6 +30,21 @@ impl From<Error> for String {
     }
 }
 
+/// A point-in-time view of the db, reads through it don't see later writes.
+/// Held by the db until `Db::release_snapshot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SnapshotHandle(u64);
+
+impl SnapshotHandle {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
 /// Access to the node's backend db
 pub trait Db
     where
@@ -36,9 +52,57 @@ pub trait Db
 {
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
 
+    /// Values for `keys` in the same order, as `get` would return them but
+    /// read in one batch.
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>>;
+
+    /// Written to the WAL but not synced, a crash of the machine (not just
+    /// the process) can lose the most recent puts.
     fn put(&self, key: &[u8], value: &[u8]) -> Result<()>;
 
+    /// Returns once the write is synced to disk, so survives a machine crash.
+    /// Each call waits on an fsync, keep it to writes that must not be lost.
+    fn put_durable(&self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// After `ttl` the key reads as absent (and is removed on that read,
+    /// snapshot reads only skip it). A later plain `put` or `delete` of the
+    /// key clears the TTL, `compare_and_swap` keeps it.
+    fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()>;
+
+    /// Writes `new` only if the current value matches `expected` (`None` meaning
+    /// the key must not exist). Returns whether the swap happened. Atomic
+    /// with respect to every other write.
+    fn compare_and_swap(&self, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool>;
+
     fn delete(&self, key: &[u8]) -> Result<()>;
 
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn put_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn put_durable_cf(&self, cf: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    fn compare_and_swap_cf(&self, cf: &str, key: &[u8], expected: Option<&[u8]>, new: &[u8]) -> Result<bool>;
+
+    fn delete_cf(&self, cf: &str, key: &[u8]) -> Result<()>;
+
+    /// Number of keys starting with `prefix`, without reading their values.
+    fn count_prefix(&self, prefix: &[u8]) -> Result<u64>;
+
+    fn count_prefix_cf(&self, cf: &str, prefix: &[u8]) -> Result<u64>;
+
+    /// Up to `limit` entries whose key starts with `prefix`, in key order
+    /// from `from` (inclusive). Expired keys are skipped.
+    fn scan_prefix(&self, prefix: &[u8], from: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    fn snapshot(&self) -> Result<SnapshotHandle>;
+
+    /// Reads `key` as it was when `snap` was taken.
+    fn get_at(&self, snap: &SnapshotHandle, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn get_at_cf(&self, snap: &SnapshotHandle, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn release_snapshot(&self, snap: SnapshotHandle) -> Result<()>;
+
     fn flush(&self) -> Result<()>;
 }
@@ -2,35 +2,43 @@ use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
 use bytes::BytesMut;
-use http::{header::HeaderValue, Request, Response};
+use http::{header::HeaderValue, Request, Response, StatusCode};
 use http::request::Builder;
 use lazy_static::lazy_static;
 use std::fmt;
 
 use crate::api::results::{Error, ErrorKind};
 
+// Used unless the config sets its own `Server` header.
+pub(crate) const DEFAULT_SERVER_HEADER: &str = "index.teggle.io/v1beta1";
+
 lazy_static! {
-    pub(crate) static ref GLOBAL_CODEC: HttpCodec = {
-        HttpCodec::new("index.teggle.io/v1beta1")
-    };
+    pub(crate) static ref GLOBAL_CODEC: HttpCodec = HttpCodec::new();
 }
 
-// Borrowed from: https://github.com/tokio-rs/tokio/blob/master/examples/tinyhttp.rs
+// Headers parsed on the first attempt, grown towards the configured max.
+const INITIAL_HEADERS_CAPACITY: usize = 16;
 
-pub(crate) struct HttpCodec {
-    server: String,
+enum DecodeError {
+    TooManyHeaders,
+    Other(Error),
 }
 
+// Borrowed from: https://github.com/tokio-rs/tokio/blob/master/examples/tinyhttp.rs
+
+pub(crate) struct HttpCodec {}
+
 impl HttpCodec {
-    pub(crate) fn new(server: &str) -> Self {
-        Self { server: server.to_string() }
+    pub(crate) fn new() -> Self {
+        Self {}
     }
 
     pub(crate) fn encode(
         &self,
         item: Response<()>,
         dst: &mut BytesMut,
-        content_length: usize
+        content_length: usize,
+        server: &str,
     ) -> Result<(), Error> {
         use std::fmt::Write;
 
@@ -39,18 +47,24 @@ impl HttpCodec {
             "\
              {:?} {}\r\n\
              Server: {}\r\n\
-             Content-Length: {}\r\n\
              Date: {}\r\n\
              ",
             item.version(),
             item.status(),
-            self.server,
-            content_length,
+            server,
             date::now()
         ).map_err(|e| {
             Error::new_with_kind(ErrorKind::EncodeFault, e.to_string())
         })?;
 
+        // 1xx, 204 and 304 must not carry a Content-Length (RFC 7230 3.3.2).
+        if allows_body(item.status()) {
+            write!(BytesWrite(dst), "Content-Length: {}\r\n", content_length)
+                .map_err(|e| {
+                    Error::new_with_kind(ErrorKind::EncodeFault, e.to_string())
+                })?;
+        }
+
         for (k, v) in item.headers() {
             dst.extend_from_slice(k.as_str().as_bytes());
             dst.extend_from_slice(b": ");
@@ -63,18 +77,71 @@ impl HttpCodec {
         Ok(())
     }
 
-    pub(crate) fn decode(&self, src: &mut BytesMut) -> Result<Option<Builder>, Error> {
-        // TODO: we should grow this headers array if parsing fails and asks
-        //       for more headers
-        let mut headers = [None; 16];
+    /// Parses the request head, retrying with room for more headers (up to
+    /// `max_headers`) when the client sent more than fit. Request targets over
+    /// `max_uri_length` are refused before any `Request` is built, as is a head
+    /// still incomplete (or completing) past `max_header_bytes`.
+    pub(crate) fn decode(&self, src: &mut BytesMut, max_headers: usize,
+                         max_uri_length: usize,
+                         max_header_bytes: usize) -> Result<Option<Builder>, Error> {
+        let mut capacity = core::cmp::min(INITIAL_HEADERS_CAPACITY, max_headers);
+        loop {
+            match self.decode_with_capacity(src, capacity, max_uri_length,
+                                            max_header_bytes) {
+                Err(DecodeError::TooManyHeaders) if capacity < max_headers => {
+                    capacity = core::cmp::min(capacity * 2, max_headers);
+                }
+                Err(DecodeError::TooManyHeaders) => {
+                    return Err(Error::new_with_kind(
+                        ErrorKind::HeadersTooLarge,
+                        format!("too many request headers (max: {})", max_headers)));
+                }
+                Err(DecodeError::Other(err)) => return Err(err),
+                Ok(builder) => return Ok(builder),
+            }
+        }
+    }
+
+    // private
+
+    fn decode_with_capacity(&self, src: &mut BytesMut, capacity: usize,
+                            max_uri_length: usize,
+                            max_header_bytes: usize) -> Result<Option<Builder>, DecodeError> {
+        let mut headers = vec![None; capacity];
         let (method, path, version, amt) = {
-            let mut parsed_headers = [httparse::EMPTY_HEADER; 16];
+            let mut parsed_headers = vec![httparse::EMPTY_HEADER; capacity];
             let mut r = httparse::Request::new(&mut parsed_headers);
-            let status = r.parse(src).map_err(|e| {
-                return Error::new_with_kind(ErrorKind::DecodeFault,
-                                            format!("failed to parse http request: {:?}", e));
+            // Bytes that don't parse as HTTP are the client's fault, not ours.
+            let status = r.parse(src).map_err(|e| match e {
+                httparse::Error::TooManyHeaders => DecodeError::TooManyHeaders,
+                e => DecodeError::Other(Error::new_with_kind(
+                    ErrorKind::BadRequest,
+                    format!("failed to parse http request: {:?}", e))),
             })?;
 
+            // A partial parse only has the path once the request line is in,
+            // don't wait for the rest of an over long one.
+            let uri_length = match r.path {
+                Some(path) => path.len(),
+                None => src.len(),
+            };
+            if uri_length > max_uri_length {
+                return Err(DecodeError::Other(Error::new_with_kind(
+                    ErrorKind::UriTooLong,
+                    format!("request uri too long (max: {})", max_uri_length))));
+            }
+
+            // Don't keep buffering a head that never ends.
+            let head_length = match status {
+                httparse::Status::Complete(amt) => amt,
+                httparse::Status::Partial => src.len(),
+            };
+            if head_length > max_header_bytes {
+                return Err(DecodeError::Other(Error::new_with_kind(
+                    ErrorKind::HeadersTooLarge,
+                    format!("request headers too large (max: {} bytes)", max_header_bytes))));
+            }
+
             let amt = match status {
                 httparse::Status::Complete(amt) => amt,
                 httparse::Status::Partial => return Ok(None),
@@ -111,10 +178,10 @@ impl HttpCodec {
             0 => { ret = ret.version(http::Version::HTTP_10); },
             1 => { ret = ret.version(http::Version::HTTP_11); },
             _ => {
-                return Err(Error::new_with_kind(
-                    ErrorKind::DecodeFault,
+                return Err(DecodeError::Other(Error::new_with_kind(
+                    ErrorKind::BadRequest,
                     "only HTTP/1.0 or 1.1 accepted".to_string(),
-                ));
+                )));
             }
         }
 
@@ -124,8 +191,8 @@ impl HttpCodec {
                 None => break,
             };
             let value = HeaderValue::from_bytes(data.slice(v.0..v.1).as_ref())
-                .map_err(|_| Error::new_with_kind(ErrorKind::DecodeFault,
-                                                  "header decode error".to_string()))?;
+                .map_err(|_| DecodeError::Other(Error::new_with_kind(
+                    ErrorKind::BadRequest, "header decode error".to_string())))?;
             ret = ret.header(&data[k.0..k.1], value);
         }
 
@@ -133,6 +200,14 @@ impl HttpCodec {
     }
 }
 
+/// Whether a response with `status` may have a body (and Content-Length).
+#[inline]
+pub(crate) fn allows_body(status: StatusCode) -> bool {
+    !(status.is_informational()
+        || status.eq(&StatusCode::NO_CONTENT)
+        || status.eq(&StatusCode::NOT_MODIFIED))
+}
+
 // Right now `write!` on `Vec<u8>` goes through io::Write and is not
 // super speedy, so inline a less-crufty implementation here which
 // doesn't go through io::Error.
@@ -3,25 +3,52 @@ use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::any::Any;
-
-use mio_httpc::{CallBuilder, Method};
+use core::fmt::Arguments;
+use core::future::Future;
+use core::time::Duration;
+
+use futures::future::{BoxFuture, join_all, ready};
+use futures::FutureExt;
+use mio_httpc::{CallBuilder, Method, Response as FetchResponseHead};
+use ring::hmac;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::SgxMutex;
+use std::time::{Instant, SystemTime};
 use tungstenite::Message;
 
-use crate::api::handler::request::Request;
-use crate::api::reactor::httpc::{HttpcCallFuture, HttpcReactor};
+use crate::api::handler::request::{HEADER_REQUEST_ID, Request};
+use crate::api::reactor::cancel::{CancelToken, Cancelled};
+use crate::api::reactor::db::{db_get_offload, DbGetFuture};
+use crate::api::reactor::httpc::{BodyStream, FetchResponse, HttpcCallFuture, HttpcReactor};
+use crate::api::reactor::join::JoinHandle;
+use crate::api::reactor::timer::{self, Sleep};
 use crate::api::results::{Error, ErrorKind};
-use crate::api::server::websocket::{SubscriptionHandlerFn, WebSocket};
+use crate::api::server::connection::Deferral;
+use crate::api::server::websocket::{broadcast, SubscriptionHandlerFn, SubscriptionId, WebSocket};
+use crate::external::db::{db_delete, db_get, db_multi_get, db_put, db_put_with_ttl, db_snapshot, DbSnapshot};
+use crate::utils::base64::encode_base64;
 
 const FETCH_DEFAULT_TIMEOUT_MS: u64 = 2500;
 
-type ContextValue = dyn Any + Sync + Send + 'static;
+// Added to signed fetches, see `HttpFetchBuilder::sign`.
+static HEADER_SIGNATURE: &str = "X-Signature";
+static HEADER_TIMESTAMP: &str = "X-Timestamp";
+
+pub type FetchFuture = BoxFuture<'static, Result<Option<FetchResponse>, Error>>;
+
+pub(crate) type ContextValue = dyn Any + Sync + Send + 'static;
+// State shared by every request (and web socket message) on a connection.
+pub(crate) type ConnState = HashMap<&'static str, Box<ContextValue>>;
 
 pub struct Context {
     request: Request,
+    deferral: Arc<SgxMutex<Deferral>>,
     httpc: Arc<SgxMutex<HttpcReactor>>,
     ws: Option<Arc<SgxMutex<WebSocket>>>,
+    conn_state: Arc<SgxMutex<ConnState>>,
+    cancel: CancelToken,
     data: HashMap<&'static str, Box<ContextValue>>,
 }
 
@@ -30,13 +57,19 @@ impl Context {
     #[inline]
     pub(crate) fn new(
         request: Request,
+        deferral: Arc<SgxMutex<Deferral>>,
         httpc: Arc<SgxMutex<HttpcReactor>>,
         ws: Option<Arc<SgxMutex<WebSocket>>>,
+        conn_state: Arc<SgxMutex<ConnState>>,
+        cancel: CancelToken,
     ) -> Self {
         Self {
             request,
+            deferral,
             httpc,
             ws,
+            conn_state,
+            cancel,
             data: HashMap::new(),
         }
     }
@@ -51,6 +84,26 @@ impl Context {
         &mut self.request
     }
 
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.request.peer_addr()
+    }
+
+    #[inline]
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.request.client_ip()
+    }
+
+    /// Logger prefixing each line with the request id and route, so lines from
+    /// handlers interleaving across connections can be told apart.
+    #[inline]
+    pub fn log(&self) -> RequestLogger {
+        RequestLogger {
+            request_id: self.request.request_id().to_string(),
+            route: format!("{} {}", self.request.method(), self.request.uri().path()),
+        }
+    }
+
     // Web Sockets
 
     #[inline]
@@ -58,7 +111,39 @@ impl Context {
         self.ws.is_some() && self.request.is_websocket()
     }
 
-    pub fn subscribe(&self, handler: SubscriptionHandlerFn) -> Result<(), Error> {
+    /// Subscribes to messages without a topic (see `subscribe_topic`).
+    pub fn subscribe(&self, handler: SubscriptionHandlerFn) -> Result<SubscriptionId, Error> {
+        self.subscribe_with(None, handler)
+    }
+
+    /// Subscribes to messages sent as `{"topic": "<topic>", "data": ...}`.
+    pub fn subscribe_topic(&self, topic: &str, handler: SubscriptionHandlerFn) -> Result<SubscriptionId, Error> {
+        self.subscribe_with(Some(topic.to_string()), handler)
+    }
+
+    /// Removes a subscription, returns false if it didn't exist.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> Result<bool, Error> {
+        if !self.is_websocket() {
+            return Err(Error::new_with_kind(
+                ErrorKind::WSFault,
+                format!("attempt to call Context->unsubscribe when request is not a web socket"),
+            ));
+        }
+
+        return match self.ws.as_ref().unwrap().lock() {
+            Ok(mut ws) => {
+                Ok(ws.unsubscribe(id))
+            }
+            Err(err) => {
+                Err(Error::new_with_kind(
+                    ErrorKind::WSFault,
+                    format!("failed to acquire lock on 'ws' during Context->unsubscribe: {:?}", err),
+                ))
+            }
+        };
+    }
+
+    fn subscribe_with(&self, topic: Option<String>, handler: SubscriptionHandlerFn) -> Result<SubscriptionId, Error> {
         if !self.is_websocket() {
             return Err(Error::new_with_kind(
                 ErrorKind::WSFault,
@@ -70,7 +155,7 @@ impl Context {
 
         return match self.ws.as_ref().unwrap().lock() {
             Ok(mut ws) => {
-                ws.subscribe(handler.clone())
+                ws.subscribe(topic, handler.clone())
             }
             Err(err) => {
                 Err(Error::new_with_kind(
@@ -94,10 +179,28 @@ impl Context {
     }
 
     #[inline]
-    pub fn send_bin(&self, data: Vec<u8>) -> Result<(), Error> {
+    pub fn send_binary(&self, data: Vec<u8>) -> Result<(), Error> {
         self.send(Message::Binary(data))
     }
 
+    #[inline]
+    #[allow(dead_code)]
+    #[deprecated(note = "renamed to `send_binary`")]
+    pub fn send_bin(&self, data: Vec<u8>) -> Result<(), Error> {
+        self.send_binary(data)
+    }
+
+    /// Serializes `data` and sends it as a text frame.
+    #[inline]
+    pub fn send_json<T: ?Sized + Serialize>(&self, data: &T) -> Result<(), Error> {
+        match serde_json::to_string(data) {
+            Ok(text) => self.send_text(text),
+            Err(err) => {
+                Err(Error::new_with_kind(ErrorKind::EncodeFault, err.to_string()))
+            }
+        }
+    }
+
     #[inline]
     pub fn send(&self, msg: Message) -> Result<(), Error> {
         if !self.is_websocket() {
@@ -120,16 +223,145 @@ impl Context {
         };
     }
 
+    /// Sends `msg` to every web socket client (across all connections) that
+    /// subscribed to `topic`, or to plain subscribers for `None`. Returns the
+    /// number of clients it was queued for.
+    #[inline]
+    pub fn broadcast(&self, topic: Option<&str>, msg: Message) -> Result<usize, Error> {
+        broadcast(topic.map(|t| t.to_string()), msg)
+    }
+
     // HTTP Client
 
     #[inline]
     pub fn http(&self) -> HttpFetchBuilder {
-        HttpFetchBuilder::http(self.httpc.clone())
+        let mut builder = HttpFetchBuilder::http(self.httpc.clone());
+        builder.header(HEADER_REQUEST_ID, self.request.request_id());
+        builder
     }
 
     #[inline]
     pub fn https(&self) -> HttpFetchBuilder {
-        HttpFetchBuilder::https(self.httpc.clone())
+        let mut builder = HttpFetchBuilder::https(self.httpc.clone());
+        builder.header(HEADER_REQUEST_ID, self.request.request_id());
+        builder
+    }
+
+    /// Starts all the fetches at once, resolving when every one has completed.
+    /// Results are in the same order as `builders`.
+    #[inline]
+    pub fn fetch_all(
+        &self,
+        builders: Vec<HttpFetchBuilder>,
+    ) -> impl Future<Output=Vec<Result<Option<FetchResponse>, Error>>> {
+        let calls: Vec<FetchFuture> = builders.into_iter()
+            .map(|mut builder| builder.fetch())
+            .collect();
+
+        join_all(calls)
+    }
+
+    // Tasks
+
+    /// Resolves once `duration` has passed, without holding up the reactor.
+    #[inline]
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        timer::sleep(duration)
+    }
+
+    /// Runs `future` as its own task on the connection's reactor, the returned
+    /// handle resolves to its output.
+    #[inline]
+    pub fn spawn<T>(&self, future: impl Future<Output=T> + 'static + Send) -> Result<JoinHandle<T>, Error>
+        where
+            T: 'static + Send,
+    {
+        match self.deferral.lock() {
+            Ok(mut deferral) => deferral.spawn_with_handle(future),
+            Err(err) => {
+                Err(Error::new_with_kind(
+                    ErrorKind::ExecError,
+                    format!("failed to acquire lock on 'deferral' during Context->spawn: {:?}", err),
+                ))
+            }
+        }
+    }
+
+    // Database
+
+    /// NOTE: ocalls block the enclave thread, so the lookup runs synchronously
+    /// when called and the returned future is already complete.
+    #[inline]
+    pub fn db_get(&self, key: &[u8]) -> impl Future<Output=Result<Option<Vec<u8>>, Error>> {
+        ready(db_get(key).map_err(|e| db_err("db_get", e)))
+    }
+
+    /// Values for `keys` in the same order, fetched in one crossing of the
+    /// enclave boundary rather than one per key. Runs synchronously, see `db_get`.
+    #[inline]
+    pub fn db_multi_get(&self, keys: &[&[u8]]) -> impl Future<Output=Result<Vec<Option<Vec<u8>>>, Error>> {
+        ready(db_multi_get(keys).map_err(|e| db_err("db_multi_get", e)))
+    }
+
+    /// Like `db_get`, but the read runs on the host's offload pool so the
+    /// server keeps serving other connections while it's on disk.
+    #[inline]
+    pub fn db_get_async(&self, key: &[u8]) -> DbGetFuture {
+        db_get_offload(key)
+    }
+
+    /// Runs synchronously, see `db_get`.
+    #[inline]
+    pub fn db_put(&self, key: &[u8], value: &[u8]) -> impl Future<Output=Result<(), Error>> {
+        ready(db_put(key, value).map_err(|e| db_err("db_put", e)))
+    }
+
+    /// For entries that should go away on their own (sessions, caches), the
+    /// key reads as absent once `ttl` has passed. Runs synchronously, see
+    /// `db_get`.
+    #[inline]
+    pub fn db_put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> impl Future<Output=Result<(), Error>> {
+        ready(db_put_with_ttl(key, value, ttl).map_err(|e| db_err("db_put_with_ttl", e)))
+    }
+
+    /// Runs synchronously, see `db_get`.
+    #[inline]
+    pub fn db_delete(&self, key: &[u8]) -> impl Future<Output=Result<(), Error>> {
+        ready(db_delete(key).map_err(|e| db_err("db_delete", e)))
+    }
+
+    /// For related reads that must not see a write land between them, read
+    /// them with `db_get_at`. Release it with `db_release_snapshot` when done.
+    #[inline]
+    pub fn db_snapshot(&self) -> Result<DbSnapshot, Error> {
+        db_snapshot().map_err(|e| db_err("db_snapshot", e))
+    }
+
+    /// Runs synchronously, see `db_get`.
+    #[inline]
+    pub fn db_get_at(&self, snap: &DbSnapshot, key: &[u8]) -> impl Future<Output=Result<Option<Vec<u8>>, Error>> {
+        ready(snap.get(key).map_err(|e| db_err("db_get_at", e)))
+    }
+
+    #[inline]
+    pub fn db_release_snapshot(&self, snap: DbSnapshot) -> Result<(), Error> {
+        snap.release().map_err(|e| db_err("db_release_snapshot", e))
+    }
+
+    // Cancellation
+
+    /// Set once the request's task has run past the exec timeout (the task is
+    /// dropped shortly after), or the client has disconnected. Handlers
+    /// should give up (e.g. with a `TimedOut` error) once they see it.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Resolves once `is_cancelled` would return true.
+    #[inline]
+    pub fn cancelled(&self) -> Cancelled {
+        self.cancel.cancelled()
     }
 
     // Context Data
@@ -155,11 +387,61 @@ impl Context {
     pub fn contains_key(&mut self, key: &'static str) -> bool {
         self.data.contains_key(&key)
     }
+
+    /// State that lives as long as the connection, unlike the per-request data
+    /// above it is seen by later keep-alive requests and web socket messages.
+    #[inline]
+    pub fn conn_state(&self) -> &Arc<SgxMutex<ConnState>> {
+        &self.conn_state
+    }
+}
+
+#[inline]
+fn db_err(op: &str, message: String) -> Error {
+    Error::new_with_kind(ErrorKind::DbError, format!("{} failed: {}", op, message))
+}
+
+/// Returned by `Context::log`, e.g. `ctx.log().info(format_args!("found {}", n))`.
+/// Owns its prefix so it can be moved into spawned futures.
+#[derive(Clone, Debug)]
+pub struct RequestLogger {
+    request_id: String,
+    route: String,
+}
+
+impl RequestLogger {
+    #[inline]
+    pub fn debug(&self, args: Arguments) {
+        debug!("[{}] {}: {}", self.request_id, self.route, args);
+    }
+
+    #[inline]
+    pub fn info(&self, args: Arguments) {
+        info!("[{}] {}: {}", self.request_id, self.route, args);
+    }
+
+    #[inline]
+    pub fn warn(&self, args: Arguments) {
+        warn!("[{}] {}: {}", self.request_id, self.route, args);
+    }
+
+    #[inline]
+    pub fn error(&self, args: Arguments) {
+        error!("[{}] {}: {}", self.request_id, self.route, args);
+    }
 }
 
 pub struct HttpFetchBuilder {
     httpc: Arc<SgxMutex<HttpcReactor>>,
     builder: Option<CallBuilder>,
+    body: Option<Vec<u8>>,
+    body_stream: Option<BodyStream>,
+    max_attempts: u8,
+    timeout_ms: u64,
+    // What's signed, kept as the builder doesn't give them back.
+    method: String,
+    sign_path: String,
+    signing_key: Option<Vec<u8>>,
 }
 
 #[allow(dead_code)]
@@ -169,7 +451,17 @@ impl HttpFetchBuilder {
         let mut builder = CallBuilder::new();
         builder.timeout_ms(FETCH_DEFAULT_TIMEOUT_MS);
 
-        Self { httpc, builder: Some(builder) }
+        Self {
+            httpc,
+            builder: Some(builder),
+            body: None,
+            body_stream: None,
+            max_attempts: 1,
+            timeout_ms: FETCH_DEFAULT_TIMEOUT_MS,
+            method: "GET".to_string(),
+            sign_path: "/".to_string(),
+            signing_key: None,
+        }
     }
 
     #[inline]
@@ -198,6 +490,7 @@ impl HttpFetchBuilder {
 
     #[inline]
     pub fn method(&mut self, method: Method) -> &mut Self {
+        self.method = format!("{:?}", method);
         self.builder.as_mut().unwrap().method_typed(method);
         self
     }
@@ -205,6 +498,7 @@ impl HttpFetchBuilder {
     #[inline]
     /// Set full path. No percent encoding is done. Will fail later if it contains invalid characters.
     pub fn path(&mut self, path: &str) -> &mut Self {
+        self.sign_path = path.to_string();
         self.builder.as_mut().unwrap().path(path);
         self
     }
@@ -214,6 +508,10 @@ impl HttpFetchBuilder {
     /// Any path unsafe characters are percent encoded.
     /// If part contains /, it will be percent encoded!
     pub fn path_segment(&mut self, path_segment: &str) -> &mut Self {
+        if !self.sign_path.ends_with("/") {
+            self.sign_path.push('/');
+        }
+        self.sign_path.push_str(path_segment);
         self.builder.as_mut().unwrap().path_segm(path_segment);
         self
     }
@@ -229,6 +527,7 @@ impl HttpFetchBuilder {
                     ErrorKind::HttpClientError,
                     "failed to set url".to_string())
             })?;
+        self.sign_path = url_path(url);
         Ok(())
     }
 
@@ -240,77 +539,262 @@ impl HttpFetchBuilder {
 
     #[inline]
     pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
-        self.builder.as_mut().unwrap().body(body);
+        self.body_stream = None;
+        self.body = Some(body);
+        self
+    }
+
+    #[inline]
+    /// Set the body as a sequence of chunks, pulled when the call is started
+    /// rather than collected up front by the handler.
+    /// NOTE: the chunks are currently joined into a single buffer before
+    /// sending, see `BodyStream`.
+    pub fn body_stream<I>(&mut self, chunks: I) -> &mut Self
+        where
+            I: IntoIterator<Item=Vec<u8>>,
+            I::IntoIter: Send + 'static,
+    {
+        self.body_stream = Some(Box::new(chunks.into_iter()));
+        self
+    }
+
+    #[inline]
+    /// Overall timeout for the call, retries included (default: 2500ms).
+    pub fn timeout_ms(&mut self, timeout_ms: u64) -> &mut Self {
+        self.timeout_ms = timeout_ms;
+        self.builder.as_mut().unwrap().timeout_ms(timeout_ms);
+        self
+    }
+
+    #[inline]
+    /// Retry (up to `max_attempts` calls in total) when the upstream answers
+    /// 429 or 503 with a `Retry-After`, waiting as asked in between. Retries
+    /// stop once the wait would run past the call's timeout, the last
+    /// response is returned either way.
+    /// NOTE: a `body_stream` is collected up front so it can be resent.
+    pub fn retry(&mut self, max_attempts: u8) -> &mut Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    #[inline]
+    /// Sign the call with HMAC-SHA256 under `key`. At `fetch()` an
+    /// `X-Timestamp` (unix seconds) header is added, and an `X-Signature`
+    /// header (base64) over "{timestamp}\n{METHOD}\n{path}\n" followed by the
+    /// body. The path is as given to `path`/`path_segment`/`url`, before
+    /// percent encoding.
+    /// NOTE: a `body_stream` is collected up front so it can be signed.
+    pub fn sign(&mut self, key: &[u8]) -> &mut Self {
+        self.signing_key = Some(key.to_vec());
         self
     }
 
     #[inline]
-    pub fn fetch(&mut self) -> HttpcCallFuture {
+    pub fn fetch(&mut self) -> FetchFuture {
         if self.builder.is_none() {
             return HttpcCallFuture::from_error(
                 Error::new_with_kind(ErrorKind::HttpClientError,
                                      "fetch() called with no builder.".to_string())
-            );
+            ).boxed();
         }
 
-        match self.httpc.lock() {
-            Ok(mut lock) => {
-                let builder = self.builder.take().unwrap();
-                //trace!("fetching: {}", builder.get_url());
+        let mut builder = self.builder.take().unwrap();
+        //trace!("fetching: {}", builder.get_url());
 
-                lock.call(builder)
-            }
-            Err(err) => {
-                HttpcCallFuture::from_error(
-                    Error::new_with_kind(ErrorKind::HttpClientError,
-                                         format!("failed to get lock on 'httpc' during HttpFetchBuilder->fetch: {:?}", err))
-                )
+        if let Some(key) = self.signing_key.take() {
+            if let Some(body) = self.body_stream.take() {
+                self.body = Some(body.flatten().collect());
             }
+
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+                .to_string();
+            let signature = sign_request(key.as_slice(), timestamp.as_str(),
+                                         self.method.as_str(), self.sign_path.as_str(),
+                                         self.body.as_deref().unwrap_or(&[]));
+
+            builder.header(HEADER_TIMESTAMP, timestamp.as_str());
+            builder.header(HEADER_SIGNATURE, signature.as_str());
+        }
+        if let Some(body) = self.body.take() {
+            builder.body(body);
+        }
+
+        if self.max_attempts <= 1 {
+            return start_call(&self.httpc, builder, self.body_stream.take()).boxed();
+        }
+
+        if let Some(body) = self.body_stream.take() {
+            builder.body(body.flatten().collect());
         }
+
+        fetch_with_retry(self.httpc.clone(), builder, self.max_attempts, self.timeout_ms)
     }
 
     #[inline]
-    pub fn get(&mut self) -> HttpcCallFuture {
+    pub fn get(&mut self) -> FetchFuture {
         self.method(Method::GET);
         self.fetch()
     }
 
     #[inline]
-    pub fn post(&mut self, body: Vec<u8>) -> HttpcCallFuture {
+    pub fn post(&mut self, body: Vec<u8>) -> FetchFuture {
         self.method(Method::POST);
         self.body(body);
         self.fetch()
     }
 
     #[inline]
-    pub fn put(&mut self, body: Vec<u8>) -> HttpcCallFuture {
+    pub fn put(&mut self, body: Vec<u8>) -> FetchFuture {
         self.method(Method::PUT);
         self.body(body);
         self.fetch()
     }
 
     #[inline]
-    pub fn patch(&mut self, body: Vec<u8>) -> HttpcCallFuture {
+    pub fn post_stream<I>(&mut self, chunks: I) -> FetchFuture
+        where
+            I: IntoIterator<Item=Vec<u8>>,
+            I::IntoIter: Send + 'static,
+    {
+        self.method(Method::POST);
+        self.body_stream(chunks);
+        self.fetch()
+    }
+
+    #[inline]
+    pub fn put_stream<I>(&mut self, chunks: I) -> FetchFuture
+        where
+            I: IntoIterator<Item=Vec<u8>>,
+            I::IntoIter: Send + 'static,
+    {
+        self.method(Method::PUT);
+        self.body_stream(chunks);
+        self.fetch()
+    }
+
+    #[inline]
+    pub fn patch(&mut self, body: Vec<u8>) -> FetchFuture {
         self.method(Method::PATCH);
         self.body(body);
         self.fetch()
     }
 
     #[inline]
-    pub fn delete(&mut self) -> HttpcCallFuture {
+    pub fn delete(&mut self) -> FetchFuture {
         self.method(Method::DELETE);
         self.fetch()
     }
 
     #[inline]
-    pub fn options(&mut self) -> HttpcCallFuture {
+    pub fn options(&mut self) -> FetchFuture {
         self.method(Method::OPTIONS);
         self.fetch()
     }
 
     #[inline]
-    pub fn head(&mut self) -> HttpcCallFuture {
+    pub fn head(&mut self) -> FetchFuture {
         self.method(Method::HEAD);
         self.fetch()
     }
-}
\ No newline at end of file
+}
+
+/// Base64 HMAC-SHA256 of the canonical request, see `HttpFetchBuilder::sign`.
+fn sign_request(key: &[u8], timestamp: &str, method: &str, path: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+
+    let mut ctx = hmac::Context::with_key(&key);
+    ctx.update(format!("{}\n{}\n{}\n", timestamp, method, path).as_bytes());
+    ctx.update(body);
+
+    encode_base64(ctx.sign().as_ref())
+}
+
+/// Path (and query) of `url`, "/" if it has none.
+fn url_path(url: &str) -> String {
+    let rest = match url.find("://") {
+        Some(idx) => &url[idx + 3..],
+        None => url,
+    };
+
+    match rest.find("/") {
+        Some(idx) => rest[idx..].to_string(),
+        None => "/".to_string(),
+    }
+}
+
+#[inline]
+fn start_call(
+    httpc: &Arc<SgxMutex<HttpcReactor>>,
+    builder: CallBuilder,
+    body: Option<BodyStream>,
+) -> HttpcCallFuture {
+    match httpc.lock() {
+        Ok(mut lock) => lock.call(builder, body),
+        Err(err) => {
+            HttpcCallFuture::from_error(
+                Error::new_with_kind(ErrorKind::HttpClientError,
+                                     format!("failed to get lock on 'httpc' during HttpFetchBuilder->fetch: {:?}", err))
+            )
+        }
+    }
+}
+
+fn fetch_with_retry(
+    httpc: Arc<SgxMutex<HttpcReactor>>,
+    builder: CallBuilder,
+    max_attempts: u8,
+    timeout_ms: u64,
+) -> FetchFuture {
+    async move {
+        let deadline = Instant::now().checked_add(Duration::from_millis(timeout_ms))
+            .ok_or_else(|| Error::new_with_kind(
+                ErrorKind::HttpClientError,
+                format!("fetch timeout too long: {}ms", timeout_ms)))?;
+        let mut attempt = 1;
+
+        loop {
+            // Every attempt gets what's left of the overall timeout.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut call = builder.clone();
+            call.timeout_ms((remaining.as_millis() as u64).max(1));
+
+            let res = start_call(&httpc, call, None).await?;
+
+            let wait = match res.as_ref() {
+                Some((head, _)) if attempt < max_attempts => retry_after(head),
+                _ => None,
+            };
+            // `Retry-After` is the upstream's to pick, it may be absurdly long.
+            match wait {
+                Some(wait) if Instant::now().checked_add(wait).map_or(false, |t| t < deadline) => {
+                    timer::sleep(wait).await;
+                    attempt += 1;
+                }
+                _ => return Ok(res),
+            }
+        }
+    }.boxed()
+}
+
+/// How long a 429/503 response asked to wait, `Retry-After` may be either
+/// delay seconds or an HTTP date.
+fn retry_after(head: &FetchResponseHead) -> Option<Duration> {
+    if head.status != 429 && head.status != 503 {
+        return None;
+    }
+
+    let value = head.headers()
+        .find(|h| h.name.eq_ignore_ascii_case("retry-after"))?
+        .value
+        .trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(at.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0)))
+}
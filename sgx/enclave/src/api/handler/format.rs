@@ -0,0 +1,88 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::api::handler::request::accept_quality;
+use crate::api::results::{Error, ErrorKind};
+
+pub(crate) static MIME_JSON: &str = "application/json";
+pub(crate) static CONTENT_TYPE_JSON: &str = "application/json; charset=utf-8";
+static CONTENT_TYPE_BINCODE: &str = "application/x-bincode";
+// Treated as bincode, for clients that don't name it.
+static CONTENT_TYPE_OCTET_STREAM: &str = "application/octet-stream";
+
+/// Body formats `Request::decode` and `Response::encode_as` understand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BodyFormat {
+    Json,
+    Bincode,
+}
+
+impl BodyFormat {
+    /// Format named by a Content-Type (parameters ignored), `None` if it isn't
+    /// one of ours.
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+        let mime = content_type.split(";").next().unwrap_or("").trim();
+
+        if mime.eq_ignore_ascii_case(MIME_JSON) {
+            Some(BodyFormat::Json)
+        } else if mime.eq_ignore_ascii_case(CONTENT_TYPE_BINCODE)
+            || mime.eq_ignore_ascii_case(CONTENT_TYPE_OCTET_STREAM) {
+            Some(BodyFormat::Bincode)
+        } else {
+            None
+        }
+    }
+
+    /// Format the client prefers going by its Accept ranges (see
+    /// `Request::accept_ranked`), JSON on a tie.
+    pub(crate) fn preferred(accept: &[(String, f32)]) -> Self {
+        let json = accept_quality(accept, MIME_JSON);
+        let bincode = accept_quality(accept, CONTENT_TYPE_BINCODE)
+            .max(accept_quality(accept, CONTENT_TYPE_OCTET_STREAM));
+
+        if bincode > json {
+            BodyFormat::Bincode
+        } else {
+            BodyFormat::Json
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => CONTENT_TYPE_JSON,
+            BodyFormat::Bincode => CONTENT_TYPE_BINCODE,
+        }
+    }
+
+    pub(crate) fn serialize<T: ?Sized + Serialize>(&self, data: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            BodyFormat::Json => serde_json::to_vec(data)
+                .map_err(|e| encode_err(e.to_string())),
+            BodyFormat::Bincode => bincode::serialize(data)
+                .map_err(|e| encode_err(e.to_string())),
+        }
+    }
+
+    /// Only bincode, JSON goes through `Request::json` for its depth check.
+    /// Length prefixes are limited to what's left of `body`, so a bogus one
+    /// fails rather than allocating for it.
+    pub(crate) fn deserialize_bincode<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+        // Same encoding as `bincode::serialize`, plus the limit.
+        bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(body.len() as u64)
+            .deserialize(body)
+            .map_err(|e| Error::new_with_kind(
+                ErrorKind::BadRequest, format!("invalid bincode body: {}", e)))
+    }
+}
+
+#[inline]
+fn encode_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::EncodeFault, message)
+}
@@ -0,0 +1,27 @@
+use serde_json::{Map, Value};
+
+/// Applies `patch` to `target` as a JSON merge patch (RFC 7386): object
+/// members are merged recursively, `null` members removed and anything else
+/// (arrays included) replaces the target value outright.
+pub fn apply_merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch.as_object() {
+        Some(patch) => patch,
+        None => {
+            *target = patch.clone();
+            return;
+        }
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (name, value) in patch.iter() {
+            if value.is_null() {
+                target.remove(name);
+            } else {
+                apply_merge_patch(target.entry(name.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
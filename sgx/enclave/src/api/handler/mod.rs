@@ -1,4 +1,7 @@
 pub(crate) mod codec;
+pub(crate) mod format;
+pub(crate) mod merge_patch;
+pub(crate) mod multipart;
 pub(crate) mod router;
 pub(crate) mod routes;
 pub(crate) mod response;
@@ -0,0 +1,141 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::api::results::{Error, ErrorKind};
+
+static DEFAULT_PART_CONTENT_TYPE: &str = "text/plain";
+
+/// A single part of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Pulls the boundary parameter out of a `multipart/form-data` content type.
+pub(crate) fn boundary(content_type: &str) -> Result<String, Error> {
+    let mut params = content_type.split(";");
+    let mime = params.next().unwrap_or("").trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(multipart_err(format!("expected content type \
+            'multipart/form-data', got: {}", content_type)));
+    }
+
+    for param in params {
+        let mut kv = param.splitn(2, "=");
+        let k = kv.next().unwrap_or("").trim();
+        if k.eq_ignore_ascii_case("boundary") {
+            let v = kv.next().unwrap_or("").trim().trim_matches('"');
+            // RFC 2046 limits boundaries to 1-70 characters.
+            if v.is_empty() || v.len() > 70 {
+                return Err(multipart_err(format!("invalid boundary: {}", v)));
+            }
+
+            return Ok(v.to_string());
+        }
+    }
+
+    Err(multipart_err("missing multipart boundary".to_string()))
+}
+
+/// Splits `body` on `boundary`, rejecting it once the part data adds up to
+/// more than `max_bytes`.
+pub(crate) fn parse(body: &[u8], boundary: &str, max_bytes: usize) -> Result<Vec<Part>, Error> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut close = b"\r\n".to_vec();
+    close.extend_from_slice(delimiter.as_slice());
+
+    // Anything before the first delimiter is preamble.
+    let mut pos = find(body, delimiter.as_slice(), 0)
+        .ok_or_else(|| multipart_err("missing opening boundary".to_string()))?
+        + delimiter.len();
+
+    let mut parts = Vec::new();
+    let mut total = 0_usize;
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(multipart_err("malformed boundary line".to_string()));
+        }
+        pos += 2;
+
+        let headers_end = find(body, b"\r\n\r\n", pos)
+            .ok_or_else(|| multipart_err("unterminated part headers".to_string()))?;
+        let headers = core::str::from_utf8(&body[pos..headers_end])
+            .map_err(|_| multipart_err("part headers are not utf-8".to_string()))?;
+        pos = headers_end + 4;
+
+        let data_end = find(body, close.as_slice(), pos)
+            .ok_or_else(|| multipart_err("missing closing boundary".to_string()))?;
+
+        total += data_end - pos;
+        if total > max_bytes {
+            return Err(Error::new_with_kind(
+                ErrorKind::BadRequest,
+                format!("multipart data too large (max: {} bytes)", max_bytes)));
+        }
+
+        let mut part = parse_headers(headers)?;
+        part.data = body[pos..data_end].to_vec();
+        parts.push(part);
+
+        pos = data_end + close.len();
+    }
+}
+
+// private
+
+fn parse_headers(headers: &str) -> Result<Part, Error> {
+    let mut name: Option<String> = None;
+    let mut filename: Option<String> = None;
+    let mut content_type: Option<String> = None;
+
+    for line in headers.split("\r\n") {
+        let mut kv = line.splitn(2, ":");
+        let k = kv.next().unwrap_or("").trim();
+        let v = kv.next().unwrap_or("").trim();
+
+        if k.eq_ignore_ascii_case("content-disposition") {
+            for param in v.split(";").skip(1) {
+                let mut pkv = param.splitn(2, "=");
+                let pk = pkv.next().unwrap_or("").trim();
+                let pv = pkv.next().unwrap_or("").trim().trim_matches('"');
+
+                if pk.eq_ignore_ascii_case("name") {
+                    name = Some(pv.to_string());
+                } else if pk.eq_ignore_ascii_case("filename") {
+                    filename = Some(pv.to_string());
+                }
+            }
+        } else if k.eq_ignore_ascii_case("content-type") {
+            content_type = Some(v.to_string());
+        }
+    }
+
+    Ok(Part {
+        name: name.ok_or_else(|| multipart_err("part has no name".to_string()))?,
+        filename,
+        content_type: content_type.unwrap_or_else(|| DEFAULT_PART_CONTENT_TYPE.to_string()),
+        data: Vec::new(),
+    })
+}
+
+#[inline]
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+
+    haystack[from..].windows(needle.len())
+        .position(|w| w.eq(needle))
+        .map(|i| i + from)
+}
+
+#[inline]
+fn multipart_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::BadRequest, format!("invalid multipart body: {}", message))
+}
@@ -2,53 +2,97 @@ use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::fmt::Display;
 use core::str::FromStr;
 
 use bytes::BytesMut;
-use http::{Extensions, HeaderMap, HeaderValue, Method, Uri, Version};
+use futures::future::{Either, select};
+use futures::FutureExt;
+use http::{Extensions, HeaderMap, HeaderValue, Method, StatusCode, Uri, Version};
 use http::header::AsHeaderName;
 use serde::de::DeserializeOwned;
+use sgx_trts::trts::rsgx_read_rand;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::SgxMutex;
 use std::time::Instant;
 use tungstenite::handshake::server::create_response;
 
 use crate::api::handler::codec::GLOBAL_CODEC;
-use crate::api::handler::context::Context;
+use crate::api::handler::context::{ConnState, Context};
+use crate::api::handler::multipart::{self, Part};
 use crate::api::handler::response::Response;
+use crate::api::handler::format::BodyFormat;
 use crate::api::handler::router::route_request;
+use crate::api::metrics::GLOBAL_METRICS;
+use crate::api::reactor::cancel::CancelToken;
 use crate::api::reactor::httpc::HttpcReactor;
+use crate::api::reactor::timer;
 use crate::api::results::{Error, ErrorKind, too_many_bytes_err};
 use crate::api::server::config::Config;
 use crate::api::server::connection::Deferral;
 use crate::api::server::websocket::WebSocket;
+use crate::utils::base64::decode_base64;
 
 static HEADER_CONNECTION_KEEPALIVE: &str = "keep-alive";
 static HEADER_CONNECTION_UPGRADE: &str = "upgrade";
 
 static HEADER_UPGRADE_WEBSOCKET: &str = "websocket";
 
+static HEADER_EXPECT_CONTINUE: &str = "100-continue";
+
+static AUTH_SCHEME_BASIC: &str = "Basic";
+static AUTH_SCHEME_BEARER: &str = "Bearer";
+
+static CONTENT_TYPE_FORM: &str = "application/x-www-form-urlencoded";
+
+pub(crate) static HEADER_REQUEST_ID: &str = "x-request-id";
+// Inbound ids longer than this are replaced rather than echoed.
+const REQUEST_ID_MAX_LEN: usize = 128;
+
+// Nesting allowed in JSON bodies unless the config says otherwise.
+const JSON_MAX_DEPTH: usize = 32;
+
 pub(crate) async fn process_raw_request(
+    config: Arc<Config>,
     deferral: Arc<SgxMutex<Deferral>>,
     httpc: Arc<SgxMutex<HttpcReactor>>,
+    conn_state: Arc<SgxMutex<ConnState>>,
+    cancel: CancelToken,
+    peer_addr: SocketAddr,
     raw_req: RawRequest,
 ) {
     let result = match raw_req.extract() {
-        Some(req) => {
+        Ok(mut req) => {
+            prepare_request(&config, &mut req, peer_addr);
+
             let mut res = Response::from_request(&req);
-            let mut ctx: Context = Context::new(req, httpc, None);
+            let mut ctx: Context = Context::new(req, deferral.clone(), httpc, None, conn_state,
+                                                 cancel.clone());
+
+            let deadline = ctx.request().received_at() + config.handler_timeout();
+            let result = route_with_deadline(&mut ctx, &mut res, deadline, &cancel).await;
+            GLOBAL_METRICS.observe_request_latency(
+                Instant::now().saturating_duration_since(ctx.request().received_at()));
+
+            match result {
+                Ok(_) => {
+                    if config.access_log() {
+                        log_access(ctx.request(), res.status_code(), res.body_len());
+                    }
+
+                    res.encode(&config)
+                }
+                Err(err) => {
+                    if config.access_log() {
+                        log_access(ctx.request(), err.http_status(), 0);
+                    }
 
-            match route_request(&mut ctx, &mut res).await {
-                Ok(_) => res.encode(),
-                Err(err) => Err(err)
+                    Err(err)
+                }
             }
         }
-        None => {
-            Err(Error::new_with_kind(
-                ErrorKind::ServerFault,
-                "failed to extract request from raw request".to_string(),
-            ))
-        }
+        Err(err) => Err(err)
     };
 
     match deferral.lock() {
@@ -76,25 +120,122 @@ pub(crate) async fn process_raw_request(
     }
 }
 
+/// Routes the request, giving up with a `HandlerTimedOut` (504) once
+/// `deadline` passes. The handler is dropped then and its cancel token
+/// tripped, for anything it handed the token to (see `Context::cancelled`).
+async fn route_with_deadline(ctx: &mut Context, res: &mut Response, deadline: Instant,
+                             cancel: &CancelToken) -> Result<(), Error> {
+    let timeout = deadline.saturating_duration_since(ctx.request().received_at());
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let routed = route_request(ctx, res).boxed();
+
+    match select(routed, timer::sleep(remaining)).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => {
+            cancel.cancel();
+
+            Err(Error::new_with_kind(
+                ErrorKind::HandlerTimedOut,
+                format!("handler timed out after {}ms", timeout.as_millis())))
+        }
+    }
+}
+
+#[inline]
+fn prepare_request(config: &Config, req: &mut Request, peer_addr: SocketAddr) {
+    req.peer_addr = Some(peer_addr);
+    req.trust_forwarded = config.trust_forwarded();
+
+    req.max_bytes = config.max_bytes_received();
+
+    if let Some(max_depth) = config.json_max_depth() {
+        req.set_json_max_depth(max_depth);
+    }
+}
+
+/// Random (v4) UUID, falling back to a nil id if the enclave can't read randomness.
+fn new_request_id() -> String {
+    let mut bytes = [0_u8; 16];
+    if let Err(err) = rsgx_read_rand(&mut bytes) {
+        warn!("failed to generate request id: {:?}", err);
+    }
+
+    uuid::Builder::from_bytes(bytes)
+        .set_variant(uuid::Variant::RFC4122)
+        .set_version(uuid::Version::Random)
+        .build()
+        .to_string()
+}
+
+/// Scans for the deepest object/array nesting, ignoring brackets in strings.
+fn check_json_depth(body: &[u8], max_depth: usize) -> Result<(), Error> {
+    let mut depth = 0_usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for b in body {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if *b == b'\\' {
+                escaped = true;
+            } else if *b == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match *b {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(bad_request_err(
+                        format!("json body nested too deeply (max depth: {})", max_depth)));
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn log_access(req: &Request, status: StatusCode, body_len: usize) {
+    info!("{} {} {} {}b {}ms {}",
+          req.method(), req.uri().path(), status.as_u16(), body_len,
+          Instant::now().saturating_duration_since(req.received_at()).as_millis(),
+          req.request_id());
+}
+
 pub(crate) async fn process_ws_raw_request(
+    config: Arc<Config>,
     deferral: Arc<SgxMutex<Deferral>>,
     httpc: Arc<SgxMutex<HttpcReactor>>,
+    conn_state: Arc<SgxMutex<ConnState>>,
+    cancel: CancelToken,
+    peer_addr: SocketAddr,
     raw_req: RawRequest,
 ) {
     let ws = Arc::new(SgxMutex::new(WebSocket::new(
         deferral.clone()
     )));
     let (result, ctx) = match raw_req.extract() {
-        Some(req) => {
+        Ok(mut req) => {
+            prepare_request(&config, &mut req, peer_addr);
+
             match create_response(req.request().into()) {
                 Ok(res) => {
                     let (parts, _) = res.into_parts();
                     let mut res = Response::from_request_and_parts(&req, parts);
-                    let mut ctx: Context = Context::new(req, httpc, Some(ws.clone()));
+                    let mut ctx: Context = Context::new(req, deferral.clone(), httpc, Some(ws.clone()),
+                                                         conn_state, cancel);
 
                     (
                         match route_request(&mut ctx, &mut res).await {
-                            Ok(_) => res.encode(),
+                            Ok(_) => res.encode(&config),
                             Err(err) => Err(err)
                         },
                         Some(ctx)
@@ -108,12 +249,7 @@ pub(crate) async fn process_ws_raw_request(
                 }
             }
         }
-        None => {
-            (Err(Error::new_with_kind(
-                ErrorKind::ServerFault,
-                "failed to extract ws request from raw request".to_string(),
-            )), None)
-        }
+        Err(err) => (Err(err), None)
     };
 
     match deferral.lock() {
@@ -122,6 +258,11 @@ pub(crate) async fn process_ws_raw_request(
                 match &result {
                     Ok(res) => {
                         if let Some(ctx) = ctx {
+                            if let Err(err) = conn.reserve_websocket() {
+                                conn.handle_error(&err);
+
+                                return Ok(());
+                            }
                             conn.send_response(res);
                             conn.websocket(ws.clone(), ctx)?;
                         } else {
@@ -155,22 +296,46 @@ pub(crate) struct RawRequest {
     data: BytesMut,
     // Total bytes read.
     bytes: usize,
+    max_bytes: usize,
+    max_headers: usize,
+    max_uri_length: usize,
+    max_header_bytes: usize,
     timeout: Option<Instant>,
+    // When the request became ready (for access logging).
+    ready_at: Option<Instant>,
     // Cached
     upgrade_websocket: bool,
     content_length: usize,
+    expect_continue: bool,
+    continue_sent: bool,
+    // Last request the connection will serve.
+    last: bool,
 }
 
 impl RawRequest {
     #[inline]
-    pub(crate) fn new(data: Vec<u8>, timeout: Instant) -> Result<Self, Error> {
+    pub(crate) fn new(data: Vec<u8>, timeout: Instant, max_bytes: usize,
+                      max_headers: usize, max_uri_length: usize,
+                      max_header_bytes: usize) -> Result<Self, Error> {
+        if data.len() > max_bytes {
+            return Err(too_many_bytes_err(data.len(), max_bytes));
+        }
+
         let mut req = Self {
             request: None,
             bytes: data.len(),
+            max_bytes,
+            max_headers,
+            max_uri_length,
+            max_header_bytes,
             data: BytesMut::from(data.as_slice()),
             timeout: Some(timeout),
+            ready_at: None,
             upgrade_websocket: false,
             content_length: 0,
+            expect_continue: false,
+            continue_sent: false,
+            last: false,
         };
         req.try_decode()?;
 
@@ -180,6 +345,13 @@ impl RawRequest {
     #[inline]
     pub(crate) fn next(&mut self, data: Vec<u8>) -> Result<(), Error> {
         if data.len() > 0 {
+            // Enforced on the running total, regardless of any Content-Length
+            // header, so slow-drip clients can't grow the buffer unbounded.
+            if self.bytes + data.len() > self.max_bytes {
+                return Err(too_many_bytes_err(self.bytes + data.len(),
+                                              self.max_bytes));
+            }
+
             self.bytes += data.len();
             self.push(data);
         }
@@ -197,6 +369,18 @@ impl RawRequest {
         self.upgrade_websocket
     }
 
+    /// True (once) when an HTTP/1.1 client sent `Expect: 100-continue` and is
+    /// still waiting on the body, the caller should send the interim response.
+    #[inline]
+    pub(crate) fn take_expect_continue(&mut self) -> bool {
+        if !self.expect_continue || self.continue_sent || self.ready() {
+            return false;
+        }
+        self.continue_sent = true;
+
+        true
+    }
+
     #[inline]
     pub(crate) fn ready(&self) -> bool {
         if self.request.is_none() {
@@ -212,16 +396,54 @@ impl RawRequest {
         return true;
     }
 
+    /// Splits off any bytes received beyond this request's body, these belong
+    /// to the next (pipelined) request on the same connection.
     #[inline]
-    pub(crate) fn extract(self) -> Option<Request> {
-        match self.request {
+    pub(crate) fn take_remainder(&mut self) -> Option<Vec<u8>> {
+        if self.request.is_none() || self.data.len() <= self.content_length {
+            return None;
+        }
+
+        let remainder = self.data.split_off(self.content_length);
+        self.bytes -= remainder.len();
+
+        Some(remainder.to_vec())
+    }
+
+    /// Closes the connection after the response to this request.
+    #[inline]
+    pub(crate) fn set_last(&mut self) {
+        self.last = true;
+    }
+
+    #[inline]
+    pub(crate) fn extract(mut self) -> Result<Request, Error> {
+        match self.request.take() {
             Some(req) => {
-                let body = self.data.to_vec();
-                let req = req.body(()).ok()?;
+                // `data` only holds what followed the head, limit it to the
+                // declared body in case the remainder wasn't taken.
+                let body_len = core::cmp::min(self.content_length, self.data.len());
+                let body = self.data.split_to(body_len).to_vec();
+                // The builder only fails on parts it couldn't parse (e.g. the URI).
+                let req = req.body(())
+                    .map_err(|e| bad_request_err(format!("invalid http request: {}", e)))?;
 
-                Some(Request::new(req, body, self.upgrade_websocket))
+                let mut req = Request::new(req, body, self.upgrade_websocket);
+                req.last = self.last;
+                req.received_at = self.ready_at.unwrap_or_else(Instant::now);
+                let request_id: Option<String> = req.header(HEADER_REQUEST_ID);
+                req.request_id = request_id
+                    .filter(|id| !id.is_empty() && id.len() <= REQUEST_ID_MAX_LEN)
+                    .unwrap_or_else(new_request_id);
+
+                Ok(req)
+            }
+            None => {
+                Err(Error::new_with_kind(
+                    ErrorKind::ServerFault,
+                    "failed to extract request from raw request".to_string(),
+                ))
             }
-            None => None,
         }
     }
 
@@ -261,11 +483,18 @@ impl RawRequest {
     #[inline]
     fn try_decode(&mut self) -> Result<(), Error> {
         if self.request.is_none() {
-            self.request = GLOBAL_CODEC.decode(&mut self.data)?;
+            self.request = GLOBAL_CODEC.decode(&mut self.data, self.max_headers,
+                                               self.max_uri_length,
+                                               self.max_header_bytes)?;
         }
 
         self.extract_upgrade_opts();
-        self.extract_content_length();
+        self.extract_content_length()?;
+        self.extract_expect_continue();
+
+        if self.ready_at.is_none() && self.ready() {
+            self.ready_at = Some(Instant::now());
+        }
 
         Ok(())
     }
@@ -288,20 +517,62 @@ impl RawRequest {
     }
 
     #[inline]
-    fn extract_content_length(&mut self) {
-        self.content_length = 0;
-
+    fn extract_expect_continue(&mut self) {
         if let Some(req) = self.request.as_ref() {
+            // HTTP/1.0 has no interim responses, the expectation is ignored.
+            if req.version_ref().map_or(true, |v| v.eq(&Version::HTTP_10)) {
+                return;
+            }
             if let Some(headers) = req.headers_ref() {
-                if let Some(val) = headers.get(http::header::CONTENT_LENGTH) {
-                    if let Ok(val) = val.to_str() {
-                        if let Ok(val) = str::parse::<usize>(val) {
-                            self.content_length = val;
-                        }
+                if has_header(headers, http::header::EXPECT, HEADER_EXPECT_CONTINUE) {
+                    self.expect_continue = true;
+                }
+            }
+        }
+    }
+
+    /// The body's length has to be beyond doubt, bytes we'd misjudge as the
+    /// end of this request would be served as the next one on a kept alive
+    /// connection (request smuggling). So no Transfer-Encoding, and every
+    /// Content-Length must be a valid and the same number.
+    #[inline]
+    fn extract_content_length(&mut self) -> Result<(), Error> {
+        self.content_length = 0;
+
+        let headers = match self.request.as_ref().and_then(|req| req.headers_ref()) {
+            Some(headers) => headers,
+            None => return Ok(()),
+        };
+
+        if headers.contains_key(http::header::TRANSFER_ENCODING) {
+            return Err(Error::new_with_kind(
+                ErrorKind::NotImplemented,
+                "transfer-encoding is not supported".to_string()));
+        }
+
+        let mut content_length: Option<usize> = None;
+        for val in headers.get_all(http::header::CONTENT_LENGTH).iter() {
+            let val = val.to_str()
+                .map_err(|_| bad_request_err("invalid content-length".to_string()))?;
+            // A list is allowed so long as every entry agrees.
+            for val in val.split(",").map(|v| v.trim()) {
+                if val.is_empty() || !val.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(bad_request_err(format!("invalid content-length: {:?}", val)));
+                }
+                let val = str::parse::<usize>(val)
+                    .map_err(|_| bad_request_err(format!("invalid content-length: {:?}", val)))?;
+
+                match content_length {
+                    Some(len) if len != val => {
+                        return Err(bad_request_err("conflicting content-length".to_string()));
                     }
+                    _ => content_length = Some(val),
                 }
             }
         }
+        self.content_length = content_length.unwrap_or(0);
+
+        Ok(())
     }
 }
 
@@ -310,6 +581,13 @@ pub struct Request {
     body: Vec<u8>,
     vars: Option<HashMap<String, String>>,
     websocket: bool,
+    received_at: Instant,
+    json_max_depth: usize,
+    peer_addr: Option<SocketAddr>,
+    trust_forwarded: bool,
+    request_id: String,
+    max_bytes: usize,
+    last: bool,
 }
 
 impl Request {
@@ -319,7 +597,57 @@ impl Request {
         body: Vec<u8>,
         websocket: bool,
     ) -> Self {
-        Self { req, body, vars: None, websocket }
+        Self {
+            req,
+            body,
+            vars: None,
+            websocket,
+            received_at: Instant::now(),
+            json_max_depth: JSON_MAX_DEPTH,
+            peer_addr: None,
+            trust_forwarded: false,
+            request_id: String::new(),
+            max_bytes: usize::MAX,
+            last: false,
+        }
+    }
+
+    /// Id for tracing the request, taken from `X-Request-Id` when the client
+    /// supplied one.
+    #[inline]
+    pub fn request_id(&self) -> &str {
+        self.request_id.as_str()
+    }
+
+    /// Address of the socket the request came in on.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Address of the client, taken from the first `X-Forwarded-For` hop when
+    /// the config trusts forwarded headers, otherwise the peer address.
+    #[inline]
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        if self.trust_forwarded {
+            let forwarded: Option<IpAddr> = self.headers()
+                .get("x-forwarded-for")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(",").next())
+                .and_then(|v| v.trim().parse().ok());
+
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+
+        self.peer_addr.map(|addr| addr.ip())
+    }
+
+    /// When the request was fully received.
+    #[inline]
+    pub fn received_at(&self) -> Instant {
+        self.received_at
     }
 
     #[inline]
@@ -350,6 +678,65 @@ impl Request {
             .ok()
     }
 
+    /// Like `var` but fails with a `BadRequest` naming the key when the
+    /// capture is missing or can't be parsed.
+    #[inline]
+    pub fn require_var<R>(&self, key: &str) -> Result<R, Error>
+        where
+            R: FromStr,
+    {
+        let val = self.vars.as_ref()
+            .and_then(|vars| vars.get(key))
+            .ok_or_else(|| bad_request_err(format!("missing path variable '{}'", key)))?;
+
+        val.parse()
+            .map_err(|_| bad_request_err(format!("invalid path variable '{}'", key)))
+    }
+
+    /// Like `require_var`, also failing with a `BadRequest` when the value
+    /// falls outside `[min, max]`.
+    #[inline]
+    #[allow(dead_code)]
+    pub fn var_in_range<R>(&self, key: &str, min: R, max: R) -> Result<R, Error>
+        where
+            R: FromStr + PartialOrd + Display,
+    {
+        let val: R = self.require_var(key)?;
+        if val < min || val > max {
+            return Err(bad_request_err(format!(
+                "path variable '{}' out of range ({} not in {}..={})", key, val, min, max)));
+        }
+
+        Ok(val)
+    }
+
+    #[inline]
+    pub fn query<R, S>(&self, key: S) -> Option<R>
+        where
+            R: FromStr,
+            S: Into<String>,
+    {
+        let key = key.into();
+        query_value(self.uri().query()?, key.as_str())?
+            .parse()
+            .ok()
+    }
+
+    /// Like `query` but fails with a `BadRequest` naming the key when the
+    /// parameter is missing or can't be parsed.
+    #[inline]
+    pub fn require_query<R>(&self, key: &str) -> Result<R, Error>
+        where
+            R: FromStr,
+    {
+        let val = self.uri().query()
+            .and_then(|query| query_value(query, key))
+            .ok_or_else(|| bad_request_err(format!("missing query parameter '{}'", key)))?;
+
+        val.parse()
+            .map_err(|_| bad_request_err(format!("invalid query parameter '{}'", key)))
+    }
+
     #[inline]
     pub fn header<R, K>(&self, key: K) -> Option<R>
         where
@@ -367,26 +754,202 @@ impl Request {
         has_header(self.headers(), key, val)
     }
 
+    /// Token from an `Authorization: Bearer <token>` header.
+    #[inline]
+    pub fn bearer_token(&self) -> Option<String> {
+        let token = auth_credentials(self.headers(), AUTH_SCHEME_BEARER)?;
+        if token.is_empty() {
+            return None;
+        }
+
+        Some(token.to_string())
+    }
+
+    /// User and password from an `Authorization: Basic <base64>` header.
+    #[inline]
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let encoded = auth_credentials(self.headers(), AUTH_SCHEME_BASIC)?;
+        let decoded = String::from_utf8(decode_base64(encoded)?).ok()?;
+        let mut parts = decoded.splitn(2, ":");
+
+        Some((parts.next()?.to_string(), parts.next()?.to_string()))
+    }
+
+    #[inline]
+    pub fn accepts_encoding(&self, encoding: &str) -> bool {
+        let accept = match self.headers().get(http::header::ACCEPT_ENCODING) {
+            Some(accept) => accept,
+            None => return false,
+        };
+        let accept = match accept.to_str() {
+            Ok(accept) => accept,
+            Err(_) => return false,
+        };
+
+        for part in accept.split(",") {
+            let mut params = part.split(";");
+            let name = params.next().unwrap_or("").trim();
+            if !name.eq_ignore_ascii_case(encoding) && name.ne("*") {
+                continue;
+            }
+
+            // An explicit q=0 means "not acceptable".
+            return !params.any(|p| {
+                let p = p.trim();
+                p.starts_with("q=") && match str::parse::<f32>(p[2..].trim()) {
+                    Ok(q) => q <= 0.0,
+                    Err(_) => false,
+                }
+            });
+        }
+
+        false
+    }
+
+    /// Media ranges from the Accept header, most preferred first. Ties keep
+    /// the order the client sent them in.
+    pub fn accept_ranked(&self) -> Vec<(String, f32)> {
+        match self.headers().get(http::header::ACCEPT).and_then(|a| a.to_str().ok()) {
+            Some(accept) => parse_accept(accept),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether the client will take `mime`, anything goes without an Accept
+    /// header.
+    #[allow(dead_code)]
+    pub fn accepts(&self, mime: &str) -> bool {
+        accept_quality(&self.accept_ranked(), mime) > 0.0
+    }
+
     #[inline]
     pub(crate) fn should_keep_alive(&self) -> bool {
+        if self.last {
+            return false;
+        }
+
         return self.version().ne(&Version::HTTP_10)
             || self.has_header_value(http::header::CONNECTION, HEADER_CONNECTION_KEEPALIVE);
     }
 
+    #[inline]
+    pub(crate) fn set_json_max_depth(&mut self, max_depth: usize) {
+        self.json_max_depth = max_depth;
+    }
+
     #[inline]
     pub(crate) fn json<T>(&self) -> Result<T, Error>
         where
             T: DeserializeOwned
     {
+        self.json_limited(self.json_max_depth)
+    }
+
+    /// Like `json` but rejects bodies nested deeper than `max_depth` with a
+    /// `BadRequest` before anything is deserialized. Bodies that don't parse
+    /// into `T` are a `BadRequest` too.
+    #[inline]
+    pub fn json_limited<T>(&self, max_depth: usize) -> Result<T, Error>
+        where
+            T: DeserializeOwned
+    {
+        check_json_depth(self.body.as_slice(), max_depth)?;
+
         let res: serde_json::Result<T> = serde_json::from_reader(self.body.as_slice());
         match res {
             Ok(res) => {
                 Ok(res)
             }
-            Err(err) => {
-                Err(Error::new_with_kind(ErrorKind::DecodeFault, err.to_string()))
+            // Whatever the client sent didn't match, serde's message carries
+            // the line and column.
+            Err(err) => Err(bad_request_err(format!("invalid json body: {}", err)))
+        }
+    }
+
+    /// The body as a JSON merge patch, for partial updates. Apply it to the
+    /// stored document with `merge_patch::apply_merge_patch`. Limited as `json`.
+    #[allow(dead_code)]
+    pub fn merge_patch(&self) -> Result<serde_json::Value, Error> {
+        self.json()
+    }
+
+    /// Deserializes the body in the format its Content-Type names, JSON when
+    /// there isn't one. Other content types are an `UnsupportedMediaType`.
+    #[allow(dead_code)]
+    pub fn decode<T>(&self) -> Result<T, Error>
+        where
+            T: DeserializeOwned
+    {
+        let content_type: Option<String> = self.header(http::header::CONTENT_TYPE);
+        let format = match content_type.as_ref() {
+            Some(content_type) => BodyFormat::from_content_type(content_type)
+                .ok_or_else(|| Error::new_with_kind(
+                    ErrorKind::UnsupportedMediaType,
+                    format!("unsupported content type: {:?}", content_type)))?,
+            None => BodyFormat::Json,
+        };
+
+        match format {
+            BodyFormat::Json => self.json(),
+            BodyFormat::Bincode => BodyFormat::deserialize_bincode(self.body.as_slice()),
+        }
+    }
+
+    /// Parses an `application/x-www-form-urlencoded` body, later duplicate
+    /// keys overwrite earlier ones.
+    #[inline]
+    pub fn form(&self) -> Result<HashMap<String, String>, Error> {
+        let content_type: Option<String> = self.header(http::header::CONTENT_TYPE);
+        let is_form = content_type.as_ref()
+            .and_then(|ct| ct.split(";").next())
+            .map(|ct| ct.trim().eq_ignore_ascii_case(CONTENT_TYPE_FORM))
+            .unwrap_or(false);
+        if !is_form {
+            return Err(Error::new_with_kind(
+                ErrorKind::DecodeFault,
+                format!("expected content type '{}', got: {:?}", CONTENT_TYPE_FORM, content_type)));
+        }
+
+        let body = core::str::from_utf8(self.body.as_slice())
+            .map_err(|e| Error::new_with_kind(
+                ErrorKind::DecodeFault, format!("form body is not utf-8: {}", e)))?;
+
+        let mut form = HashMap::new();
+        for pair in body.split("&").filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, "=");
+            let k = kv.next().and_then(decode_urlencoded);
+            let v = decode_urlencoded(kv.next().unwrap_or(""));
+
+            match (k, v) {
+                (Some(k), Some(v)) => {
+                    form.insert(k, v);
+                }
+                _ => {
+                    return Err(Error::new_with_kind(
+                        ErrorKind::DecodeFault,
+                        format!("malformed form field: {}", pair)));
+                }
             }
         }
+
+        Ok(form)
+    }
+
+    /// Parses a `multipart/form-data` body into its parts, malformed bodies
+    /// are rejected with a `BadRequest`.
+    #[inline]
+    pub fn multipart(&self) -> Result<Vec<Part>, Error> {
+        let content_type: String = self.header(http::header::CONTENT_TYPE)
+            .ok_or_else(|| bad_request_err("missing content type for multipart body".to_string()))?;
+        let boundary = multipart::boundary(content_type.as_str())?;
+
+        multipart::parse(self.body.as_slice(), boundary.as_str(), self.max_bytes)
+    }
+
+    /// The raw request body.
+    #[inline]
+    pub fn body_bytes(&self) -> &[u8] {
+        self.body.as_slice()
     }
 
     // Proxies
@@ -462,3 +1025,114 @@ fn has_header<K: AsHeaderName>(headers: &HeaderMap<HeaderValue>, key: K, val: &s
 
     false
 }
+
+// Credentials following `scheme` (matched case-insensitively) in the
+// Authorization header.
+fn auth_credentials<'a>(headers: &'a HeaderMap<HeaderValue>, scheme: &str) -> Option<&'a str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?.trim();
+    let mut parts = value.splitn(2, " ");
+    if !parts.next()?.eq_ignore_ascii_case(scheme) {
+        return None;
+    }
+
+    Some(parts.next()?.trim())
+}
+
+/// Splits an Accept header into `(media range, q)`, sorted by q descending.
+/// Malformed q values count as 1, q=0 ranges are kept (they exclude).
+pub(crate) fn parse_accept(accept: &str) -> Vec<(String, f32)> {
+    let mut ranges: Vec<(String, f32)> = accept.split(",")
+        .filter_map(|part| {
+            let mut params = part.split(";");
+            let range = params.next().unwrap_or("").trim();
+            if range.is_empty() {
+                return None;
+            }
+
+            let q = params
+                .map(|p| p.trim())
+                .find(|p| p.starts_with("q="))
+                .and_then(|p| str::parse::<f32>(p[2..].trim()).ok())
+                .unwrap_or(1.0)
+                .max(0.0)
+                .min(1.0);
+
+            Some((range.to_ascii_lowercase(), q))
+        })
+        .collect();
+
+    // Stable, so equal q keep their order.
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(core::cmp::Ordering::Equal));
+
+    ranges
+}
+
+/// q of the most specific range matching `mime` (parameters ignored), 0 when
+/// none does. Everything is acceptable when `ranges` is empty.
+pub(crate) fn accept_quality(ranges: &[(String, f32)], mime: &str) -> f32 {
+    if ranges.is_empty() {
+        return 1.0;
+    }
+
+    let mime = mime.split(";").next().unwrap_or("").trim().to_ascii_lowercase();
+    let kind = mime.split("/").next().unwrap_or("");
+
+    let mut best: Option<(u8, f32)> = None;
+    for (range, q) in ranges {
+        let specificity = if range.eq(&mime) {
+            2
+        } else if range.ends_with("/*") && range[..range.len() - 2].eq(kind) {
+            1
+        } else if range.eq("*/*") {
+            0
+        } else {
+            continue;
+        };
+
+        if best.map(|(s, _)| specificity > s).unwrap_or(true) {
+            best = Some((specificity, *q));
+        }
+    }
+
+    best.map(|(_, q)| q).unwrap_or(0.0)
+}
+
+#[inline]
+fn bad_request_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::BadRequest, message)
+}
+
+fn query_value(query: &str, key: &str) -> Option<String> {
+    for pair in query.split("&") {
+        let mut kv = pair.splitn(2, "=");
+        let k = decode_urlencoded(kv.next()?)?;
+        if k.eq(key) {
+            return decode_urlencoded(kv.next().unwrap_or(""));
+        }
+    }
+
+    None
+}
+
+/// Percent-decodes an `application/x-www-form-urlencoded` component ('+' is a space).
+pub(crate) fn decode_urlencoded(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)?;
+                let hex = core::str::from_utf8(hex).ok()?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8(out).ok()
+}
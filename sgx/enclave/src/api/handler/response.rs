@@ -3,19 +3,35 @@ use alloc::vec::Vec;
 use core::convert::TryFrom;
 
 use bytes::BytesMut;
-use http::{HeaderValue, StatusCode, Version};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use http::{HeaderMap, HeaderValue, Method, StatusCode, Version};
 use http::header::HeaderName;
 use http::response::{Parts};
 use serde::Serialize;
+use std::io::Write;
 
-use crate::api::handler::codec::GLOBAL_CODEC;
-use crate::api::handler::request::Request;
-use crate::api::results::{EncodedResponseResult, Error, ResponseBody};
+use crate::api::handler::codec::{allows_body, GLOBAL_CODEC};
+use crate::api::handler::format::{BodyFormat, CONTENT_TYPE_JSON, MIME_JSON};
+use crate::api::handler::request::{accept_quality, HEADER_REQUEST_ID, Request};
+use crate::api::results::{EncodedResponseResult, Error, ErrorKind, ResponseBody};
+use crate::api::server::config::Config;
+
+static ENCODING_GZIP: &str = "gzip";
+static ENCODING_DEFLATE: &str = "deflate";
+static CONNECTION_CLOSE: &str = "close";
+static CONNECTION_KEEP_ALIVE: &str = "keep-alive";
 
 pub(crate) struct Response {
     parts: Parts,
     body_bytes: Option<Vec<u8>>,
     close: bool,
+    head: bool,
+    accept_encoding: Option<ContentEncoding>,
+    // See `Request::accept_ranked`, used by `negotiate`.
+    accept: Vec<(String, f32)>,
+    if_none_match: Option<String>,
+    request_id: Option<String>,
 }
 
 impl Response {
@@ -29,6 +45,11 @@ impl Response {
             parts,
             body_bytes: None,
             close: true,
+            head: false,
+            accept_encoding: None,
+            accept: Vec::new(),
+            if_none_match: None,
+            request_id: None,
         }
     }
 
@@ -37,6 +58,13 @@ impl Response {
         let mut res = Self::new();
         res.version(req.version());
         res.close = !req.should_keep_alive();
+        res.head = req.method().eq(&Method::HEAD);
+        res.accept_encoding = ContentEncoding::from_request(req);
+        res.accept = req.accept_ranked();
+        if req.method().eq(&Method::GET) || req.method().eq(&Method::HEAD) {
+            res.if_none_match = req.header(http::header::IF_NONE_MATCH);
+        }
+        res.request_id = Some(req.request_id().to_string());
         res
     }
 
@@ -46,6 +74,11 @@ impl Response {
             parts,
             body_bytes: None,
             close: !req.should_keep_alive(),
+            head: false,
+            accept_encoding: None,
+            accept: Vec::new(),
+            if_none_match: None,
+            request_id: Some(req.request_id().to_string()),
         };
         res.version(req.version());
         res
@@ -55,11 +88,26 @@ impl Response {
     pub fn from_error(err: &Error)-> Self {
         let mut res = Self::new();
         let status = err.http_status();
+        // Bad requests say what was wrong, the client can fix those.
+        if let ErrorKind::BadRequest = err.kind() {
+            res.error(status, err.message()).unwrap();
+            return res;
+        }
+
         res.error(status,status.canonical_reason()
             .or(Some("General Fault")).unwrap()).unwrap();
         res
     }
 
+    /// Takes the status, headers and body from `other`, keeping the connection
+    /// level state (version, keep-alive, HEAD) of this response.
+    #[inline]
+    pub(crate) fn replace(&mut self, other: Response) {
+        self.parts.status = other.parts.status;
+        self.parts.headers = other.parts.headers;
+        self.body_bytes = other.body_bytes;
+    }
+
     #[inline]
     pub fn status<T>(&mut self, status: T) -> &mut Self
         where
@@ -70,6 +118,26 @@ impl Response {
         self
     }
 
+    #[inline]
+    pub fn status_code(&self) -> StatusCode {
+        self.parts.status
+    }
+
+    #[inline]
+    pub fn headers(&self) -> &HeaderMap<HeaderValue> {
+        &self.parts.headers
+    }
+
+    #[inline]
+    pub fn body_bytes(&self) -> Option<&[u8]> {
+        self.body_bytes.as_ref().map(|b| b.as_slice())
+    }
+
+    #[inline]
+    pub fn body_len(&self) -> usize {
+        self.body_bytes.as_ref().map(|b| b.len()).unwrap_or(0)
+    }
+
     #[inline]
     pub fn version(&mut self, version: Version) -> &mut Self {
         self.parts.version = version;
@@ -90,31 +158,129 @@ impl Response {
         self
     }
 
+    /// Sets the `ETag` header, the tag is quoted if it isn't already. A GET
+    /// carrying a matching `If-None-Match` is answered with a 304.
+    #[inline]
+    pub fn etag(&mut self, tag: &str) -> &mut Self {
+        if tag.starts_with('"') || tag.starts_with("W/") {
+            self.parts.headers.insert(http::header::ETAG, HeaderValue::from_str(tag).unwrap());
+        } else {
+            self.parts.headers.insert(http::header::ETAG,
+                                      HeaderValue::from_str(format!("\"{}\"", tag).as_str()).unwrap());
+        }
+        self
+    }
+
     #[inline]
     pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
         self.body_bytes = Some(body);
         self
     }
 
+    /// Data serde_json can't serialize (e.g. a map with non-string keys) is an
+    /// `EncodeFault`, the response is left as it was.
     #[inline]
     pub fn json<T: ?Sized + Serialize>(
         &mut self,
         data: &T,
-    ) -> Result<(), serde_json::Error> {
+    ) -> Result<(), Error> {
         match serde_json::to_vec(data) {
             Ok(res_body) => {
-                self.header(http::header::CONTENT_TYPE, "application/json")
+                self.default_content_type(CONTENT_TYPE_JSON)
                     .body(res_body);
 
                 Ok(())
             }
-            Err(e) => Err(e)
+            Err(e) => Err(Error::new_with_kind(
+                ErrorKind::EncodeFault,
+                format!("failed to serialize json response: {}", e)))
         }
     }
 
+    /// Renders `data` as whichever of JSON or the `alternates` (content type
+    /// and renderer) the client prefers. JSON wins ties, and is used when the
+    /// client accepts none of them. Sets `Vary: Accept`.
+    #[allow(dead_code)]
+    pub fn negotiate<T: ?Sized + Serialize>(
+        &mut self,
+        data: &T,
+        alternates: &[(&str, &dyn Fn(&T) -> Vec<u8>)],
+    ) -> Result<(), Error> {
+        self.vary_accept();
+
+        let mut best_q = accept_quality(&self.accept, MIME_JSON);
+        let mut best: Option<usize> = None;
+        for (idx, (content_type, _)) in alternates.iter().enumerate() {
+            let q = accept_quality(&self.accept, content_type);
+            if q > best_q {
+                best_q = q;
+                best = Some(idx);
+            }
+        }
+
+        match best {
+            Some(idx) => {
+                let (content_type, render) = alternates[idx];
+                self.bytes(content_type, render(data));
+
+                Ok(())
+            }
+            None => self.json(data),
+        }
+    }
+
+    /// Serializes `data` as JSON or bincode, whichever the request's Accept
+    /// header prefers (JSON on a tie), see `Request::decode`. Sets
+    /// `Vary: Accept`.
+    #[allow(dead_code)]
+    pub fn encode_as<T: ?Sized + Serialize>(&mut self, data: &T) -> Result<(), Error> {
+        self.vary_accept();
+
+        let format = BodyFormat::preferred(&self.accept);
+        let body = format.serialize(data)?;
+        self.bytes(format.content_type(), body);
+
+        Ok(())
+    }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn text(&mut self, s: &str) -> &mut Self {
+        self.bytes("text/plain; charset=utf-8", s.as_bytes().to_vec())
+    }
+
+    #[inline]
+    pub fn bytes(&mut self, content_type: &str, body: Vec<u8>) -> &mut Self {
+        self.default_content_type(content_type)
+            .body(body)
+    }
+
+    /// The body was picked by the request's Accept header, caches must key on
+    /// it too.
+    #[inline]
+    fn vary_accept(&mut self) {
+        let varies = self.parts.headers.get_all(http::header::VARY).iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(","))
+            .any(|v| v.trim().eq_ignore_ascii_case("accept"));
+        if !varies {
+            self.header(http::header::VARY, "Accept");
+        }
+    }
+
+    /// Body setters don't replace a Content-Type the handler set itself.
+    #[inline]
+    fn default_content_type(&mut self, content_type: &str) -> &mut Self {
+        if !self.parts.headers.contains_key(http::header::CONTENT_TYPE) {
+            self.header(http::header::CONTENT_TYPE, content_type);
+        }
+        self
+    }
+
     #[inline]
     pub fn ok(&mut self, msg: &str) -> Result<(), Error> {
-        self.json(&Msg { message: msg.to_string() }).unwrap();
+        self.parts.headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_JSON));
+        self.json(&Msg { message: msg.to_string() })?;
         self.status(StatusCode::OK);
 
         Ok(())
@@ -122,25 +288,99 @@ impl Response {
 
     #[inline]
     pub fn error(&mut self, status: StatusCode, msg: &str) -> Result<(), Error> {
-        self.json(&ErrorMsg { status: u16::from(status), message: msg.to_string() }).unwrap();
+        // Whatever the handler meant to send, this body is JSON.
+        self.parts.headers.insert(http::header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_JSON));
+        self.json(&ErrorMsg { status: u16::from(status), message: msg.to_string() })?;
+        self.status(status);
+
+        Ok(())
+    }
+
+    /// Redirects to `location`, `status` must be a 3xx.
+    #[allow(dead_code)]
+    pub fn redirect(&mut self, status: StatusCode, location: &str) -> Result<(), Error> {
+        if !status.is_redirection() {
+            return Err(Error::new_with_kind(
+                ErrorKind::ServerFault,
+                format!("redirect status must be 3xx, got: {}", status)));
+        }
+        let location = HeaderValue::from_str(location).map_err(|e| {
+            Error::new_with_kind(ErrorKind::ServerFault,
+                                 format!("invalid redirect location {:?}: {:?}", location, e))
+        })?;
+
+        self.parts.headers.insert(http::header::LOCATION, location);
+        self.body(Vec::new());
         self.status(status);
 
         Ok(())
     }
 
     #[inline]
-    pub fn encode(self) -> EncodedResponseResult {
+    pub fn encode(mut self, config: &Config) -> EncodedResponseResult {
         let mut encoded = BytesMut::new();
-        let res: http::Response<()> = http::Response::from_parts(self.parts, ());
 
-        let body = self.body_bytes.or(Some(Vec::new())).unwrap();
-        let content_length = if body.len() > 0 { body.len() + 2 } else { 0 };
+        // Runs after the handler, it's the one that sets the ETag.
+        if self.is_not_modified() {
+            self.parts.status = StatusCode::NOT_MODIFIED;
+            self.parts.headers.remove(http::header::CONTENT_TYPE);
+            self.body_bytes = None;
+        }
+
+        // Handlers win over the deployment wide defaults. Validated with the
+        // config, anything that still fails to parse is skipped.
+        for (name, value) in config.default_response_headers() {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()),
+                                            HeaderValue::from_str(value.as_str())) {
+                if !self.parts.headers.contains_key(&name) {
+                    self.parts.headers.insert(name, value);
+                }
+            }
+        }
+
+        // Set here so it survives handlers replacing the response.
+        if let Some(request_id) = self.request_id.take() {
+            if !self.parts.headers.contains_key(HEADER_REQUEST_ID) {
+                self.header(HEADER_REQUEST_ID, request_id);
+            }
+        }
+
+        // HTTP/1.0 clients only keep the connection open if told to.
+        if !self.parts.headers.contains_key(http::header::CONNECTION) {
+            if self.close {
+                self.header(http::header::CONNECTION, CONNECTION_CLOSE);
+            } else if self.parts.version == Version::HTTP_10 {
+                self.header(http::header::CONNECTION, CONNECTION_KEEP_ALIVE);
+            }
+        }
+
+        let mut body = self.body_bytes.take().or(Some(Vec::new())).unwrap();
+        if !allows_body(self.parts.status) && body.len() > 0 {
+            return Err(Error::new_with_kind(
+                ErrorKind::EncodeFault,
+                format!("response with status {} must not have a body", self.parts.status)));
+        }
+        // Compressed bodies must not carry the trailing CRLF, it would be
+        // handed to the client's decoder as garbage.
+        let mut trailer: &[u8] = b"\r\n";
+        if let Some(encoding) = self.negotiate_encoding(config, body.len()) {
+            body = encoding.compress(body.as_slice())?;
+            trailer = b"";
 
-        match GLOBAL_CODEC.encode(res, &mut encoded, content_length) {
+            self.header(http::header::CONTENT_ENCODING, encoding.as_str());
+            self.header(http::header::VARY, "Accept-Encoding");
+        }
+
+        let res: http::Response<()> = http::Response::from_parts(self.parts, ());
+        let content_length = if body.len() > 0 { body.len() + trailer.len() } else { 0 };
+
+        match GLOBAL_CODEC.encode(res, &mut encoded, content_length,
+                                 config.server_header()) {
             Ok(_) => {
-                if body.len() > 0 {
+                // HEAD responses carry the Content-Length of the GET, but no body.
+                if body.len() > 0 && !self.head {
                     encoded.extend_from_slice(body.as_slice());
-                    encoded.extend_from_slice(b"\r\n");
+                    encoded.extend_from_slice(trailer);
                 }
 
                 Ok(ResponseBody::new_with_close(encoded.to_vec(), self.close))
@@ -148,6 +388,90 @@ impl Response {
             Err(e) => Err(e)
         }
     }
+
+    // private
+
+    #[inline]
+    fn is_not_modified(&self) -> bool {
+        if !self.parts.status.is_success() {
+            return false;
+        }
+        let if_none_match = match self.if_none_match.as_ref() {
+            Some(if_none_match) => if_none_match,
+            None => return false,
+        };
+        let etag = match self.parts.headers.get(http::header::ETAG)
+            .and_then(|v| v.to_str().ok()) {
+            Some(etag) => etag,
+            None => return false,
+        };
+
+        // Weak comparison, as required for If-None-Match.
+        let etag = etag.trim_start_matches("W/");
+        if_none_match.split(",").any(|tag| {
+            let tag = tag.trim();
+            tag.eq("*") || tag.trim_start_matches("W/").eq(etag)
+        })
+    }
+
+    #[inline]
+    fn negotiate_encoding(&self, config: &Config, body_len: usize) -> Option<ContentEncoding> {
+        let min_bytes = config.compression_min_bytes()?;
+        if body_len == 0 || body_len < min_bytes {
+            return None;
+        }
+        if self.parts.headers.contains_key(http::header::CONTENT_ENCODING) {
+            // Handler already encoded the body itself.
+            return None;
+        }
+
+        self.accept_encoding
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum ContentEncoding {
+    Gzip,
+    Deflate,
+}
+
+impl ContentEncoding {
+    #[inline]
+    fn from_request(req: &Request) -> Option<Self> {
+        if req.accepts_encoding(ENCODING_GZIP) {
+            Some(ContentEncoding::Gzip)
+        } else if req.accepts_encoding(ENCODING_DEFLATE) {
+            Some(ContentEncoding::Deflate)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => ENCODING_GZIP,
+            ContentEncoding::Deflate => ENCODING_DEFLATE,
+        }
+    }
+
+    fn compress(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let res = match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body).and_then(|_| encoder.finish())
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body).and_then(|_| encoder.finish())
+            }
+        };
+
+        res.map_err(|e| {
+            Error::new_with_kind(ErrorKind::EncodeFault,
+                                 format!("failed to {} response body: {:?}", self.as_str(), e))
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize)]
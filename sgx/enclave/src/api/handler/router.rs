@@ -10,6 +10,7 @@ use std::path::PathBuf;
 use std::sync::SgxRwLock;
 use crate::api::handler::context::Context;
 
+use crate::api::handler::request::Request;
 use crate::api::handler::response::Response;
 use crate::api::handler::routes::ROUTER;
 use crate::api::results::Error;
@@ -20,6 +21,8 @@ pub(crate) type Handler = Arc<dyn Send + Sync + for<'a> Fn(&'a mut Context, &'a
 pub(crate) type HandlerFn = for<'a> fn(&'a mut Context, &'a mut Response) -> BoxFuture<'a, Result<(), Error>>;
 pub(crate) type Middleware = Arc<dyn Send + Sync + for<'a> Fn(&'a mut Context, &'a mut Response, Handler) -> BoxFuture<'a, Result<(), Error>>>;
 pub(crate) type MiddlewareFn = for<'a> fn(&'a mut Context, &'a mut Response, Handler) -> BoxFuture<'a, Result<(), Error>>;
+pub(crate) type ErrorHandler = Arc<dyn Send + Sync + Fn(&Request, &Error) -> Option<Response>>;
+pub(crate) type ErrorHandlerFn = fn(&Request, &Error) -> Option<Response>;
 
 #[inline]
 pub(crate) async fn route_request(ctx: &mut Context, res: &mut Response) -> Result<(), Error> {
@@ -28,23 +31,73 @@ pub(crate) async fn route_request(ctx: &mut Context, res: &mut Response) -> Resu
         (req.method(), req.uri().path())
     };
 
-    match ROUTER.clone().find(method, path) {
+    let found = match ROUTER.clone().find(method, path) {
+        Some(found) => Some(found),
+        // HEAD is served by the GET handler, the body is dropped on encode.
+        None if method.eq(&Method::HEAD) => ROUTER.clone().find(&Method::GET, path),
+        // CORS preflight, run the middleware of the route being requested.
+        None if method.eq(&Method::OPTIONS) => {
+            let requested: Option<Method> = ctx.request()
+                .header(http::header::ACCESS_CONTROL_REQUEST_METHOD);
+
+            match requested {
+                Some(requested) => match ROUTER.clone().find(&requested, path) {
+                    Some((handler, captures)) => {
+                        ctx.request_mut().vars(captures);
+
+                        return handler.preflight(ctx, res).await;
+                    }
+                    None => None
+                },
+                // Plain OPTIONS, list what the path supports.
+                None => {
+                    let methods = ROUTER.clone().allowed_methods(path);
+                    if methods.is_empty() {
+                        return route_not_found(ctx, res).await;
+                    }
+
+                    let mut allow: Vec<&str> = methods.iter().map(|m| m.as_str()).collect();
+                    allow.push(Method::OPTIONS.as_str());
+
+                    res.status(StatusCode::NO_CONTENT)
+                        .header(http::header::ALLOW, allow.join(", "));
+
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    match found {
         Some((handler, captures)) => {
             ctx.request_mut().vars(captures);
 
             handler.route(ctx, res).await
         }
-        None => {
-            res.error(StatusCode::NOT_FOUND, "Not Found")
-        }
+        None => route_not_found(ctx, res).await
+    }
+}
+
+/// The handler set with `Router::not_found`, or a JSON 404.
+#[inline]
+async fn route_not_found(ctx: &mut Context, res: &mut Response) -> Result<(), Error> {
+    match ROUTER.clone().not_found_handler() {
+        Some(handler) => handler.route(ctx, res).await,
+        None => res.error(StatusCode::NOT_FOUND, "Not Found"),
     }
 }
 
 pub(crate) struct Router {
     top: Option<Arc<SgxRwLock<Router>>>,
     routes: Option<HashMap<String, RouteHandler>>,
+    // Unique keys of `routes` by path segment, kept alongside them.
+    tree: Option<RouteNode>,
     path: Option<PathBuf>,
     middleware: Vec<Middleware>,
+    error_handlers: Vec<ErrorHandler>,
+    // Only set on the top router.
+    not_found: Option<RouteHandler>,
 }
 
 impl Router {
@@ -53,15 +106,21 @@ impl Router {
         let top = Self {
             top: None,
             routes: Some(HashMap::new()),
+            tree: Some(RouteNode::default()),
             path: None,
             middleware: Vec::new(),
+            error_handlers: Vec::new(),
+            not_found: None,
         };
 
         Self {
             top: Some(Arc::new(SgxRwLock::new(top))),
             routes: None,
+            tree: None,
             path: None,
             middleware: Vec::new(),
+            error_handlers: Vec::new(),
+            not_found: None,
         }
     }
 
@@ -71,13 +130,69 @@ impl Router {
         let r = Router {
             top: self.top.clone(),
             routes: None,
+            tree: None,
             path: self.push_path(path),
             middleware: self.middleware.clone(),
+            error_handlers: self.error_handlers.clone(),
+            not_found: None,
         };
 
         func(r);
     }
 
+    /// Adds every route of `sub` under `prefix` (relative to this router's
+    /// path). The routes run this router's middleware and error handlers
+    /// first, followed by the ones `sub` registered them with.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn mount(&mut self, prefix: &str, sub: Router) -> &mut Self {
+        let prefix = self.push_path(prefix).unwrap();
+        let prefix = prefix.to_str().unwrap();
+
+        for (_unique, route) in sub.take_routes() {
+            let route = route.rebase(prefix, &self.middleware, &self.error_handlers);
+            self.with_top(|top| {
+                top.insert_route_from_top(route);
+            });
+        }
+
+        self
+    }
+
+    /// Registers a handler consulted when a route (or its middleware) returns
+    /// an error. The first handler to return a `Response` wins, handlers
+    /// registered in the innermost scope are consulted first.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn on_error(&mut self, handler: ErrorHandlerFn) -> &mut Self {
+        self.on_error_raw(Arc::new(handler))
+    }
+
+    #[allow(dead_code)]
+    #[inline]
+    pub fn on_error_raw(&mut self, handler: ErrorHandler) -> &mut Self {
+        self.error_handlers.push(handler);
+        self
+    }
+
+    /// Handles requests no route matches, in place of the JSON 404. It runs
+    /// the middleware and error handlers of the scope it's registered in,
+    /// replacing any fallback set before.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn not_found(&mut self, handler: HandlerFn) -> &mut Self {
+        // The method and path are never matched against.
+        let route_handler = RouteHandler::new(
+            Method::GET, "/", Arc::new(handler),
+            self.middleware.clone(), self.error_handlers.clone());
+
+        self.with_top(move |top| {
+            top.not_found = Some(route_handler);
+        });
+
+        self
+    }
+
     #[allow(dead_code)]
     #[inline]
     pub fn require(&mut self, middleware: MiddlewareFn) -> &mut Self {
@@ -137,73 +252,85 @@ impl Router {
         where
             String: From<P>
     {
-        match self.routes.as_ref() {
-            Some(routes) => {
-                let method = method.as_str();
+        match (self.routes.as_ref(), self.tree.as_ref()) {
+            (Some(routes), Some(tree)) => {
                 let path = path_into_trimmed_string(path);
                 let path_parts: Vec<&str> = path.split("/")
                     .filter(|p| { !p.is_empty() })
                     .collect();
 
-                let mut handler: Option<&RouteHandler> = None;
-                let mut captures: HashMap<String, String> = HashMap::new();
-
-                for (_unique, cur) in routes.into_iter() {
-                    if cur.method.ne(method) {
-                        continue;
-                    }
-                    if cur.tokens.len() != path_parts.len() {
-                        continue;
-                    }
+                let cur = tree.find(&path_parts, &mut |unique| {
+                    routes.get(unique).filter(|cur| cur.method.eq(method))
+                })?;
+                let captures = cur.matches(&path_parts)?;
 
-                    let mut skipped = false;
-                    let mut cur_i: usize = 0;
-                    for token in cur.tokens.iter() {
-                        match path_parts.get(cur_i) {
-                            Some(p) => {
-                                match token {
-                                    RouteHandlerToken::Path { value } => {
-                                        if value.ne(p) {
-                                            skipped = true;
-                                            break;
-                                        }
-                                    }
-                                    RouteHandlerToken::Capture { name } => {
-                                        captures.insert(name.clone(), p.to_string());
-                                    }
-                                }
+                Some((cur.clone(), captures))
+            }
+            _ => {
+                match self.top.as_ref() {
+                    Some(top) => {
+                        match top.write() {
+                            Ok(top) => {
+                                top.find(method, path)
                             }
-                            None => {
-                                skipped = true;
-                                break;
+                            Err(e) => {
+                                unreachable!("Route failed to get top write lock!: {}", e);
                             }
                         }
-                        cur_i += 1;
                     }
-                    if skipped {
-                        captures.clear();
-                        continue;
+                    _ => {
+                        unreachable!("Invalid state: Route with no routes or top!");
                     }
-
-                    handler = Some(cur);
-                    break;
                 }
+            }
+        }
+    }
 
-                if let Some(handler) = handler {
-                    return Some((handler.clone(), captures));
+    pub fn not_found_handler(&self) -> Option<RouteHandler> {
+        match self.top.as_ref() {
+            Some(top) => {
+                match top.read() {
+                    Ok(top) => top.not_found.clone(),
+                    Err(e) => {
+                        unreachable!("Route failed to get top read lock!: {}", e);
+                    }
                 }
+            }
+            None => self.not_found.clone(),
+        }
+    }
 
-                return None;
+    /// Methods with a route matching `path`, in a stable order.
+    pub fn allowed_methods<P>(&self, path: P) -> Vec<Method>
+        where
+            String: From<P>
+    {
+        match (self.routes.as_ref(), self.tree.as_ref()) {
+            (Some(routes), Some(tree)) => {
+                let path = path_into_trimmed_string(path);
+                let path_parts: Vec<&str> = path.split("/")
+                    .filter(|p| { !p.is_empty() })
+                    .collect();
+
+                let mut uniques: Vec<&String> = Vec::new();
+                tree.find_all(&path_parts, &mut uniques);
+
+                let mut methods: Vec<Method> = uniques.into_iter()
+                    .filter_map(|unique| routes.get(unique))
+                    .map(|cur| cur.method.clone())
+                    .collect();
+                methods.sort_by_key(|m| method_order(m));
+                methods.dedup();
+
+                methods
             }
             _ => {
                 match self.top.as_ref() {
                     Some(top) => {
-                        match top.write() {
-                            Ok(top) => {
-                                top.find(method, path)
-                            }
+                        match top.read() {
+                            Ok(top) => top.allowed_methods(path),
                             Err(e) => {
-                                unreachable!("Route failed to get top write lock!: {}", e);
+                                unreachable!("Route failed to get top read lock!: {}", e);
                             }
                         }
                     }
@@ -232,12 +359,26 @@ impl Router {
     }
 
     fn add_route(&mut self, method: Method, path: PathBuf, handler: Handler) -> &mut Self {
+        let middleware = self.middleware.clone();
+        let error_handlers = self.error_handlers.clone();
+
+        self.with_top(move |top| {
+            top.add_route_from_top(method, path, handler, middleware, error_handlers);
+        });
+
+        self
+    }
+
+    #[inline]
+    fn with_top<F>(&self, func: F)
+        where
+            F: FnOnce(&mut Router)
+    {
         match self.top.as_ref() {
             Some(top) => {
                 match top.write() {
                     Ok(mut top) => {
-                        top.add_route_from_top(method, path, handler,
-                                               self.middleware.clone());
+                        func(&mut top);
                     }
                     Err(e) => {
                         unreachable!("Route failed to get top write lock!: {}", e);
@@ -248,29 +389,51 @@ impl Router {
                 unreachable!("Invalid state: Route with no routes or top!");
             }
         }
+    }
 
-        self
+    fn take_routes(&self) -> HashMap<String, RouteHandler> {
+        let mut routes = HashMap::new();
+        self.with_top(|top| {
+            if let Some(top_routes) = top.routes.as_mut() {
+                routes = core::mem::take(top_routes);
+            }
+            if let Some(tree) = top.tree.as_mut() {
+                *tree = RouteNode::default();
+            }
+        });
+
+        routes
     }
 
     fn add_route_from_top(&mut self, method: Method, path: PathBuf,
                           handler: Handler, middleware: Vec<Middleware>,
+                          error_handlers: Vec<ErrorHandler>,
     ) -> &mut Self {
         if self.top.is_some() {
             unreachable!("Cannot call add_route_from_top unless top.")
         }
 
-        match self.routes.as_mut() {
-            Some(routes) => {
-                let path = path.to_str().unwrap();
-                let route_handler =
-                    RouteHandler::new(method.clone(), path, handler,
-                                      middleware);
+        let path = path.to_str().unwrap();
+        let route_handler =
+            RouteHandler::new(method.clone(), path, handler,
+                              middleware, error_handlers);
+
+        self.insert_route_from_top(route_handler)
+    }
 
+    fn insert_route_from_top(&mut self, route_handler: RouteHandler) -> &mut Self {
+        if self.top.is_some() {
+            unreachable!("Cannot call insert_route_from_top unless top.")
+        }
+
+        match (self.routes.as_mut(), self.tree.as_mut()) {
+            (Some(routes), Some(tree)) => {
                 match routes.get(&route_handler.unique) {
                     None => {
-                        routes.insert(route_handler.unique.clone(), route_handler);
+                        debug!("🔄 added route: {}", route_handler.unique);
 
-                        debug!("🔄 added route: {} {}", method, path);
+                        tree.insert(&route_handler.tokens, route_handler.unique.clone());
+                        routes.insert(route_handler.unique.clone(), route_handler);
                     }
                     Some(_) => {
                         panic!("duplicate route detected: {}", route_handler.unique);
@@ -293,11 +456,13 @@ pub(crate) struct RouteHandler {
     tokens: Vec<RouteHandlerToken>,
     handler: Handler,
     middleware: Arc<Vec<Middleware>>,
+    error_handlers: Arc<Vec<ErrorHandler>>,
 }
 
 impl RouteHandler {
     #[inline]
-    fn new<P>(method: Method, path: P, handler: Handler, middleware: Vec<Middleware>) -> Self
+    fn new<P>(method: Method, path: P, handler: Handler, middleware: Vec<Middleware>,
+              error_handlers: Vec<ErrorHandler>) -> Self
         where
             String: From<P>
     {
@@ -310,18 +475,111 @@ impl RouteHandler {
             tokens,
             handler,
             middleware: Arc::new(middleware),
+            error_handlers: Arc::new(error_handlers),
+        }
+    }
+
+    /// Captures for `path_parts` if they match this route's tokens.
+    fn matches(&self, path_parts: &[&str]) -> Option<HashMap<String, String>> {
+        if self.tokens.len() != path_parts.len() {
+            return None;
+        }
+
+        let mut captures: HashMap<String, String> = HashMap::new();
+        for (token, part) in self.tokens.iter().zip(path_parts.iter()) {
+            match token {
+                RouteHandlerToken::Path { value } => {
+                    if value.ne(part) {
+                        return None;
+                    }
+                }
+                RouteHandlerToken::Capture { name } => {
+                    captures.insert(name.clone(), part.to_string());
+                }
+            }
+        }
+
+        Some(captures)
+    }
+
+    /// Copy of this route under `prefix`, wrapped in the given middleware
+    /// and error handlers.
+    fn rebase(&self, prefix: &str, middleware: &[Middleware],
+              error_handlers: &[ErrorHandler]) -> Self {
+        let (unique, mut tokens) =
+            extract_route_handler_tokens(self.method.clone(), prefix);
+        tokens.extend(self.tokens.iter().cloned());
+
+        // Unique keys start with the method, followed by the path parts.
+        let rest = &self.unique[self.method.as_str().len()..];
+
+        let mut all_middleware = middleware.to_vec();
+        all_middleware.extend(self.middleware.iter().cloned());
+        let mut all_error_handlers = error_handlers.to_vec();
+        all_error_handlers.extend(self.error_handlers.iter().cloned());
+
+        Self {
+            unique: format!("{}{}", unique, rest),
+            method: self.method.clone(),
+            tokens,
+            handler: self.handler.clone(),
+            middleware: Arc::new(all_middleware),
+            error_handlers: Arc::new(all_error_handlers),
         }
     }
 
     #[inline]
     async fn route(&self, ctx: &mut Context, res: &mut Response) -> Result<(), Error> {
-        if self.middleware.len() > 0 {
+        let result = if self.middleware.len() > 0 {
             _invoke_middleware(ctx, res, self.middleware.clone(), 0,
                                self.handler.clone()).await
         } else {
             self.handler.clone()(ctx, res).await
+        };
+
+        match result {
+            Err(err) => self.map_error(ctx, res, err),
+            _ => result
         }
     }
+
+    /// Runs only the middleware, for an `OPTIONS` request with no route of
+    /// its own. Middleware that doesn't handle it ends in an empty 204.
+    #[inline]
+    async fn preflight(&self, ctx: &mut Context, res: &mut Response) -> Result<(), Error> {
+        let handler: Handler = Arc::new(|_ctx, res| Box::pin(async move {
+            res.status(StatusCode::NO_CONTENT);
+
+            Ok(())
+        }));
+
+        let result = if self.middleware.len() > 0 {
+            _invoke_middleware(ctx, res, self.middleware.clone(), 0,
+                               handler).await
+        } else {
+            handler(ctx, res).await
+        };
+
+        match result {
+            Err(err) => self.map_error(ctx, res, err),
+            _ => result
+        }
+    }
+
+    #[inline]
+    fn map_error(&self, ctx: &mut Context, res: &mut Response, err: Error) -> Result<(), Error> {
+        for error_handler in self.error_handlers.iter().rev() {
+            if let Some(mapped) = error_handler(ctx.request(), &err) {
+                warn!("failed to handle request (mapped by error handler): {}", err);
+
+                res.replace(mapped);
+
+                return Ok(());
+            }
+        }
+
+        Err(err)
+    }
 }
 
 #[inline]
@@ -358,6 +616,80 @@ fn _invoke_middleware<'a>(
     })
 }
 
+#[inline]
+fn method_order(method: &Method) -> usize {
+    [Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::PATCH, Method::DELETE]
+        .iter()
+        .position(|m| m.eq(method))
+        .unwrap_or(usize::MAX)
+}
+
+/// Routes indexed by path segment, so a lookup walks the request's segments
+/// rather than every route. Literal segments are tried before captures,
+/// falling back to the capture when the literal branch has no match.
+#[derive(Default)]
+struct RouteNode {
+    children: HashMap<String, RouteNode>,
+    capture: Option<Box<RouteNode>>,
+    // Unique keys of the routes ending here (one per method).
+    routes: Vec<String>,
+}
+
+impl RouteNode {
+    fn insert(&mut self, tokens: &[RouteHandlerToken], unique: String) {
+        let mut node = self;
+        for token in tokens.iter() {
+            node = match token {
+                RouteHandlerToken::Path { value } => {
+                    node.children.entry(value.clone()).or_default()
+                }
+                RouteHandlerToken::Capture { .. } => {
+                    &mut **node.capture.get_or_insert_with(Default::default)
+                }
+            };
+        }
+
+        node.routes.push(unique);
+    }
+
+    /// First route matching `path_parts` that `accept` returns.
+    fn find<'a, F>(&self, path_parts: &[&str], accept: &mut F) -> Option<&'a RouteHandler>
+        where
+            F: FnMut(&String) -> Option<&'a RouteHandler>
+    {
+        let (part, rest) = match path_parts.split_first() {
+            Some(split) => split,
+            None => return self.routes.iter().find_map(|unique| accept(unique)),
+        };
+
+        if let Some(child) = self.children.get(*part) {
+            if let Some(found) = child.find(rest, accept) {
+                return Some(found);
+            }
+        }
+
+        self.capture.as_ref()?.find(rest, accept)
+    }
+
+    /// Every route matching `path_parts`, whatever the method.
+    fn find_all<'a>(&'a self, path_parts: &[&str], found: &mut Vec<&'a String>) {
+        let (part, rest) = match path_parts.split_first() {
+            Some(split) => split,
+            None => {
+                found.extend(self.routes.iter());
+                return;
+            }
+        };
+
+        if let Some(child) = self.children.get(*part) {
+            child.find_all(rest, found);
+        }
+        if let Some(capture) = self.capture.as_ref() {
+            capture.find_all(rest, found);
+        }
+    }
+}
+
 #[derive(Clone)]
 enum RouteHandlerToken {
     Path { value: String },
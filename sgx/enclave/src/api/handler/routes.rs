@@ -1,14 +1,22 @@
 use alloc::boxed::Box;
-use alloc::string::String;
+use alloc::string::{String, ToString};
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 
+use http::StatusCode;
 use lazy_static::lazy_static;
+use sgx_types::sgx_target_info_t;
 use tungstenite::Message;
 
 use crate::api::handler::context::Context;
 use crate::api::handler::response::Response;
 use crate::api::handler::router::Router;
+use crate::api::metrics::GLOBAL_METRICS;
 use crate::api::middleware::recovery::middleware_recovery;
+use crate::api::results::{Error, ErrorKind};
+use crate::crypto::attest::{create_report, MAX_NONCE_LEN};
+use crate::external::db::db_ping;
+use crate::utils::base64::{decode_base64, encode_base64};
 
 lazy_static! {
     pub(crate) static ref ROUTER: Arc<Router> = Arc::new(build_routes());
@@ -20,6 +28,19 @@ struct TestPayload {
     pub email: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct AttestRequest {
+    /// Base64, bound into the report's `report_data`.
+    pub nonce: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AttestResponse {
+    pub nonce: String,
+    /// Base64 encoded `sgx_report_t`.
+    pub report: String,
+}
+
 fn build_routes() -> Router {
     let mut r = Router::new();
 
@@ -41,19 +62,34 @@ fn build_routes() -> Router {
             panic!("YELP");
         }));
 
-        r.post("/post", |ctx: &mut Context, res: &mut Response|
-            Box::pin(async move {
-                let req = ctx.request();
-                let content_type: Option<String> = req.header(http::header::CONTENT_TYPE);
-                let test_val: Option<&String> = ctx.get("test");
-                let payload: TestPayload = req.json()?;
+        // Only this route decodes a body, so only it maps decode faults.
+        r.route("/post", |mut r| {
+            r.on_error(|_req, err| {
+                match err.kind() {
+                    ErrorKind::DecodeFault => {
+                        let mut res = Response::new();
+                        res.error(StatusCode::UNPROCESSABLE_ENTITY, "Unprocessable Entity").ok()?;
 
-                error!("Content-Type: {:?}", content_type);
-                error!("test value: {:?}", test_val);
-                error!("Payload: {:?}", payload);
+                        Some(res)
+                    }
+                    _ => None
+                }
+            });
 
-                res.ok("Ok")
-            }));
+            r.post("/", |ctx: &mut Context, res: &mut Response|
+                Box::pin(async move {
+                    let req = ctx.request();
+                    let content_type: Option<String> = req.header(http::header::CONTENT_TYPE);
+                    let test_val: Option<&String> = ctx.get("test");
+                    let payload: TestPayload = req.json()?;
+
+                    error!("Content-Type: {:?}", content_type);
+                    error!("test value: {:?}", test_val);
+                    error!("Payload: {:?}", payload);
+
+                    res.ok("Ok")
+                }));
+        });
 
         r.get("/fetch", |ctx: &mut Context, res: &mut Response|
             Box::pin(async move {
@@ -73,6 +109,41 @@ fn build_routes() -> Router {
             }));
     });
 
+    r.get("/healthz", |_ctx, res| Box::pin(async move {
+        match db_ping() {
+            Ok(()) => res.ok("OK"),
+            Err(err) => {
+                warn!("health check failed to reach db: {}", err);
+
+                res.error(StatusCode::SERVICE_UNAVAILABLE, "Database Unavailable")
+            }
+        }
+    }));
+
+    r.get("/metrics", |_ctx, res| Box::pin(async move {
+        res.json(&GLOBAL_METRICS.snapshot())
+    }));
+
+    r.post("/attest", |ctx, res| Box::pin(async move {
+        let payload: AttestRequest = ctx.request().json()?;
+        let nonce = decode_base64(payload.nonce.as_str())
+            .ok_or_else(|| Error::new_with_kind(
+                ErrorKind::BadRequest, "nonce is not valid base64".to_string()))?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(Error::new_with_kind(ErrorKind::BadRequest,
+                format!("nonce must be 1-{} bytes", MAX_NONCE_LEN)));
+        }
+
+        // TODO: target the quoting enclave once quotes are fetched from the host.
+        let report = create_report(nonce.as_slice(), &sgx_target_info_t::default())
+            .map_err(|e| Error::new_with_kind(ErrorKind::ServerFault, e))?;
+
+        res.json(&AttestResponse {
+            nonce: payload.nonce,
+            report: encode_base64(report.as_slice()),
+        })
+    }));
+
     r.get("/ping", |_ctx, res| Box::pin(async move {
         res.ok("PONG")
     }));
@@ -83,19 +154,43 @@ fn build_routes() -> Router {
 
     r.get("/hello/:name", |ctx, res| Box::pin(async move {
         let req = ctx.request();
-        let name: Option<String> = req.var("name");
+        let name: String = req.require_var("name")?;
 
-        res.ok(format!("Hello {}", name.unwrap()).as_str())
+        res.ok(format!("Hello {}", name).as_str())
     }));
 
     r.get("/calc/:a/:b", |ctx, res| Box::pin(async move {
         let req = ctx.request();
-        let a: Option<u32> = req.var("a");
-        let b: Option<u32> = req.var("b");
+        let a: u32 = req.require_var("a")?;
+        let b: u32 = req.require_var("b")?;
 
-        res.ok(format!("Sum {}", a.unwrap() + b.unwrap()).as_str())
+        res.ok(format!("Sum {}", a.saturating_add(b)).as_str())
     }));
 
+    r.route("/kv", |mut r| {
+        r.get("/:key", |ctx, res| Box::pin(async move {
+            let key: String = ctx.request().require_var("key")?;
+
+            match ctx.db_get(kv_key(&key).as_slice()).await? {
+                Some(value) => {
+                    res.bytes("application/octet-stream", value);
+
+                    Ok(())
+                }
+                None => res.error(StatusCode::NOT_FOUND, "Not Found")
+            }
+        }));
+
+        r.put("/:key", |ctx, res| Box::pin(async move {
+            let key: String = ctx.request().require_var("key")?;
+            let value = ctx.request().body_bytes().to_vec();
+
+            ctx.db_put(kv_key(&key).as_slice(), value.as_slice()).await?;
+
+            res.ok("Ok")
+        }));
+    });
+
     r.get("/ws", |ctx: &mut Context, _res| Box::pin(async move {
         ctx.subscribe(|ctx, msg| Box::pin(async move {
             match ctx.lock() {
@@ -105,7 +200,7 @@ fn build_routes() -> Router {
                         | Message::Binary(_) => {
                             info!("WS MSG: {:?}", msg);
 
-                            if let Err(err) = ctx.send_bin(b"Hello, World".to_vec()) {
+                            if let Err(err) = ctx.send_binary(b"Hello, World".to_vec()) {
                                 warn!("failed to send msg: {:?}", err);
                             }
 
@@ -126,10 +221,16 @@ fn build_routes() -> Router {
             }
         }))?;
 
-        ctx.send_bin(b"Welcome".to_vec())?;
+        ctx.send_binary(b"Welcome".to_vec())?;
 
         Ok(())
     }));
 
     r
-}
\ No newline at end of file
+}
+
+// Keeps the sample kv routes away from keys used elsewhere.
+#[inline]
+fn kv_key(key: &str) -> Vec<u8> {
+    format!("kv:{}", key).into_bytes()
+}
@@ -0,0 +1,163 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use std::sync::SgxMutex;
+
+use crate::api::results::ErrorKind;
+
+// Upper bounds (inclusive, ms) of the request latency buckets, slower requests
+// land in the overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+lazy_static! {
+    // Shared by every API server thread in the enclave.
+    pub(crate) static ref GLOBAL_METRICS: Metrics = Metrics::new();
+}
+
+pub(crate) struct Metrics {
+    requests_total: AtomicUsize,
+    connections_active: AtomicUsize,
+    exec_tasks_in_flight: AtomicUsize,
+    httpc_calls_total: AtomicUsize,
+    tls_handshake_failures_total: AtomicUsize,
+    errors_total: SgxMutex<BTreeMap<String, usize>>,
+    request_latency: SgxMutex<Histogram>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self {
+            requests_total: AtomicUsize::new(0),
+            connections_active: AtomicUsize::new(0),
+            exec_tasks_in_flight: AtomicUsize::new(0),
+            httpc_calls_total: AtomicUsize::new(0),
+            tls_handshake_failures_total: AtomicUsize::new(0),
+            errors_total: SgxMutex::new(BTreeMap::new()),
+            request_latency: SgxMutex::new(Histogram::new(&LATENCY_BUCKETS_MS)),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        let errors_total = match self.errors_total.lock() {
+            Ok(errors_total) => errors_total.clone(),
+            Err(err) => {
+                warn!("failed to acquire lock on 'errors_total' during Metrics->snapshot: {:?}", err);
+                BTreeMap::new()
+            }
+        };
+        let request_latency_ms = match self.request_latency.lock() {
+            Ok(request_latency) => request_latency.clone(),
+            Err(err) => {
+                warn!("failed to acquire lock on 'request_latency' during Metrics->snapshot: {:?}", err);
+                Histogram::new(&LATENCY_BUCKETS_MS)
+            }
+        };
+
+        MetricsSnapshot {
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            exec_tasks_in_flight: self.exec_tasks_in_flight.load(Ordering::Relaxed),
+            httpc_calls_total: self.httpc_calls_total.load(Ordering::Relaxed),
+            tls_handshake_failures_total: self.tls_handshake_failures_total.load(Ordering::Relaxed),
+            errors_total,
+            request_latency_ms,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn inc_requests(&self) {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_connections(&self) {
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn dec_connections(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn add_exec_tasks(&self, n: usize) {
+        self.exec_tasks_in_flight.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn sub_exec_tasks(&self, n: usize) {
+        self.exec_tasks_in_flight.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_httpc_calls(&self) {
+        self.httpc_calls_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn inc_tls_handshake_failures(&self) {
+        self.tls_handshake_failures_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_errors(&self, kind: ErrorKind) {
+        match self.errors_total.lock() {
+            Ok(mut errors_total) => {
+                *errors_total.entry(kind.to_string()).or_insert(0) += 1;
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'errors_total' during Metrics->inc_errors: {:?}", err);
+            }
+        }
+    }
+
+    pub(crate) fn observe_request_latency(&self, latency: Duration) {
+        match self.request_latency.lock() {
+            Ok(mut request_latency) => request_latency.observe(latency.as_millis() as u64),
+            Err(err) => {
+                warn!("failed to acquire lock on 'request_latency' during Metrics->observe_request_latency: {:?}", err);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MetricsSnapshot {
+    requests_total: usize,
+    connections_active: usize,
+    exec_tasks_in_flight: usize,
+    httpc_calls_total: usize,
+    tls_handshake_failures_total: usize,
+    errors_total: BTreeMap<String, usize>,
+    request_latency_ms: Histogram,
+}
+
+/// Counts observations per bucket, `counts` has one more entry than `bounds`
+/// for values above the last bound.
+#[derive(Serialize, Clone)]
+pub(crate) struct Histogram {
+    bounds: Vec<u64>,
+    counts: Vec<usize>,
+    sum: u64,
+}
+
+impl Histogram {
+    pub(crate) fn new(bounds: &[u64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0,
+        }
+    }
+
+    pub(crate) fn observe(&mut self, value: u64) {
+        let idx = self.bounds.iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.counts[idx] += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+}
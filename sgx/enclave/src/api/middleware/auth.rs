@@ -0,0 +1,28 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use http::StatusCode;
+use http::header;
+
+use crate::api::handler::router::Middleware;
+
+/// Rejects requests with a 401 unless they carry a bearer token accepted by
+/// `validator`.
+#[allow(dead_code)]
+pub(crate) fn middleware_require_bearer(validator: fn(&str) -> bool) -> Middleware {
+    Arc::new(move |ctx, res, next| {
+        let authorized = ctx.request().bearer_token()
+            .map(|token| validator(token.as_str()))
+            .unwrap_or(false);
+
+        Box::pin(async move {
+            if !authorized {
+                res.header(header::WWW_AUTHENTICATE, "Bearer");
+
+                return res.error(StatusCode::UNAUTHORIZED, "Unauthorized");
+            }
+
+            next(ctx, res).await
+        })
+    })
+}
@@ -0,0 +1,33 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+
+use http::header;
+
+use crate::api::handler::router::Middleware;
+use crate::api::results::{Error, ErrorKind};
+
+/// Rejects requests with a 413 when the declared content length or the body
+/// received is over `limit`, before the handler runs.
+///
+/// Bodies are still capped by `Config::max_bytes_received` while being read,
+/// routes wanting a larger limit need the global one raised to match.
+#[allow(dead_code)]
+pub(crate) fn middleware_max_body(limit: usize) -> Middleware {
+    Arc::new(move |ctx, res, next| {
+        let declared: Option<usize> = ctx.request().header(header::CONTENT_LENGTH);
+        let size = declared.unwrap_or(0)
+            .max(ctx.request().body_bytes().len());
+
+        Box::pin(async move {
+            if size > limit {
+                return Err(Error::new_with_kind(
+                    ErrorKind::PayloadTooLarge,
+                    format!("request body of {} bytes exceeds route limit of {} bytes",
+                            size, limit)));
+            }
+
+            next(ctx, res).await
+        })
+    })
+}
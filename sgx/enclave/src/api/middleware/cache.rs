@@ -0,0 +1,169 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+use http::header;
+use std::collections::HashMap;
+use std::sync::SgxMutex;
+use std::time::Instant;
+
+use crate::api::handler::response::Response;
+use crate::api::handler::router::Middleware;
+
+/// Caches successful GET responses for `ttl`, keyed on the method, path and
+/// query. Requests with credentials (`Authorization` or `Cookie`) bypass the
+/// cache, and responses sent with `Cache-Control: no-store` or a `Set-Cookie`
+/// aren't cached, so nothing per client is replayed to another. Neither are
+/// responses with a `Vary` header (e.g. negotiated on `Accept`), the key
+/// doesn't cover the request headers they depend on. Once `max_entries` is
+/// reached expired entries are dropped, then the least recently used.
+#[allow(dead_code)]
+pub(crate) fn middleware_cache(ttl: Duration, max_entries: usize) -> Middleware {
+    let cache = Arc::new(ResponseCache::new(ttl, max_entries));
+
+    Arc::new(move |ctx, res, next| {
+        let cache = cache.clone();
+        let req = ctx.request();
+        let key = if req.method().eq(&Method::GET) && !has_credentials(req.headers()) {
+            Some(match req.uri().query() {
+                Some(query) => format!("{} {}?{}", req.method(), req.uri().path(), query),
+                None => format!("{} {}", req.method(), req.uri().path()),
+            })
+        } else {
+            None
+        };
+
+        Box::pin(async move {
+            let key = match key {
+                Some(key) => key,
+                None => return next(ctx, res).await,
+            };
+
+            if cache.serve(key.as_str(), res) {
+                return Ok(());
+            }
+
+            next(ctx, res).await?;
+            cache.store(key, res);
+
+            Ok(())
+        })
+    })
+}
+
+struct CacheEntry {
+    expires: Instant,
+    last_used: Instant,
+    status: StatusCode,
+    headers: HeaderMap<HeaderValue>,
+    body: Option<Vec<u8>>,
+}
+
+struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: SgxMutex<HashMap<String, CacheEntry>>,
+}
+
+impl ResponseCache {
+    fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            entries: SgxMutex::new(HashMap::new()),
+        }
+    }
+
+    fn serve(&self, key: &str, res: &mut Response) -> bool {
+        let now = Instant::now();
+
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("failed to acquire lock on 'entries' during cache->serve: {:?}", err);
+                return false;
+            }
+        };
+
+        let entry = match entries.get_mut(key) {
+            Some(entry) if entry.expires > now => entry,
+            Some(_) => {
+                entries.remove(key);
+                return false;
+            }
+            None => return false,
+        };
+        entry.last_used = now;
+
+        res.status(entry.status);
+        for (name, value) in entry.headers.iter() {
+            res.header(name, value);
+        }
+        if let Some(body) = entry.body.as_ref() {
+            res.body(body.clone());
+        }
+
+        true
+    }
+
+    fn store(&self, key: String, res: &Response) {
+        if res.status_code() != StatusCode::OK || is_no_store(res.headers())
+            || res.headers().contains_key(header::SET_COOKIE)
+            || res.headers().contains_key(header::VARY) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("failed to acquire lock on 'entries' during cache->store: {:?}", err);
+                return;
+            }
+        };
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            self.evict(&mut entries, &now);
+        }
+
+        entries.insert(key, CacheEntry {
+            expires: now + self.ttl,
+            last_used: now,
+            status: res.status_code(),
+            headers: res.headers().clone(),
+            body: res.body_bytes().map(|b| b.to_vec()),
+        });
+    }
+
+    // private
+
+    fn evict(&self, entries: &mut HashMap<String, CacheEntry>, now: &Instant) {
+        entries.retain(|_, entry| entry.expires > *now);
+        if entries.len() < self.max_entries {
+            return;
+        }
+
+        let oldest = entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            entries.remove(&oldest);
+        }
+    }
+}
+
+#[inline]
+fn has_credentials(headers: &HeaderMap<HeaderValue>) -> bool {
+    headers.contains_key(header::AUTHORIZATION) || headers.contains_key(header::COOKIE)
+}
+
+#[inline]
+fn is_no_store(headers: &HeaderMap<HeaderValue>) -> bool {
+    headers.get_all(header::CACHE_CONTROL).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(","))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
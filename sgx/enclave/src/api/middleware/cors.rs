@@ -0,0 +1,159 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use http::{Method, StatusCode};
+use http::header;
+
+use crate::api::handler::response::Response;
+use crate::api::handler::router::Middleware;
+use crate::api::results::{Error, ErrorKind};
+
+pub(crate) struct CorsConfig {
+    origins: Vec<String>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+#[allow(dead_code)]
+impl CorsConfig {
+    pub(crate) fn new() -> Self {
+        Self {
+            origins: Vec::new(),
+            any_origin: false,
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    pub(crate) fn allow_origin(&mut self, origin: &str) -> &mut Self {
+        self.origins.push(origin.to_string());
+        self
+    }
+
+    /// Can't be combined with `allow_credentials(true)`, see `middleware_cors`.
+    pub(crate) fn allow_any_origin(&mut self) -> &mut Self {
+        self.any_origin = true;
+        self
+    }
+
+    pub(crate) fn allow_methods(&mut self, methods: &[Method]) -> &mut Self {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    pub(crate) fn allow_headers(&mut self, headers: &[&str]) -> &mut Self {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    pub(crate) fn allow_credentials(&mut self, credentials: bool) -> &mut Self {
+        self.credentials = credentials;
+        self
+    }
+
+    pub(crate) fn max_age(&mut self, secs: u32) -> &mut Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    // private
+
+    /// Any origin with credentials would let every site make credentialed
+    /// requests, the spec forbids the wildcard with credentials for this
+    /// reason. Credentials need an explicit origin list.
+    fn validate(&self) -> Result<(), Error> {
+        if self.any_origin && self.credentials {
+            return Err(Error::new_with_kind(
+                ErrorKind::ServerFault,
+                "invalid cors config: credentials can't be allowed for any origin".to_string()));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.any_origin || self.origins.iter().any(|o| o.eq(origin))
+    }
+
+    /// With an allow-list the response depends on the request's origin,
+    /// whether it matched or not.
+    #[inline]
+    fn apply_vary(&self, res: &mut Response) {
+        if !self.any_origin {
+            res.header(header::VARY, "Origin");
+        }
+    }
+
+    #[inline]
+    fn apply_origin(&self, res: &mut Response, origin: &str) {
+        if self.any_origin {
+            res.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+        } else {
+            res.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+
+        if self.credentials {
+            res.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+    }
+
+    #[inline]
+    fn apply_preflight(&self, res: &mut Response) {
+        let methods: Vec<&str> = self.methods.iter().map(|m| m.as_str()).collect();
+        res.header(header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", "));
+
+        if !self.headers.is_empty() {
+            res.header(header::ACCESS_CONTROL_ALLOW_HEADERS, self.headers.join(", "));
+        }
+        if let Some(max_age) = self.max_age {
+            res.header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string());
+        }
+    }
+}
+
+/// Answers CORS preflight requests with a 204 and adds the allow headers to
+/// responses for allowed origins. Disallowed origins get no CORS headers.
+/// Fails if `config` allows credentials for any origin.
+#[allow(dead_code)]
+pub(crate) fn middleware_cors(config: CorsConfig) -> Result<Middleware, Error> {
+    config.validate()?;
+    let config = Arc::new(config);
+
+    Ok(Arc::new(move |ctx, res, next| {
+        let config = config.clone();
+        let req = ctx.request();
+        let origin: Option<String> = req.header(header::ORIGIN);
+        let origin = origin.filter(|o| config.is_allowed(o.as_str()));
+        let preflight = req.method().eq(&Method::OPTIONS)
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        Box::pin(async move {
+            if preflight {
+                config.apply_vary(res);
+                if let Some(origin) = origin.as_ref() {
+                    config.apply_origin(res, origin.as_str());
+                    config.apply_preflight(res);
+                }
+                res.status(StatusCode::NO_CONTENT);
+
+                return Ok(());
+            }
+
+            let result = next(ctx, res).await;
+            config.apply_vary(res);
+            if let Some(origin) = origin.as_ref() {
+                config.apply_origin(res, origin.as_str());
+            }
+
+            result
+        })
+    }))
+}
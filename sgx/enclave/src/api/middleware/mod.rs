@@ -1 +1,6 @@
-pub(crate) mod recovery;
\ No newline at end of file
+pub(crate) mod auth;
+pub(crate) mod body_limit;
+pub(crate) mod cache;
+pub(crate) mod cors;
+pub(crate) mod rate_limit;
+pub(crate) mod recovery;
@@ -0,0 +1,139 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::SgxMutex;
+use std::time::Instant;
+
+use crate::api::handler::router::Middleware;
+use crate::api::results::{Error, ErrorKind};
+
+// Clients tracked at once, past this the least recently seen is dropped (its
+// bucket has had the longest to refill, so is the most likely full anyway).
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket per client address, `capacity` requests may be made in a burst
+/// after which they are allowed at `refill_per_sec`. Both must be above 0.
+#[allow(dead_code)]
+pub(crate) fn middleware_rate_limit(capacity: u32, refill_per_sec: u32) -> Result<Middleware, Error> {
+    if capacity == 0 || refill_per_sec == 0 {
+        return Err(Error::new_with_kind(
+            ErrorKind::ServerFault,
+            "invalid rate limit config: capacity and refill_per_sec must be above 0".to_string()));
+    }
+    let limiter = Arc::new(RateLimiter::new(capacity, refill_per_sec));
+
+    Ok(Arc::new(move |ctx, res, next| {
+        let allowed = match ctx.client_ip() {
+            Some(ip) => limiter.try_acquire(ip),
+            None => Ok(()),
+        };
+
+        Box::pin(async move {
+            allowed?;
+
+            next(ctx, res).await
+        })
+    }))
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    // Key into `Buckets::recency`.
+    tick: u64,
+}
+
+struct Buckets {
+    entries: HashMap<IpAddr, Bucket>,
+    // Last used to client, the first entry is the next evicted.
+    recency: BTreeMap<u64, IpAddr>,
+    tick: u64,
+}
+
+struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: SgxMutex<Buckets>,
+}
+
+impl RateLimiter {
+    fn new(capacity: u32, refill_per_sec: u32) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_per_sec: refill_per_sec as f64,
+            buckets: SgxMutex::new(Buckets {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    fn try_acquire(&self, ip: IpAddr) -> Result<(), Error> {
+        let now = Instant::now();
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(err) => {
+                // Fail open, a broken limiter shouldn't take the API down.
+                warn!("failed to acquire lock on 'buckets' during rate_limit->try_acquire: {:?}", err);
+                return Ok(());
+            }
+        };
+        let buckets = &mut *buckets;
+
+        buckets.tick += 1;
+        let tick = buckets.tick;
+
+        let capacity = self.capacity;
+        let bucket = buckets.entries.entry(ip).or_insert_with(|| Bucket {
+            tokens: capacity,
+            last: now,
+            tick,
+        });
+        buckets.recency.remove(&bucket.tick);
+        buckets.recency.insert(tick, ip);
+        bucket.tick = tick;
+        self.refill(bucket, &now);
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Self::evict(buckets);
+
+        if !allowed {
+            return Err(Error::new_with_kind(
+                ErrorKind::TooManyRequests,
+                format!("rate limit exceeded for {}", ip)));
+        }
+
+        Ok(())
+    }
+
+    // private
+
+    #[inline]
+    fn refill(&self, bucket: &mut Bucket, now: &Instant) {
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.last = *now;
+    }
+
+    fn evict(buckets: &mut Buckets) {
+        while buckets.entries.len() > MAX_BUCKETS {
+            let oldest = match buckets.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(ip) = buckets.recency.remove(&oldest) {
+                buckets.entries.remove(&ip);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 pub(crate) mod server;
 pub(crate) mod reactor;
 pub(crate) mod handler;
+pub(crate) mod metrics;
 pub(crate) mod middleware;
 pub(crate) mod results;
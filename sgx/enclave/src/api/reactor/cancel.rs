@@ -0,0 +1,90 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use std::sync::SgxMutex;
+
+/// Tripped by the exec reactor when a task passes its deadline, so the task
+/// gets a chance to finish (e.g. send a timeout response) before it's dropped,
+/// or by the connection when the client goes away.
+#[derive(Clone)]
+pub(crate) struct CancelToken {
+    inner: Arc<CancelInner>,
+}
+
+struct CancelInner {
+    cancelled: AtomicBool,
+    wakers: SgxMutex<Vec<Waker>>,
+}
+
+impl CancelToken {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(CancelInner {
+                cancelled: AtomicBool::new(false),
+                wakers: SgxMutex::new(Vec::new()),
+            })
+        }
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+
+        match self.inner.wakers.lock() {
+            Ok(mut wakers) => {
+                for waker in wakers.drain(..) {
+                    waker.wake();
+                }
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'wakers' during CancelToken->cancel: {:?}", err);
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token is cancelled.
+    #[inline]
+    pub(crate) fn cancelled(&self) -> Cancelled {
+        Cancelled { token: self.clone() }
+    }
+}
+
+pub(crate) struct Cancelled {
+    token: CancelToken,
+}
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        match self.token.inner.wakers.lock() {
+            Ok(mut wakers) => {
+                if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'wakers' during Cancelled->poll: {:?}", err);
+            }
+        }
+
+        // Cancelled between the check and registering the waker.
+        if self.token.is_cancelled() {
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,74 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use crate::api::reactor::timer::{self, Sleep};
+use crate::api::results::{Error, ErrorKind};
+use crate::external::db::{db_get_submit, DbGetTicket};
+
+// How often an offloaded get is checked on, via the server loop's timers.
+const DB_OFFLOAD_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Submits `key` to the host's offload pool, the future resolves once the
+/// host has read it (see `db_get_submit`). Dropping it cancels the get.
+pub(crate) fn db_get_offload(key: &[u8]) -> DbGetFuture {
+    match db_get_submit(key) {
+        Ok(ticket) => DbGetFuture { ticket: Some(ticket), err: None, sleep: None },
+        Err(err) => DbGetFuture { ticket: None, err: Some(db_get_err(err)), sleep: None },
+    }
+}
+
+pub(crate) struct DbGetFuture {
+    ticket: Option<DbGetTicket>,
+    err: Option<Error>,
+    sleep: Option<Sleep>,
+}
+
+impl Future for DbGetFuture {
+    type Output = Result<Option<Vec<u8>>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(err) = self.err.take() {
+            return Poll::Ready(Err(err));
+        }
+
+        let res = match self.ticket.as_mut() {
+            Some(ticket) => ticket.poll(),
+            None => {
+                return Poll::Ready(Err(Error::new_with_kind(
+                    ErrorKind::DbError,
+                    "db_get polled after completion".to_string(),
+                )));
+            }
+        };
+
+        match res {
+            Ok(Some(value)) => {
+                self.ticket = None;
+                Poll::Ready(Ok(value))
+            }
+            Ok(None) => {
+                // Not done yet, check again after the next tick.
+                let mut sleep = timer::sleep(DB_OFFLOAD_POLL_INTERVAL);
+                if Pin::new(&mut sleep).poll(cx).is_ready() {
+                    cx.waker().wake_by_ref();
+                }
+                self.sleep = Some(sleep);
+
+                Poll::Pending
+            }
+            Err(err) => {
+                self.ticket = None;
+                Poll::Ready(Err(db_get_err(err)))
+            }
+        }
+    }
+}
+
+#[inline]
+fn db_get_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::DbError, format!("db_get failed: {}", message))
+}
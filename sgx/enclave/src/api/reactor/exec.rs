@@ -2,6 +2,7 @@ use alloc::sync::Arc;
 use core::future::Future;
 use core::ops::Add;
 use core::task::Context;
+use core::time::Duration;
 
 use futures::future::BoxFuture;
 use futures::FutureExt;
@@ -12,10 +13,16 @@ use std::collections::HashMap;
 use std::panic::{self, AssertUnwindSafe};
 use std::sync::SgxMutex;
 use std::time::Instant;
-use crate::api::results::caught_err_to_str;
+use crate::api::metrics::GLOBAL_METRICS;
+use crate::api::reactor::cancel::CancelToken;
+use crate::api::reactor::join::{JoinHandle, with_join_handle};
+use crate::api::results::{caught_err_to_str, Error, ErrorKind};
 
 use crate::api::server::config::Config;
 
+// How long a cancelled task has to wrap up before it's dropped.
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
 pub(crate) struct ExecReactor {
     tasks: HashMap<Token, Arc<Task>>,
     config: Arc<Config>,
@@ -33,11 +40,60 @@ impl ExecReactor {
         }
     }
 
-    pub(crate) fn spawn(&mut self, poll: &mut mio::Poll, future: impl Future<Output=()> + 'static + Send) {
+    #[allow(dead_code)]
+    pub(crate) fn spawn(&mut self, poll: &mut mio::Poll,
+                        future: impl Future<Output=()> + 'static + Send) -> Result<(), Error> {
         self.spawn_boxed(poll, future.boxed())
     }
 
-    pub(crate) fn spawn_boxed(&mut self, poll: &mut mio::Poll, future: BoxFuture<'static, ()>) {
+    #[allow(dead_code)]
+    pub(crate) fn spawn_with_handle<T>(
+        &mut self,
+        poll: &mut mio::Poll,
+        future: impl Future<Output=T> + 'static + Send,
+    ) -> Result<JoinHandle<T>, Error>
+        where
+            T: 'static + Send,
+    {
+        let (task, handle) = with_join_handle(future);
+        self.spawn_boxed(poll, task)?;
+        Ok(handle)
+    }
+
+    /// Every task (including those queued through `Deferral::spawn`) ends up
+    /// here, a panic inside one is logged and ends only that task. Fails with
+    /// `Overloaded` once `Config::max_tasks` tasks are held.
+    pub(crate) fn spawn_boxed(&mut self, poll: &mut mio::Poll,
+                              future: BoxFuture<'static, ()>) -> Result<(), Error> {
+        self.spawn_task(poll, future, None)
+    }
+
+    /// Like `spawn` but `cancel` is tripped (and the task woken) when the
+    /// task passes its deadline, rather than it being dropped straight away.
+    pub(crate) fn spawn_cancellable(
+        &mut self,
+        poll: &mut mio::Poll,
+        future: impl Future<Output=()> + 'static + Send,
+        cancel: CancelToken,
+    ) -> Result<(), Error> {
+        self.spawn_task(poll, future.boxed(), Some(cancel))
+    }
+
+    fn spawn_task(
+        &mut self,
+        poll: &mut mio::Poll,
+        future: BoxFuture<'static, ()>,
+        cancel: Option<CancelToken>,
+    ) -> Result<(), Error> {
+        if let Some(max_tasks) = self.config.max_tasks() {
+            if self.tasks.len() >= max_tasks {
+                return Err(Error::new_with_kind(
+                    ErrorKind::Overloaded,
+                    format!("unable to spawn task, limit exceeded: {}", max_tasks)));
+            }
+        }
+
+        let future = catch_task_panic(future);
         let token = Token(self.next_id);
 
         if self.next_id + 1 >= (self.offset + u32::MAX as usize) {
@@ -46,16 +102,22 @@ impl ExecReactor {
             self.next_id += 1;
         }
 
+        GLOBAL_METRICS.add_exec_tasks(1);
         self.tasks.insert(token, Arc::new(Task::new(
             SgxMutex::new(Some(future)),
             Instant::now().add(self.config.exec_timeout()),
+            cancel,
         )));
         self.tasks.get_mut(&token).unwrap().start(poll, token.clone());
 
         trace!("spawn[{:?}]: SPAWNED", token.clone());
+
+        Ok(())
     }
 
     pub(crate) fn ready(&mut self, poll: &mut mio::Poll, token: mio::Token) {
+        let before = self.tasks.len();
+
         match panic::catch_unwind(AssertUnwindSafe(|| {
             if let Some(task) = self.tasks.remove(&token) {
                 task.reset_readiness();
@@ -94,34 +156,53 @@ impl ExecReactor {
                 // TODO: Can this be improved?
             }
         }
+
+        // Completed (or panicked) tasks are not put back.
+        GLOBAL_METRICS.sub_exec_tasks(before.saturating_sub(self.tasks.len()));
     }
 
     pub(crate) fn check_timeouts(&mut self, _poll: &mut mio::Poll, now: &Instant) {
         for (token, _task) in self.tasks
-            .drain_filter(|_, t| t.check_deadline(&now)) {
+            .drain_filter(|_, t| t.check_deadline(&now) && !t.try_cancel()) {
             trace!("check_timeouts[{:?}]: TIME OUT", token);
+            GLOBAL_METRICS.sub_exec_tasks(1);
 
-            // No further actions. Cannot surface errors here to future.
-            // TODO: Can this be improved?
+            // Tasks without a cancel token (or that ignored it) are dropped.
         }
     }
 }
 
+// Catching inside the future means the panic never unwinds through the
+// reactor (and the task's own mutex) during poll.
+#[inline]
+fn catch_task_panic(future: BoxFuture<'static, ()>) -> BoxFuture<'static, ()> {
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .map(|result| {
+            if let Err(err) = result {
+                error!("recovered from panic in exec task: {}", caught_err_to_str(err));
+            }
+        })
+        .boxed()
+}
+
 struct Task {
     future: SgxMutex<Option<BoxFuture<'static, ()>>>,
     registration: Registration,
     set_readiness: SetReadiness,
     deadline: Instant,
+    cancel: Option<CancelToken>,
 }
 
 impl Task {
     fn new(
         future: SgxMutex<Option<BoxFuture<'static, ()>>>,
         deadline: Instant,
+        cancel: Option<CancelToken>,
     ) -> Self {
         let (registration, set_readiness) = Registration::new2();
 
-        Self { future, registration, set_readiness, deadline }
+        Self { future, registration, set_readiness, deadline, cancel }
     }
 
     fn start(&self, poll: &Poll, token: mio::Token) {
@@ -144,7 +225,25 @@ impl Task {
     }
 
     fn check_deadline(&self, now: &Instant) -> bool {
-        now.gt(&self.deadline)
+        match self.cancel.as_ref() {
+            Some(cancel) if cancel.is_cancelled() => now.gt(&self.deadline.add(CANCEL_GRACE)),
+            _ => now.gt(&self.deadline),
+        }
+    }
+
+    // Trips the cancel token the first time round, returns false once there's
+    // nothing left to do but drop the task.
+    fn try_cancel(&self) -> bool {
+        match self.cancel.as_ref() {
+            Some(cancel) if !cancel.is_cancelled() => {
+                cancel.cancel();
+                // Poll it even if it isn't waiting on the token.
+                self.set_ready();
+
+                true
+            }
+            _ => false
+        }
     }
 }
 
@@ -12,9 +12,21 @@ use std::sync::SgxMutex;
 
 use mio_httpc::{CallBuilder, CallRef, Httpc, HttpcCfg, Response, SimpleCall};
 
+use crate::api::metrics::GLOBAL_METRICS;
 use crate::api::reactor::waker::ReactorWaker;
 use crate::api::results::{Error, ErrorKind};
 
+pub(crate) type FetchResponse = (Response, Vec<u8>);
+
+/// Request body supplied as a sequence of chunks.
+///
+/// mio_httpc's `SimpleCall` only sends a body set on the `CallBuilder`, so the
+/// chunks are drained into the builder on the reactor right before the call is
+/// started. The whole body is still buffered once at that point; a switch to
+/// the lower level `Call` API would let `HttpcCall::ready` feed chunks as the
+/// upstream socket drains.
+pub(crate) type BodyStream = Box<dyn Iterator<Item=Vec<u8>> + Send>;
+
 pub(crate) struct HttpcReactor {
     httpc: Httpc,
     calls: HashMap<CallRef, Arc<SgxMutex<HttpcCall>>>,
@@ -39,9 +51,11 @@ impl HttpcReactor {
         self.waker.register(poll)
     }
 
-    pub(crate) fn call(&mut self, builder: CallBuilder) -> HttpcCallFuture {
+    pub(crate) fn call(&mut self, builder: CallBuilder, body: Option<BodyStream>) -> HttpcCallFuture {
+        GLOBAL_METRICS.inc_httpc_calls();
+
         let call = Arc::new(SgxMutex::new(
-            HttpcCall::new(builder)
+            HttpcCall::new(builder, body)
         ));
 
         self.pending.push(call.clone());
@@ -111,6 +125,10 @@ impl HttpcReactor {
                 }
 
                 if let Some(mut builder) = lock.builder.take() {
+                    if let Some(body) = lock.body.take() {
+                        builder.body(drain_body(body));
+                    }
+
                     match builder.simple_call(&mut self.httpc, poll) {
                         Ok(inner_call) => {
                             let cref = inner_call.call().get_ref().clone();
@@ -139,17 +157,28 @@ impl HttpcReactor {
     }
 }
 
+#[inline]
+fn drain_body(body: BodyStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for chunk in body {
+        buf.extend_from_slice(chunk.as_slice());
+    }
+    buf
+}
+
 pub(crate) struct HttpcCall {
     builder: Option<CallBuilder>,
+    body: Option<BodyStream>,
     call: Option<SimpleCall>,
     err: Option<Error>,
     waker: Option<Waker>,
 }
 
 impl HttpcCall {
-    fn new(builder: CallBuilder) -> Self {
+    fn new(builder: CallBuilder, body: Option<BodyStream>) -> Self {
         Self {
             builder: Some(builder),
+            body,
             call: None,
             err: None,
             waker: None,
@@ -159,6 +188,7 @@ impl HttpcCall {
     pub(crate) fn from_error(err: Error) -> Self {
         Self {
             builder: None,
+            body: None,
             call: None,
             err: Some(err),
             waker: None,
@@ -229,7 +259,7 @@ impl HttpcCallFuture {
 }
 
 impl Future for HttpcCallFuture {
-    type Output = Result<Option<(Response, Vec<u8>)>, Error>;
+    type Output = Result<Option<FetchResponse>, Error>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut state = self.state.lock().unwrap();
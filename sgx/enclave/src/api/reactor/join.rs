@@ -0,0 +1,118 @@
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::SgxMutex;
+
+use crate::api::results::{Error, ErrorKind};
+
+/// Wraps `future` so its output is delivered to the returned `JoinHandle`.
+///
+/// Dropping the handle detaches the task, it still runs to completion and the
+/// output is discarded. If the task itself is dropped before completing (e.g.
+/// it timed out on the exec reactor) the handle resolves to an `ExecError`.
+pub(crate) fn with_join_handle<T>(
+    future: impl Future<Output=T> + 'static + Send,
+) -> (BoxFuture<'static, ()>, JoinHandle<T>)
+    where
+        T: 'static + Send,
+{
+    let slot = Arc::new(SgxMutex::new(JoinSlot {
+        value: None,
+        waker: None,
+        done: false,
+        detached: false,
+    }));
+
+    let guard = CompletionGuard { slot: slot.clone() };
+    let task = async move {
+        let out = future.await;
+        guard.complete(out);
+    }.boxed();
+
+    (task, JoinHandle { slot })
+}
+
+struct JoinSlot<T> {
+    value: Option<T>,
+    waker: Option<Waker>,
+    done: bool,
+    detached: bool,
+}
+
+pub(crate) struct JoinHandle<T> {
+    slot: Arc<SgxMutex<JoinSlot<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = match self.slot.lock() {
+            Ok(slot) => slot,
+            Err(err) => {
+                return Poll::Ready(Err(Error::new_with_kind(
+                    ErrorKind::ExecError,
+                    format!("failed to acquire lock on 'slot' during JoinHandle->poll: {:?}", err),
+                )));
+            }
+        };
+
+        if let Some(value) = slot.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+        if slot.done {
+            return Poll::Ready(Err(Error::new_with_kind(
+                ErrorKind::ExecError,
+                "spawned task was dropped before completing".to_string(),
+            )));
+        }
+
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.detached = true;
+            slot.value = None;
+        }
+    }
+}
+
+// Marks the slot done (waking the handle) on completion or when the task is dropped.
+struct CompletionGuard<T> {
+    slot: Arc<SgxMutex<JoinSlot<T>>>,
+}
+
+impl<T> CompletionGuard<T> {
+    fn complete(self, value: T) {
+        if let Ok(mut slot) = self.slot.lock() {
+            if !slot.detached {
+                slot.value = Some(value);
+            }
+        }
+    }
+}
+
+impl<T> Drop for CompletionGuard<T> {
+    fn drop(&mut self) {
+        match self.slot.lock() {
+            Ok(mut slot) => {
+                slot.done = true;
+                if let Some(waker) = slot.waker.take() {
+                    waker.wake();
+                }
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'slot' during CompletionGuard->drop: {:?}", err);
+            }
+        }
+    }
+}
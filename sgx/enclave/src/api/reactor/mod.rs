@@ -1,3 +1,7 @@
+pub(crate) mod cancel;
+pub(crate) mod db;
 pub(crate) mod exec;
 pub(crate) mod httpc;
+pub(crate) mod join;
+pub(crate) mod timer;
 pub(crate) mod waker;
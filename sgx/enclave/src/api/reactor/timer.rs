@@ -0,0 +1,108 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use std::sync::SgxMutex;
+use std::time::Instant;
+
+lazy_static! {
+    // Pending sleeps across all servers, ordered by deadline. Each server
+    // loop fires the expired ones, so whichever polls first wakes them.
+    static ref TIMERS: SgxMutex<BTreeMap<(Instant, usize), Waker>> =
+        SgxMutex::new(BTreeMap::new());
+}
+
+static TIMER_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// Resolves once `duration` has passed.
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: Instant::now() + duration,
+        id: TIMER_SEQ.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+/// Wakes every sleep whose deadline is at or before `now`.
+pub(crate) fn fire_expired(now: &Instant) {
+    let expired: Vec<Waker> = match TIMERS.lock() {
+        Ok(mut timers) => {
+            let mut expired = Vec::new();
+            while let Some(key) = timers.keys().next().cloned() {
+                if key.0.gt(now) {
+                    break;
+                }
+                if let Some(waker) = timers.remove(&key) {
+                    expired.push(waker);
+                }
+            }
+            expired
+        }
+        Err(err) => {
+            warn!("failed to acquire lock on 'timers' during timer->fire_expired: {:?}", err);
+            return;
+        }
+    };
+
+    // Woken outside the lock, a waker may schedule a task that sleeps again.
+    for waker in expired {
+        waker.wake();
+    }
+}
+
+/// The earliest pending deadline, servers poll no longer than this.
+pub(crate) fn next_deadline() -> Option<Instant> {
+    match TIMERS.lock() {
+        Ok(timers) => timers.keys().next().map(|(deadline, _)| *deadline),
+        Err(err) => {
+            warn!("failed to acquire lock on 'timers' during timer->next_deadline: {:?}", err);
+            None
+        }
+    }
+}
+
+pub(crate) struct Sleep {
+    deadline: Instant,
+    id: usize,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        match TIMERS.lock() {
+            Ok(mut timers) => {
+                timers.insert((self.deadline, self.id), cx.waker().clone());
+                self.registered = true;
+            }
+            Err(err) => {
+                // Can't be woken, give up on the wait rather than hang.
+                warn!("failed to acquire lock on 'timers' during Sleep->poll: {:?}", err);
+                return Poll::Ready(());
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if !self.registered {
+            return;
+        }
+
+        if let Ok(mut timers) = TIMERS.lock() {
+            timers.remove(&(self.deadline, self.id));
+        }
+    }
+}
@@ -23,7 +23,7 @@ impl ResponseBody {
     #[allow(dead_code)]
     pub fn dummy() -> Self {
         Self {
-            body: b"HTTP/1.1 200 OK\r\nServer: index.teggle.io/v1beta1\r\nContent-Length: 18\r\nDate: TODO\r\ncontent-type: application/json\r\n\r\n{\"message\":\"PONG\"}".to_vec(),
+            body: b"HTTP/1.1 200 OK\r\nServer: index.teggle.io/v1beta1\r\nContent-Length: 18\r\nDate: TODO\r\ncontent-type: application/json; charset=utf-8\r\n\r\n{\"message\":\"PONG\"}".to_vec(),
             close: true,
         }
     }
@@ -47,22 +47,46 @@ pub enum ErrorKind {
     EncodeFault,
     // Decode fault.
     DecodeFault,
+    // Bad request (client error).
+    BadRequest,
     // General fault.
     ServerFault,
     // Web Socket fault.
     WSFault,
     // Web Socket closed.
     WSClosed,
+    // Web Socket client not keeping up.
+    WSBackpressure,
+    // Web Socket text frame that isn't valid UTF-8.
+    WSInvalidPayload,
+    // Web Socket upgrade refused, too many open.
+    WSLimit,
     // Timed out.
     TimedOut,
+    // Handler overran Config::handler_timeout.
+    HandlerTimedOut,
     // Too big.
     PayloadTooLarge,
+    // Too many headers.
+    HeadersTooLarge,
+    // Request target too long.
+    UriTooLong,
+    // Body in a format we don't decode.
+    UnsupportedMediaType,
+    // Request uses a feature we don't support (e.g. Transfer-Encoding).
+    NotImplemented,
+    // Rate limited.
+    TooManyRequests,
+    // Exec reactor full.
+    Overloaded,
     // Exec Error.
     ExecError,
     // Http Client Error.
     HttpClientError,
     // Http Client Timed out.
     HttpClientTimedOut,
+    // Database Error.
+    DbError,
 }
 
 impl Display for ErrorKind {
@@ -70,14 +94,26 @@ impl Display for ErrorKind {
         match self {
             ErrorKind::EncodeFault => write!(f, "EncodeFault"),
             ErrorKind::DecodeFault => write!(f, "DecodeFault"),
+            ErrorKind::BadRequest => write!(f, "BadRequest"),
             ErrorKind::ServerFault => write!(f, "ServerFault"),
             ErrorKind::WSFault => write!(f, "WSFault"),
             ErrorKind::WSClosed => write!(f, "WSClosed"),
+            ErrorKind::WSBackpressure => write!(f, "WSBackpressure"),
+            ErrorKind::WSInvalidPayload => write!(f, "WSInvalidPayload"),
+            ErrorKind::WSLimit => write!(f, "WSLimit"),
             ErrorKind::TimedOut => write!(f, "TimedOut"),
+            ErrorKind::HandlerTimedOut => write!(f, "HandlerTimedOut"),
             ErrorKind::PayloadTooLarge => write!(f, "PayloadTooLarge"),
+            ErrorKind::HeadersTooLarge => write!(f, "HeadersTooLarge"),
+            ErrorKind::UriTooLong => write!(f, "UriTooLong"),
+            ErrorKind::UnsupportedMediaType => write!(f, "UnsupportedMediaType"),
+            ErrorKind::NotImplemented => write!(f, "NotImplemented"),
+            ErrorKind::TooManyRequests => write!(f, "TooManyRequests"),
+            ErrorKind::Overloaded => write!(f, "Overloaded"),
             ErrorKind::ExecError => write!(f, "ExecError"),
             ErrorKind::HttpClientError => write!(f, "HttpClientError"),
             ErrorKind::HttpClientTimedOut => write!(f, "HttpClientTimedOut"),
+            ErrorKind::DbError => write!(f, "DbError"),
         }
     }
 }
@@ -112,20 +148,36 @@ impl Error {
         match self.kind {
             ErrorKind::EncodeFault => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorKind::DecodeFault => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
             ErrorKind::ServerFault => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorKind::WSFault => StatusCode::BAD_REQUEST,
             ErrorKind::WSClosed => StatusCode::IM_USED,
+            ErrorKind::WSBackpressure => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::WSInvalidPayload => StatusCode::BAD_REQUEST,
+            ErrorKind::WSLimit => StatusCode::SERVICE_UNAVAILABLE,
             ErrorKind::TimedOut => StatusCode::REQUEST_TIMEOUT,
+            ErrorKind::HandlerTimedOut => StatusCode::GATEWAY_TIMEOUT,
             ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            ErrorKind::UriTooLong => StatusCode::URI_TOO_LONG,
+            ErrorKind::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
             ErrorKind::ExecError => StatusCode::BAD_GATEWAY,
             ErrorKind::HttpClientError => StatusCode::BAD_GATEWAY,
             ErrorKind::HttpClientTimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ErrorKind::DbError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    pub fn message(&self) -> &str {
+        self.message.as_str()
+    }
 }
 
 impl Display for Error {
@@ -1,70 +1,514 @@
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::fmt::Display;
+use core::str::FromStr;
 use core::time::Duration;
+use http::header::{HeaderName, HeaderValue};
 use rustls::server::NoClientAuth;
 
+use std::env;
 use std::io::BufReader;
-use std::sync::Arc;
+use std::sync::{Arc, SgxRwLock};
 use std::untrusted::fs;
 
-fn load_certs(filename: &str) -> Vec<rustls::Certificate> {
-    let certfile = fs::File::open(filename).expect("cannot open certificate file");
+use crate::api::handler::codec::DEFAULT_SERVER_HEADER;
+use crate::api::results::{Error, ErrorKind};
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_HEADERS: usize = 64;
+// 8 Kb
+const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+// 16 Kb, must stay below max_bytes_received (see `validate`).
+const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+const DEFAULT_TCP_BACKLOG: i32 = 250;
+const DEFAULT_EVENTS_CAPACITY: usize = 2048;
+const DEFAULT_POLL_TIMEOUT: Duration = Duration::from_millis(1000);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HANDLER_TIMEOUT: Duration = Duration::from_secs(30);
+// Deadlines are `Instant`s, far past this they'd overflow.
+const MAX_HANDLER_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Defaults for `from_env`.
+// 50 Kb
+const DEFAULT_MAX_BYTES_RECEIVED: usize = 50 * 1024;
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Currently the exec deadlines cannot be surfaced to the future
+// their main purpose is to release some system resources.
+const DEFAULT_EXEC_TIMEOUT: Duration = Duration::from_secs(7200);
+// Per server, across all connections.
+const DEFAULT_MAX_TASKS: usize = 50_000;
+// Per connection.
+const DEFAULT_MAX_DEFERS_QUEUE: usize = 100;
+const DEFAULT_MAX_FUTURES_QUEUE: usize = 100;
+// Responses smaller than this are sent uncompressed.
+const DEFAULT_COMPRESSION_MIN_BYTES: usize = 1024;
+const DEFAULT_ACCESS_LOG: bool = true;
+const DEFAULT_LOG_TLS_FAILURES: bool = true;
+const DEFAULT_MAX_CONNECTIONS: usize = 10_000;
+const DEFAULT_MAX_WEBSOCKETS: usize = 5_000;
+const DEFAULT_MAX_WEBSOCKETS_PER_IP: usize = 32;
+
+// Optional (`usize`) settings are disabled with this value.
+static ENV_NONE: &str = "none";
+
+fn load_certs(filename: &str) -> Result<Vec<rustls::Certificate>, Error> {
+    let certfile = fs::File::open(filename)
+        .map_err(|e| tls_err(format!("cannot open certificate file {:?}: {:?}", filename, e)))?;
     let mut reader = BufReader::new(certfile);
-    rustls_pemfile::certs(&mut reader)
-        .unwrap()
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut reader)
+        .map_err(|e| tls_err(format!("cannot parse certificate file {:?}: {:?}", filename, e)))?
         .iter()
         .map(|v| rustls::Certificate(v.clone()))
-        .collect()
+        .collect();
+
+    if certs.is_empty() {
+        return Err(tls_err(format!("no certificates found in {:?}", filename)));
+    }
+
+    Ok(certs)
 }
 
-fn load_private_key(filename: &str) -> rustls::PrivateKey {
-    let keyfile = fs::File::open(filename).expect("cannot open private key file");
+fn load_private_key(filename: &str) -> Result<rustls::PrivateKey, Error> {
+    let keyfile = fs::File::open(filename)
+        .map_err(|e| tls_err(format!("cannot open private key file {:?}: {:?}", filename, e)))?;
     let mut reader = BufReader::new(keyfile);
 
     loop {
-        match rustls_pemfile::read_one(&mut reader).expect("cannot parse private key .pem file") {
-            Some(rustls_pemfile::Item::RSAKey(key)) => return rustls::PrivateKey(key),
-            Some(rustls_pemfile::Item::PKCS8Key(key)) => return rustls::PrivateKey(key),
-            Some(rustls_pemfile::Item::ECKey(key)) => return rustls::PrivateKey(key),
+        let item = rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| tls_err(format!("cannot parse private key file {:?}: {:?}", filename, e)))?;
+
+        match item {
+            Some(rustls_pemfile::Item::RSAKey(key)) => return Ok(rustls::PrivateKey(key)),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => return Ok(rustls::PrivateKey(key)),
+            Some(rustls_pemfile::Item::ECKey(key)) => return Ok(rustls::PrivateKey(key)),
             None => break,
             _ => {}
         }
     }
 
-    panic!(
+    Err(tls_err(format!(
         "no keys found in {:?} (encrypted keys not supported)",
         filename
-    );
+    )))
+}
+
+#[inline]
+fn tls_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::ServerFault, message)
+}
+
+#[inline]
+fn config_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::ServerFault, format!("invalid config: {}", message))
+}
+
+/// `None` when `name` isn't set.
+fn env_string(name: &str) -> Result<Option<String>, Error> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value.trim().to_string())),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(config_err(format!("{}: {}", name, err))),
+    }
+}
+
+fn env_var<T>(name: &str, default: T) -> Result<T, Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+{
+    match env_string(name)? {
+        Some(value) => value.parse::<T>()
+            .map_err(|e| config_err(format!("{}: invalid value '{}': {}", name, value, e))),
+        None => Ok(default),
+    }
+}
+
+fn env_opt_var(name: &str, default: Option<usize>) -> Result<Option<usize>, Error> {
+    match env_string(name)? {
+        Some(ref value) if value.eq_ignore_ascii_case(ENV_NONE) => Ok(None),
+        Some(_) => env_var(name, 0).map(Some),
+        None => Ok(default),
+    }
+}
+
+fn env_duration_ms(name: &str, default: Duration) -> Result<Duration, Error> {
+    env_var(name, default.as_millis() as u64).map(Duration::from_millis)
+}
+
+struct TlsState {
+    server_config: Arc<rustls::ServerConfig>,
+    cert_path: String,
+    key_path: String,
 }
 
 pub struct Config {
-    tls_config: Arc<rustls::ServerConfig>,
+    tls: SgxRwLock<TlsState>,
     max_bytes_received: usize,
     request_timeout: Duration,
     exec_timeout: Duration,
     max_defers_queue: Option<usize>,
     max_futures_queue: Option<usize>,
+    compression_min_bytes: Option<usize>,
+    access_log: bool,
+    log_tls_failures: bool,
+    json_max_depth: Option<usize>,
+    trust_forwarded: bool,
+    server_header: String,
+    default_response_headers: Vec<(String, String)>,
+    max_connections: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    max_websockets: Option<usize>,
+    max_websockets_per_ip: Option<usize>,
+    idle_timeout: Duration,
+    tcp_nodelay: bool,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    max_headers: usize,
+    max_uri_length: usize,
+    max_header_bytes: usize,
+    max_tasks: Option<usize>,
+    tcp_backlog: i32,
+    events_capacity: usize,
+    poll_timeout: Duration,
+    drain_timeout: Duration,
+    handler_timeout: Duration,
 }
 
 impl Config {
+    /// Loads the TLS certificate chain and private key up front, so a bad
+    /// pair is reported before the listener is bound.
     pub fn new(
+        cert_path: &str,
+        key_path: &str,
         max_bytes_received: usize,
         request_timeout: Duration,
         exec_timeout: Duration,
         max_defers_queue: Option<usize>,
         max_futures_queue: Option<usize>,
-    ) -> Self {
-        Self {
-            tls_config: make_config(),
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            tls: SgxRwLock::new(TlsState {
+                server_config: make_config(cert_path, key_path)?,
+                cert_path: cert_path.to_string(),
+                key_path: key_path.to_string(),
+            }),
             max_bytes_received,
             request_timeout,
             exec_timeout,
             max_defers_queue,
-            max_futures_queue
+            max_futures_queue,
+            compression_min_bytes: None,
+            access_log: false,
+            log_tls_failures: DEFAULT_LOG_TLS_FAILURES,
+            json_max_depth: None,
+            trust_forwarded: false,
+            server_header: DEFAULT_SERVER_HEADER.to_string(),
+            default_response_headers: Vec::new(),
+            max_connections: None,
+            max_requests_per_connection: None,
+            max_websockets: None,
+            max_websockets_per_ip: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            tcp_nodelay: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_tasks: None,
+            tcp_backlog: DEFAULT_TCP_BACKLOG,
+            events_capacity: DEFAULT_EVENTS_CAPACITY,
+            poll_timeout: DEFAULT_POLL_TIMEOUT,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            handler_timeout: DEFAULT_HANDLER_TIMEOUT,
+        })
+    }
+
+    /// Server config with every setting read from an `IDX_*` environment
+    /// variable, falling back to the defaults above when one isn't set.
+    /// Durations are in milliseconds, optional limits accept `none`.
+    pub fn from_env(cert_path: &str, key_path: &str) -> Result<Self, Error> {
+        let mut config = Self::new(
+            cert_path,
+            key_path,
+            env_var("IDX_MAX_BYTES", DEFAULT_MAX_BYTES_RECEIVED)?,
+            env_duration_ms("IDX_REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT)?,
+            env_duration_ms("IDX_EXEC_TIMEOUT_MS", DEFAULT_EXEC_TIMEOUT)?,
+            env_opt_var("IDX_MAX_DEFERS_QUEUE", Some(DEFAULT_MAX_DEFERS_QUEUE))?,
+            env_opt_var("IDX_MAX_FUTURES_QUEUE", Some(DEFAULT_MAX_FUTURES_QUEUE))?,
+        )?;
+
+        config.set_compression_min_bytes(
+            env_opt_var("IDX_COMPRESSION_MIN_BYTES", Some(DEFAULT_COMPRESSION_MIN_BYTES))?)
+            .set_access_log(env_var("IDX_ACCESS_LOG", DEFAULT_ACCESS_LOG)?)
+            .set_log_tls_failures(env_var("IDX_LOG_TLS_FAILURES", DEFAULT_LOG_TLS_FAILURES)?)
+            .set_max_connections(env_opt_var("IDX_MAX_CONNECTIONS", Some(DEFAULT_MAX_CONNECTIONS))?)
+            .set_max_requests_per_connection(env_opt_var("IDX_MAX_REQUESTS_PER_CONNECTION", None)?)
+            .set_max_websockets(env_opt_var("IDX_MAX_WEBSOCKETS", Some(DEFAULT_MAX_WEBSOCKETS))?)
+            .set_max_websockets_per_ip(
+                env_opt_var("IDX_MAX_WEBSOCKETS_PER_IP", Some(DEFAULT_MAX_WEBSOCKETS_PER_IP))?)
+            .set_max_tasks(env_opt_var("IDX_MAX_TASKS", Some(DEFAULT_MAX_TASKS))?)
+            .set_max_headers(env_var("IDX_MAX_HEADERS", DEFAULT_MAX_HEADERS)?)
+            .set_max_uri_length(env_var("IDX_MAX_URI_LENGTH", DEFAULT_MAX_URI_LENGTH)?)
+            .set_max_header_bytes(env_var("IDX_MAX_HEADER_BYTES", DEFAULT_MAX_HEADER_BYTES)?)
+            .set_idle_timeout(env_duration_ms("IDX_IDLE_TIMEOUT_MS", DEFAULT_IDLE_TIMEOUT)?)
+            .set_tcp_backlog(env_var("IDX_TCP_BACKLOG", DEFAULT_TCP_BACKLOG)?)
+            .set_events_capacity(env_var("IDX_EVENTS_CAPACITY", DEFAULT_EVENTS_CAPACITY)?)
+            .set_poll_timeout(env_duration_ms("IDX_POLL_TIMEOUT_MS", DEFAULT_POLL_TIMEOUT)?)
+            .set_drain_timeout(env_duration_ms("IDX_DRAIN_TIMEOUT_MS", DEFAULT_DRAIN_TIMEOUT)?)
+            .set_handler_timeout(env_duration_ms("IDX_HANDLER_TIMEOUT_MS", DEFAULT_HANDLER_TIMEOUT)?);
+
+        Ok(config)
+    }
+
+    pub fn set_compression_min_bytes(&mut self, compression_min_bytes: Option<usize>) -> &mut Self {
+        self.compression_min_bytes = compression_min_bytes;
+        self
+    }
+
+    pub fn set_access_log(&mut self, access_log: bool) -> &mut Self {
+        self.access_log = access_log;
+        self
+    }
+
+    /// Warn (with the peer address and rustls' reason) when a TLS handshake
+    /// fails, otherwise they're only traced. Failures are counted either way,
+    /// turn it off when scanners hitting the port drown out the log.
+    pub fn set_log_tls_failures(&mut self, log_tls_failures: bool) -> &mut Self {
+        self.log_tls_failures = log_tls_failures;
+        self
+    }
+
+    /// Overrides the default JSON nesting limit used by `Request::json`.
+    pub fn set_json_max_depth(&mut self, json_max_depth: Option<usize>) -> &mut Self {
+        self.json_max_depth = json_max_depth;
+        self
+    }
+
+    /// Take the client address from `X-Forwarded-For`, only enable this when
+    /// running behind a proxy that sets it.
+    pub fn set_trust_forwarded(&mut self, trust_forwarded: bool) -> &mut Self {
+        self.trust_forwarded = trust_forwarded;
+        self
+    }
+
+    /// Value sent in the `Server` header of every response.
+    pub fn set_server_header(&mut self, server_header: &str) -> &mut Self {
+        self.server_header = server_header.to_string();
+        self
+    }
+
+    /// Headers (e.g. `Strict-Transport-Security`) added to every response
+    /// that doesn't already set them, checked by `validate`.
+    pub fn set_default_response_headers(&mut self, headers: Vec<(String, String)>) -> &mut Self {
+        self.default_response_headers = headers;
+        self
+    }
+
+    pub fn add_default_response_header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.default_response_headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Connections accepted beyond this many open ones (per server) are
+    /// closed straight away.
+    pub fn set_max_connections(&mut self, max_connections: Option<usize>) -> &mut Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Requests served over one keep-alive connection, the response to the
+    /// last one closes it.
+    pub fn set_max_requests_per_connection(&mut self, max_requests: Option<usize>) -> &mut Self {
+        self.max_requests_per_connection = max_requests;
+        self
+    }
+
+    /// Open web sockets across every server, upgrades beyond it get a 503.
+    pub fn set_max_websockets(&mut self, max_websockets: Option<usize>) -> &mut Self {
+        self.max_websockets = max_websockets;
+        self
+    }
+
+    /// Open web sockets from one client address (the socket's, not any
+    /// forwarded one), upgrades beyond it get a 503.
+    pub fn set_max_websockets_per_ip(&mut self, max_websockets: Option<usize>) -> &mut Self {
+        self.max_websockets_per_ip = max_websockets;
+        self
+    }
+
+    /// How long a keep-alive connection may sit with nothing read or written
+    /// (and no request in progress) before it's closed.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) -> &mut Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Disable Nagle's algorithm on accepted sockets (on by default).
+    pub fn set_tcp_nodelay(&mut self, tcp_nodelay: bool) -> &mut Self {
+        self.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// SO_RCVBUF for accepted sockets, `None` keeps the OS default.
+    pub fn set_recv_buffer_size(&mut self, recv_buffer_size: Option<usize>) -> &mut Self {
+        self.recv_buffer_size = recv_buffer_size;
+        self
+    }
+
+    /// SO_SNDBUF for accepted sockets, `None` keeps the OS default.
+    pub fn set_send_buffer_size(&mut self, send_buffer_size: Option<usize>) -> &mut Self {
+        self.send_buffer_size = send_buffer_size;
+        self
+    }
+
+    /// Requests with more headers than this are rejected with a 431.
+    pub fn set_max_headers(&mut self, max_headers: usize) -> &mut Self {
+        self.max_headers = max_headers;
+        self
+    }
+
+    /// Requests with a longer request target (path and query) are rejected
+    /// with a 414.
+    pub fn set_max_uri_length(&mut self, max_uri_length: usize) -> &mut Self {
+        self.max_uri_length = max_uri_length;
+        self
+    }
+
+    /// Requests whose head (request line and headers) is still incomplete
+    /// after this many bytes are rejected with a 431. Must be below
+    /// `max_bytes_received`, which would otherwise cut them off with a 413.
+    pub fn set_max_header_bytes(&mut self, max_header_bytes: usize) -> &mut Self {
+        self.max_header_bytes = max_header_bytes;
+        self
+    }
+
+    /// Tasks the exec reactor (per server) holds at once, requests arriving
+    /// when it's full are shed with a 503.
+    pub fn set_max_tasks(&mut self, max_tasks: Option<usize>) -> &mut Self {
+        self.max_tasks = max_tasks;
+        self
+    }
+
+    /// Listen backlog for each bound address.
+    pub fn set_tcp_backlog(&mut self, tcp_backlog: i32) -> &mut Self {
+        self.tcp_backlog = tcp_backlog;
+        self
+    }
+
+    /// Events handled per poll, more suit servers with many busy connections.
+    pub fn set_events_capacity(&mut self, events_capacity: usize) -> &mut Self {
+        self.events_capacity = events_capacity;
+        self
+    }
+
+    /// Longest a poll blocks, also how often timeouts are checked.
+    pub fn set_poll_timeout(&mut self, poll_timeout: Duration) -> &mut Self {
+        self.poll_timeout = poll_timeout;
+        self
+    }
+
+    /// How long in-flight requests get to finish on shutdown before their
+    /// connections are closed anyway.
+    pub fn set_drain_timeout(&mut self, drain_timeout: Duration) -> &mut Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// How long (from when the request arrived) a handler gets to respond,
+    /// past it the client is sent a 504 and the handler is cancelled. At most
+    /// a day.
+    pub fn set_handler_timeout(&mut self, handler_timeout: Duration) -> &mut Self {
+        self.handler_timeout = handler_timeout;
+        self
+    }
+
+    /// Checks the settings that would otherwise fail (or spin) at runtime.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.tcp_backlog <= 0 {
+            return Err(config_err(format!("tcp_backlog must be > 0, got: {}", self.tcp_backlog)));
+        }
+        if self.events_capacity == 0 {
+            return Err(config_err("events_capacity must be > 0".to_string()));
+        }
+        if self.poll_timeout.as_millis() == 0 {
+            return Err(config_err("poll_timeout must be at least 1ms".to_string()));
+        }
+        if self.handler_timeout.as_millis() == 0 {
+            return Err(config_err("handler_timeout must be at least 1ms".to_string()));
+        }
+        if self.handler_timeout > MAX_HANDLER_TIMEOUT {
+            return Err(config_err(format!("handler_timeout must be at most {}ms, got: {}ms",
+                                          MAX_HANDLER_TIMEOUT.as_millis(),
+                                          self.handler_timeout.as_millis())));
         }
+        if self.max_uri_length == 0 {
+            return Err(config_err("max_uri_length must be > 0".to_string()));
+        }
+        if self.max_header_bytes == 0 {
+            return Err(config_err("max_header_bytes must be > 0".to_string()));
+        }
+        if self.max_header_bytes >= self.max_bytes_received {
+            return Err(config_err(format!("max_header_bytes must be < max_bytes_received ({}), got: {}",
+                                          self.max_bytes_received, self.max_header_bytes)));
+        }
+        for (name, value) in self.default_response_headers.iter() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(config_err(format!("invalid default response header name: {:?}", name)));
+            }
+            if HeaderValue::from_str(value.as_str()).is_err() {
+                return Err(config_err(format!("invalid value for default response header {:?}: {:?}",
+                                              name, value)));
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn tls_config(&self) -> &Arc<rustls::ServerConfig> {
-        &self.tls_config
+    /// Rebuilds the TLS config from the given cert/key pair and swaps it in.
+    /// Connections accepted afterwards use the new certificate, existing ones
+    /// keep the config they were created with.
+    pub fn reload_tls(&self, cert_path: &str, key_path: &str) -> Result<(), Error> {
+        let server_config = make_config(cert_path, key_path)?;
+
+        let mut tls = match self.tls.write() {
+            Ok(tls) => tls,
+            Err(err) => {
+                warn!("failed to acquire lock on 'tls' during config->reload_tls: {:?}", err);
+                err.into_inner()
+            }
+        };
+        tls.server_config = server_config;
+        tls.cert_path = cert_path.to_string();
+        tls.key_path = key_path.to_string();
+
+        Ok(())
+    }
+
+    pub fn tls_config(&self) -> Arc<rustls::ServerConfig> {
+        match self.tls.read() {
+            Ok(tls) => tls.server_config.clone(),
+            Err(err) => {
+                warn!("failed to acquire lock on 'tls' during config->tls_config: {:?}", err);
+                err.into_inner().server_config.clone()
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn cert_path(&self) -> String {
+        match self.tls.read() {
+            Ok(tls) => tls.cert_path.clone(),
+            Err(err) => err.into_inner().cert_path.clone(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn key_path(&self) -> String {
+        match self.tls.read() {
+            Ok(tls) => tls.key_path.clone(),
+            Err(err) => err.into_inner().key_path.clone(),
+        }
     }
 
     pub fn max_bytes_received(&self) -> usize {
@@ -79,6 +523,58 @@ impl Config {
         self.exec_timeout
     }
 
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub fn recv_buffer_size(&self) -> Option<usize> {
+        self.recv_buffer_size
+    }
+
+    pub fn send_buffer_size(&self) -> Option<usize> {
+        self.send_buffer_size
+    }
+
+    pub fn max_headers(&self) -> usize {
+        self.max_headers
+    }
+
+    pub fn max_uri_length(&self) -> usize {
+        self.max_uri_length
+    }
+
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes
+    }
+
+    pub fn tcp_backlog(&self) -> i32 {
+        self.tcp_backlog
+    }
+
+    pub fn events_capacity(&self) -> usize {
+        self.events_capacity
+    }
+
+    pub fn poll_timeout(&self) -> Duration {
+        self.poll_timeout
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    pub fn handler_timeout(&self) -> Duration {
+        self.handler_timeout
+    }
+
+    pub fn max_tasks(&self) -> Option<usize> {
+        self.max_tasks
+    }
+
     pub fn max_defers_queue(&self) -> Option<usize> {
         self.max_defers_queue
     }
@@ -86,12 +582,56 @@ impl Config {
     pub fn max_futures_queue(&self) -> Option<usize> {
         self.max_futures_queue
     }
+
+    pub fn compression_min_bytes(&self) -> Option<usize> {
+        self.compression_min_bytes
+    }
+
+    pub fn access_log(&self) -> bool {
+        self.access_log
+    }
+
+    pub fn log_tls_failures(&self) -> bool {
+        self.log_tls_failures
+    }
+
+    pub fn json_max_depth(&self) -> Option<usize> {
+        self.json_max_depth
+    }
+
+    pub fn trust_forwarded(&self) -> bool {
+        self.trust_forwarded
+    }
+
+    pub fn server_header(&self) -> &str {
+        self.server_header.as_str()
+    }
+
+    pub fn default_response_headers(&self) -> &[(String, String)] {
+        self.default_response_headers.as_slice()
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn max_requests_per_connection(&self) -> Option<usize> {
+        self.max_requests_per_connection
+    }
+
+    pub fn max_websockets(&self) -> Option<usize> {
+        self.max_websockets
+    }
+
+    pub fn max_websockets_per_ip(&self) -> Option<usize> {
+        self.max_websockets_per_ip
+    }
 }
 
-pub fn make_config() -> Arc<rustls::ServerConfig> {
+pub fn make_config(cert_path: &str, key_path: &str) -> Result<Arc<rustls::ServerConfig>, Error> {
     // TODO: Load from secure file (fetched from Omnibus).
-    let certs = load_certs("end.fullchain");
-    let privkey = load_private_key("end.rsa");
+    let certs = load_certs(cert_path)?;
+    let privkey = load_private_key(key_path)?;
 
     let config = rustls::ServerConfig::builder()
         .with_cipher_suites(&rustls::ALL_CIPHER_SUITES.to_vec())
@@ -100,7 +640,8 @@ pub fn make_config() -> Arc<rustls::ServerConfig> {
         .expect("inconsistent cipher-suites/versions specified")
         .with_client_cert_verifier(NoClientAuth::new())
         .with_single_cert_with_ocsp_and_sct(certs, privkey, vec![], vec![])
-        .expect("bad certificates/private key");
+        .map_err(|e| tls_err(format!("bad certificates/private key ({:?}, {:?}): {:?}",
+                                     cert_path, key_path, e)))?;
 
-    Arc::new(config)
+    Ok(Arc::new(config))
 }
\ No newline at end of file
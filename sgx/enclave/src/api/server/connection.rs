@@ -13,48 +13,80 @@ use mio::net::TcpStream;
 use mio::Token;
 use std::io;
 use std::io::{Read, Write};
-use std::net::Shutdown;
+use std::net::{Shutdown, SocketAddr};
 use std::sync::SgxMutex;
 use std::time::Instant;
 use tungstenite::Message;
+use tungstenite::protocol::frame::coding::CloseCode;
 
 use crate::api::{
     handler::request::{process_raw_request, RawRequest},
     handler::response::Response,
+    metrics::GLOBAL_METRICS,
+    reactor::cancel::CancelToken,
     reactor::exec::ExecReactor,
     reactor::httpc::HttpcReactor,
+    reactor::join::{JoinHandle, with_join_handle},
     reactor::waker::ReactorWaker,
     results::{Error, ErrorKind, ResponseBody, too_many_bytes_err},
     server::config::Config,
 };
-use crate::api::handler::context::Context;
+use crate::api::handler::context::{ConnState, Context};
 use crate::api::handler::request::process_ws_raw_request;
-use crate::api::server::websocket::WebSocket;
+use crate::api::server::websocket::{acquire_websocket_slot, register_websocket, release_websocket_slot, unregister_websocket, WebSocket};
+
+static CONTINUE_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 
 pub(crate) struct Connection {
     token: mio::Token,
     socket: TcpStream,
+    peer_addr: SocketAddr,
     tls_conn: rustls::ServerConnection,
     config: Arc<Config>,
     deferral: Arc<SgxMutex<Deferral>>,
     exec: Arc<SgxMutex<ExecReactor>>,
     httpc: Arc<SgxMutex<HttpcReactor>>,
+    state: Arc<SgxMutex<ConnState>>,
     request: Option<RawRequest>,
+    // Bytes received for the next request while one is still in flight.
+    pending: Option<Vec<u8>>,
+    in_flight: bool,
+    // Error reading the next request while one is in flight, sent (and the
+    // connection closed) once that one's response has gone out.
+    held_error: Option<Error>,
+    // A close response is queued, nothing the client sends after is served.
+    input_closed: bool,
     closing: bool,
     closed: bool,
     close_notify_sent: bool,
     ws: Option<Arc<SgxMutex<WebSocket>>>,
+    ws_registry_id: Option<usize>,
+    // Counted against the web socket limits, see `reserve_websocket`.
+    ws_slot: bool,
+    // The latest request's task, cancelled if the client goes away.
+    task_cancel: Option<CancelToken>,
+    // Plaintext rustls hasn't taken yet (from `outbound_pos`), it only
+    // buffers so much at a time.
+    outbound: Vec<u8>,
+    outbound_pos: usize,
+    // Send close_notify once `outbound` has drained.
+    close_after_flush: bool,
+    last_activity: Instant,
+    requests_served: usize,
 }
 
 impl Connection {
     pub(crate) fn new(
         conn_id: usize,
         socket: TcpStream,
+        peer_addr: SocketAddr,
         tls_conn: rustls::ServerConnection,
         config: Arc<Config>,
         exec: Arc<SgxMutex<ExecReactor>>,
         httpc: Arc<SgxMutex<HttpcReactor>>,
     ) -> Self {
+        GLOBAL_METRICS.inc_connections();
+
         let deferral = Arc::new(SgxMutex::new(
             Deferral::new(
                 Token(conn_id + 1),
@@ -65,16 +97,30 @@ impl Connection {
         Self {
             token: Token(conn_id),
             socket,
+            peer_addr,
             tls_conn,
             config,
             exec,
             httpc,
             deferral,
+            state: Arc::new(SgxMutex::new(ConnState::new())),
             request: None,
+            pending: None,
+            in_flight: false,
+            held_error: None,
+            input_closed: false,
             closing: false,
             closed: false,
             close_notify_sent: false,
             ws: None,
+            ws_registry_id: None,
+            ws_slot: false,
+            task_cancel: None,
+            outbound: Vec::new(),
+            outbound_pos: 0,
+            close_after_flush: false,
+            last_activity: Instant::now(),
+            requests_served: 0,
         }
     }
 
@@ -96,7 +142,7 @@ impl Connection {
 
         if ev.readiness().is_writable() {
             trace!("ready[{:?}]: WRITE", self.token);
-            self.write_tls_and_handle_error();
+            self.flush();
         }
 
         if self.is_closing() {
@@ -122,7 +168,11 @@ impl Connection {
             }
         };
 
-        if let Some((deferrals, futures)) = pending {
+        if let Some((mut deferrals, futures, failure)) = pending {
+            if let Some(err) = failure {
+                self.handle_error(&err);
+                deferrals.clear();
+            }
             for defer in deferrals {
                 trace!("wake[{:?}]: RUN", self.token);
                 match defer(self) {
@@ -137,7 +187,10 @@ impl Connection {
                     Ok(mut exec) => {
                         for future in futures {
                             trace!("wake[{:?}]: SPAWN", self.token);
-                            exec.spawn_boxed(poll, future);
+                            // Dropped, any JoinHandle on it resolves to an error.
+                            if let Err(err) = exec.spawn_boxed(poll, future) {
+                                warn!("wake[{:?}]: failed to spawn deferred future: {}", self.token, err);
+                            }
                         }
                     }
                     Err(err) => {
@@ -146,12 +199,12 @@ impl Connection {
                 }
             }
         }
+
+        self.resume(poll);
     }
 
     #[inline]
     fn handle_request(&mut self, poll: &mut mio::Poll) {
-        let config = self.config.clone();
-
         if self.is_closed() || self.is_closing() {
             return;
         }
@@ -159,6 +212,8 @@ impl Connection {
         let mut bytes_read: usize = 0;
         if let Some(req) = self.request.as_ref() {
             bytes_read = req.len();
+        } else if let Some(pending) = self.pending.as_ref() {
+            bytes_read = pending.len();
         }
 
         let mut request_body = Vec::new();
@@ -171,39 +226,98 @@ impl Connection {
         if request_body.len() > 0 {
             //trace!("req body: {:?}", String::from_utf8(request_body.clone()));
 
-            // Consume request body.
-            if let Some(req) = &mut self.request {
-                if let Err(err) = req.next(request_body) {
-                    self.handle_error(&err);
-                    return;
-                }
-            } else {
-                match RawRequest::new(request_body,
-                                      Instant::now()
-                                          .add(config.request_timeout())) {
-                    Ok(req) => {
-                        self.request = Some(req);
-                    }
-                    Err(err) => {
-                        self.handle_error(&err);
-                        return;
-                    }
-                }
+            self.consume(poll, request_body);
+        }
+    }
+
+    #[inline]
+    fn consume(&mut self, poll: &mut mio::Poll, request_body: Vec<u8>) {
+        let config = self.config.clone();
+
+        if self.input_closed || self.held_error.is_some() {
+            trace!("consume[{:?}]: DROP (closing)", self.token);
+            return;
+        }
+
+        if self.in_flight {
+            // Hold on to pipelined bytes until the current response is sent.
+            match self.pending.as_mut() {
+                Some(pending) => pending.extend_from_slice(request_body.as_slice()),
+                None => self.pending = Some(request_body),
             }
+            return;
+        }
 
-            if let Some(req) = self.request.take() {
-                if let Err(err) = req.validate(config) {
+        // Consume request body.
+        if let Some(req) = &mut self.request {
+            if let Err(err) = req.next(request_body) {
+                // Nothing more of it is read, the error response closes.
+                self.request = None;
+                self.handle_error(&err);
+                return;
+            }
+        } else {
+            match RawRequest::new(request_body,
+                                  Instant::now()
+                                      .add(config.request_timeout()),
+                                  config.max_bytes_received(),
+                                  config.max_headers(),
+                                  config.max_uri_length(),
+                                  config.max_header_bytes()) {
+                Ok(req) => {
+                    self.request = Some(req);
+                }
+                Err(err) => {
                     self.handle_error(&err);
                     return;
                 }
+            }
+        }
 
-                // Ready?
-                if req.ready() {
-                    self.process_request(poll, req);
-                    //self.send_mock_response();
-                } else {
-                    self.request = Some(req);
+        if let Some(mut req) = self.request.take() {
+            if let Err(err) = req.validate(config) {
+                self.handle_error(&err);
+                return;
+            }
+
+            // Headers passed validation, let the client send the body.
+            if req.take_expect_continue() {
+                trace!("consume[{:?}]: 100 CONTINUE", self.token);
+                self.write(CONTINUE_RESPONSE);
+            }
+
+            // Ready?
+            if req.ready() {
+                // Anything beyond this request belongs to the next one.
+                if let Some(remainder) = req.take_remainder() {
+                    self.pending = Some(remainder);
                 }
+
+                self.in_flight = true;
+                self.process_request(poll, req);
+                //self.send_mock_response();
+            } else {
+                self.request = Some(req);
+            }
+        }
+    }
+
+    // Start on any request that was received while the last one was in flight.
+    #[inline]
+    fn resume(&mut self, poll: &mut mio::Poll) {
+        if self.in_flight || self.is_closed() || self.is_closing() {
+            return;
+        }
+
+        if let Some(pending) = self.pending.take() {
+            if self.is_websocket() {
+                trace!("resume[{:?}]: DROP PENDING (websocket)", self.token);
+                return;
+            }
+
+            if pending.len() > 0 {
+                trace!("resume[{:?}]: PIPELINED", self.token);
+                self.consume(poll, pending);
             }
         }
     }
@@ -252,27 +366,48 @@ impl Connection {
     }
 
     #[inline]
-    fn process_request(&mut self, poll: &mut mio::Poll, req: RawRequest) {
+    fn process_request(&mut self, poll: &mut mio::Poll, mut req: RawRequest) {
+        GLOBAL_METRICS.inc_requests();
+
+        self.requests_served += 1;
+        if let Some(max_requests) = self.config.max_requests_per_connection() {
+            // An upgraded connection stops serving requests anyway.
+            if self.requests_served >= max_requests && !req.is_upgrade_websocket() {
+                req.set_last();
+            }
+        }
+
+        let config = self.config.clone();
         let deferral = self.deferral.clone();
         let httpc = self.httpc.clone();
+        let state = self.state.clone();
+        let peer_addr = self.peer_addr;
+        let cancel = CancelToken::new();
+        let task_cancel = cancel.clone();
+        self.task_cancel = Some(cancel.clone());
 
         if let Err(err) = self.spawn(poll, async move {
             if req.is_upgrade_websocket() {
-                process_ws_raw_request(deferral, httpc, req).await
+                process_ws_raw_request(config, deferral, httpc, state, task_cancel,
+                                       peer_addr, req).await
             } else {
-                process_raw_request(deferral, httpc, req).await
+                process_raw_request(config, deferral, httpc, state, task_cancel,
+                                    peer_addr, req).await
             }
-        }) {
+        }, cancel) {
+            // No response is coming for it, the error is the response.
+            self.in_flight = false;
             self.handle_error(&err);
         }
     }
 
     // Spawn an async function.
     #[inline]
-    fn spawn(&mut self, poll: &mut mio::Poll, future: impl Future<Output=()> + 'static + Send) -> Result<(), Error> {
+    fn spawn(&mut self, poll: &mut mio::Poll, future: impl Future<Output=()> + 'static + Send,
+             cancel: CancelToken) -> Result<(), Error> {
         match self.exec.lock() {
             Ok(mut exec) => {
-                exec.spawn(poll, future);
+                exec.spawn_cancellable(poll, future, cancel)?;
             }
             Err(err) => {
                 return Err(
@@ -299,9 +434,24 @@ impl Connection {
         */
 
         self.write(&body[..]);
+        self.in_flight = false;
 
-        if res.close() {
+        if !res.close() {
+            if let Some(err) = self.held_error.take() {
+                self.pending = None;
+                self.handle_error(&err);
+            }
+            return;
+        }
+
+        self.input_closed = true;
+        self.held_error = None;
+        self.pending = None;
+        // After the response, not ahead of what's still buffered.
+        if self.outbound.is_empty() {
             self.send_close_notify();
+        } else {
+            self.close_after_flush = true;
         }
     }
 
@@ -317,8 +467,51 @@ impl Connection {
                 self.write_tls_and_handle_error();
                 self.close();
                 self.deregister(poll);
+                return;
             }
         }
+
+        if self.is_idle(now) {
+            trace!("check_timeout[{:?}]: IDLE", self.token);
+            // Flush the close_notify before the socket goes away.
+            self.send_close_notify();
+            self.write_tls_and_handle_error();
+            self.close();
+            self.deregister(poll);
+        }
+    }
+
+    /// Mid request: still reading it, running it, or sending the response.
+    #[inline]
+    pub(crate) fn is_busy(&self) -> bool {
+        self.request.is_some()
+            || self.in_flight
+            || !self.outbound.is_empty()
+            || self.tls_conn.wants_write()
+    }
+
+    /// Closes the connection straight away, flushing what rustls holds first.
+    pub(crate) fn shutdown(&mut self, poll: &mut mio::Poll) {
+        if self.closed {
+            return;
+        }
+
+        self.send_close_notify();
+        self.write_tls_and_handle_error();
+        self.close();
+        self.deregister(poll);
+    }
+
+    // Idle keep-alive connections, partial requests are covered by the request
+    // timeout and web sockets are left to the application.
+    #[inline]
+    fn is_idle(&self, now: &Instant) -> bool {
+        !self.closed
+            && self.request.is_none()
+            && !self.in_flight
+            && self.outbound.is_empty()
+            && !self.is_websocket()
+            && now.saturating_duration_since(self.last_activity) > self.config.idle_timeout()
     }
 
     // Web Socket
@@ -327,12 +520,37 @@ impl Connection {
         self.ws.is_some()
     }
 
+    /// Counts this connection against `max_websockets(_per_ip)`, before the
+    /// upgrade is answered. Fails with `WSLimit` (a 503) when either is reached.
+    pub(crate) fn reserve_websocket(&mut self) -> Result<(), Error> {
+        if self.ws_slot {
+            return Ok(());
+        }
+
+        acquire_websocket_slot(self.peer_addr.ip(),
+                               self.config.max_websockets(),
+                               self.config.max_websockets_per_ip())?;
+        self.ws_slot = true;
+
+        Ok(())
+    }
+
+    #[inline]
+    fn release_websocket(&mut self) {
+        if self.ws_slot {
+            self.ws_slot = false;
+            release_websocket_slot(self.peer_addr.ip());
+        }
+    }
+
     #[inline]
     pub(crate) fn websocket(
         &mut self,
         websocket: Arc<SgxMutex<WebSocket>>,
         context: Context
     ) -> Result<(), Error> {
+        // Registered before the socket is locked below, see `broadcast`.
+        self.ws_registry_id = register_websocket(websocket.clone());
         self.ws = Some(websocket);
 
         return match self.ws.as_ref().unwrap().lock() {
@@ -372,6 +590,23 @@ impl Connection {
         }
     }
 
+    fn ws_close_with(&mut self, code: CloseCode, reason: &str) {
+        match self.ws.as_ref().unwrap().lock() {
+            Ok(mut websocket) => {
+                let mut tls_stream =
+                    mut_tls_stream(&mut self.tls_conn, &mut self.socket);
+                if let Err(err) = websocket.close_with(&mut tls_stream, code, reason) {
+                    warn!("failed to send websocket close frame: {}", err);
+                }
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'ws' during ws_close_with: {:?}", err);
+            }
+        }
+
+        self.closing = true;
+    }
+
     // Tls Session Related
     #[inline]
     fn handle_io_error(&mut self, err: io::Error) {
@@ -390,6 +625,18 @@ impl Connection {
                 self.closing = true;
                 return;
             }
+            // Close rather than drop messages the client would never know about.
+            if let ErrorKind::WSBackpressure = err.kind() {
+                warn!("closing websocket, client not keeping up: {}", err);
+                self.ws_close_with(CloseCode::Policy, "backpressure");
+                return;
+            }
+            // Stop reading, nothing after a bad frame can be trusted.
+            if let ErrorKind::WSInvalidPayload = err.kind() {
+                warn!("closing websocket, invalid frame payload: {}", err);
+                self.ws_close_with(CloseCode::Invalid, "invalid utf-8");
+                return;
+            }
 
             // Not a standard request.
             warn!("error occurred during websocket request: {}", err);
@@ -397,13 +644,18 @@ impl Connection {
         }
 
         warn!("failed to handle request: {}", err);
+        GLOBAL_METRICS.inc_errors(err.kind());
 
+        if self.input_closed {
+            // A close response is already queued, nothing goes out after it.
+            return;
+        }
         if self.is_closed() {
             // Abort early, stale connection.
             return;
         }
 
-        match Response::from_error(err).encode() {
+        match Response::from_error(err).encode(&self.config) {
             Ok(res) => {
                 self.send_response(&res);
             }
@@ -413,11 +665,26 @@ impl Connection {
         }
     }
 
+    /// Lets a handler still running for this connection see (through
+    /// `Context::cancelled`) that nobody is left to read its response.
+    #[inline]
+    fn cancel_task(&mut self) {
+        if let Some(cancel) = self.task_cancel.take() {
+            cancel.cancel();
+        }
+    }
+
     #[inline]
     fn close(&mut self) {
+        self.cancel_task();
         self.send_close_notify();
         let _ = self.socket.shutdown(Shutdown::Both);
         self.closed = true;
+
+        if let Some(id) = self.ws_registry_id.take() {
+            unregister_websocket(id);
+        }
+        self.release_websocket();
     }
 
     #[inline]
@@ -428,8 +695,10 @@ impl Connection {
         }
     }
 
+    /// Closes the connection if the socket can't be registered, check
+    /// `is_closed` after.
     #[inline]
-    pub(crate) fn register(&self, poll: &mut mio::Poll) {
+    pub(crate) fn register(&mut self, poll: &mut mio::Poll) {
         match self.deferral.lock() {
             Ok(deferral) => {
                 if let Err(err) = deferral.register(poll) {
@@ -441,26 +710,36 @@ impl Connection {
             }
         }
 
-        poll.register(&self.socket,
-                      self.token,
-                      self.event_set(),
-                      mio::PollOpt::level() | mio::PollOpt::oneshot())
-            .unwrap();
+        if let Err(err) = poll.register(&self.socket,
+                                        self.token,
+                                        self.event_set(),
+                                        mio::PollOpt::level() | mio::PollOpt::oneshot()) {
+            warn!("failed to register connection {:?}, closing: {:?}", self.token, err);
+            self.close();
+            self.deregister(poll);
+        }
     }
 
+    // A connection that can't be polled would never be served again, so it's
+    // closed rather than left to leak.
     #[inline]
-    fn reregister(&self, poll: &mut mio::Poll) {
-        poll.reregister(&self.socket,
-                        self.token,
-                        self.event_set(),
-                        mio::PollOpt::level() | mio::PollOpt::oneshot())
-            .unwrap();
+    fn reregister(&mut self, poll: &mut mio::Poll) {
+        if let Err(err) = poll.reregister(&self.socket,
+                                          self.token,
+                                          self.event_set(),
+                                          mio::PollOpt::level() | mio::PollOpt::oneshot()) {
+            warn!("failed to reregister connection {:?}, closing: {:?}", self.token, err);
+            self.close();
+            self.deregister(poll);
+        }
     }
 
     #[inline]
     fn deregister(&self, poll: &mut mio::Poll) {
-        poll.deregister(&self.socket)
-            .unwrap();
+        // Fails if it never got registered, nothing more to do then.
+        if let Err(err) = poll.deregister(&self.socket) {
+            trace!("failed to deregister connection {:?}: {:?}", self.token, err);
+        }
 
         match self.deferral.lock() {
             Ok(deferral) => {
@@ -477,7 +756,7 @@ impl Connection {
     #[inline]
     fn event_set(&self) -> mio::Ready {
         let rd = self.tls_conn.wants_read();
-        let wr = self.tls_conn.wants_write();
+        let wr = self.tls_conn.wants_write() || !self.outbound.is_empty();
 
         if rd && wr {
             mio::Ready::readable() | mio::Ready::writable()
@@ -492,9 +771,18 @@ impl Connection {
         if let Ok(io_state) = self.tls_conn.process_new_packets() {
             if io_state.plaintext_bytes_to_read() > 0 {
                 if io_state.plaintext_bytes_to_read() + bytes_read > self.config.max_bytes_received() {
-                    self.handle_error(&too_many_bytes_err(
+                    let err = too_many_bytes_err(
                         io_state.plaintext_bytes_to_read() + bytes_read,
-                        self.config.max_bytes_received()));
+                        self.config.max_bytes_received());
+                    // Answering now would put it ahead of the in flight
+                    // request's response, it's sent after that instead.
+                    if self.in_flight {
+                        if self.held_error.is_none() {
+                            self.held_error = Some(err);
+                        }
+                    } else {
+                        self.handle_error(&err);
+                    }
                     return 0;
                 }
 
@@ -531,6 +819,7 @@ impl Connection {
                 if let io::ErrorKind::ConnectionAborted = err.kind() {
                     trace!("TLS read error: ConnectionAborted");
                     self.closing = true;
+                    self.cancel_task();
                     return;
                 }
 
@@ -542,14 +831,27 @@ impl Connection {
                 // EOF
                 trace!("TLS read error: EOF");
                 self.closing = true;
+                self.cancel_task();
                 return;
             }
-            Ok(_) => {}
+            Ok(_) => {
+                self.last_activity = Instant::now();
+            }
         };
 
         // Process newly-received TLS messages.
+        let handshaking = self.tls_conn.is_handshaking();
         if let Err(err) = self.tls_conn.process_new_packets() {
-            warn!("TLS error: {:?}", err);
+            if handshaking {
+                GLOBAL_METRICS.inc_tls_handshake_failures();
+                if self.config.log_tls_failures() {
+                    warn!("TLS handshake with {} failed: {}", self.peer_addr, err);
+                } else {
+                    trace!("TLS handshake with {} failed: {}", self.peer_addr, err);
+                }
+            } else {
+                warn!("TLS error from {}: {}", self.peer_addr, err);
+            }
 
             // last gasp write to send any alerts
             self.write_tls_and_handle_error();
@@ -559,26 +861,81 @@ impl Connection {
         }
     }
 
+    /// Queues `plaintext`, whatever rustls won't buffer yet is kept until
+    /// the socket drains (see `flush`).
     fn write(&mut self, plaintext: &[u8]) {
-        match self.tls_conn.writer().write_all(plaintext) {
-            Err(err) => {
-                if let io::ErrorKind::ConnectionAborted = err.kind() {
-                    trace!("TLS plain write error: ConnectionAborted");
+        self.outbound.extend_from_slice(plaintext);
+        self.write_outbound();
+    }
+
+    // Hand as much of `outbound` to rustls as it'll take.
+    fn write_outbound(&mut self) {
+        while self.outbound_pos < self.outbound.len() {
+            match self.tls_conn.writer().write(&self.outbound[self.outbound_pos..]) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.outbound_pos += n;
+                }
+                Err(err) => {
+                    if let io::ErrorKind::ConnectionAborted = err.kind() {
+                        trace!("TLS plain write error: ConnectionAborted");
+                        self.closing = true;
+                        return;
+                    }
+
+                    warn!("TLS plain write error: {:?}", err);
                     self.closing = true;
                     return;
                 }
+            }
+        }
 
-                warn!("TLS plain write error: {:?}", err);
-                self.closing = true;
+        if self.outbound_pos < self.outbound.len() {
+            return;
+        }
+        self.outbound.clear();
+        self.outbound_pos = 0;
+
+        if self.close_after_flush {
+            self.close_after_flush = false;
+            self.send_close_notify();
+        }
+    }
+
+    // Write TLS records until the socket would block, topping rustls up from
+    // `outbound` as it empties. Anything left waits for the next writable.
+    fn flush(&mut self) {
+        loop {
+            self.write_outbound();
+            if self.closing || !self.tls_conn.wants_write() {
+                return;
+            }
+
+            match self.write_tls() {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(err) => {
+                    if let io::ErrorKind::WouldBlock = err.kind() {
+                        return;
+                    }
+
+                    warn!("TLS write failed {:?}", err);
+                    self.closing = true;
+                    return;
+                }
             }
-            Ok(_) => {}
         }
     }
 
     #[inline]
     fn write_tls(&mut self) -> io::Result<usize> {
-        self.tls_conn
-            .write_tls(&mut self.socket)
+        let written = self.tls_conn
+            .write_tls(&mut self.socket)?;
+        if written > 0 {
+            self.last_activity = Instant::now();
+        }
+
+        Ok(written)
     }
 
     #[inline]
@@ -606,6 +963,17 @@ impl Connection {
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        GLOBAL_METRICS.dec_connections();
+
+        if let Some(id) = self.ws_registry_id.take() {
+            unregister_websocket(id);
+        }
+        self.release_websocket();
+    }
+}
+
 fn mut_tls_stream<'a>(
     conn: &'a mut rustls::ServerConnection,
     sock: &'a mut TcpStream
@@ -617,6 +985,8 @@ pub(crate) struct Deferral {
     waker: ReactorWaker,
     defers: Vec<Box<dyn Send + Sync + for<'a> FnOnce(&'a mut Connection) -> Result<(), Error>>>,
     futures: Vec<BoxFuture<'static, ()>>,
+    // Handled by the connection on its next wake, see `fail`.
+    failure: Option<Error>,
     // Options
     max_defers_queue: Option<usize>,
     max_futures_queue: Option<usize>,
@@ -632,6 +1002,7 @@ impl Deferral {
             waker: ReactorWaker::new(waker_token),
             defers: Vec::new(),
             futures: Vec::new(),
+            failure: None,
             max_defers_queue,
             max_futures_queue,
         }
@@ -660,10 +1031,9 @@ impl Deferral {
     }
 
     #[inline]
-    #[allow(dead_code)]
     pub(crate) fn spawn(&mut self, future: impl Future<Output=()> + 'static + Send) -> Result<(), Error> {
         if let Some(max_futures_queue) = self.max_futures_queue {
-            if self.defers.len() >= max_futures_queue {
+            if self.futures.len() >= max_futures_queue {
                 return Err(Error::new_with_kind(
                     ErrorKind::ServerFault,
                     format!("unable to queue future, limit exceeded: {}", max_futures_queue).to_string(),
@@ -679,6 +1049,35 @@ impl Deferral {
         Ok(())
     }
 
+    /// Has the connection handle `err` (see `Connection::handle_error`) when it
+    /// next wakes, dropping anything still deferred. Not subject to the queue
+    /// limits, only the first failure is kept.
+    #[inline]
+    pub(crate) fn fail(&mut self, err: Error) {
+        if self.failure.is_none() {
+            self.failure = Some(err);
+        }
+
+        if let Err(err) = self.waker.trigger() {
+            warn!("Deferral->fail failed to trigger waker: {:?}", err)
+        }
+    }
+
+    /// Like `spawn` but the output can be awaited through the returned handle.
+    #[inline]
+    pub(crate) fn spawn_with_handle<T>(
+        &mut self,
+        future: impl Future<Output=T> + 'static + Send,
+    ) -> Result<JoinHandle<T>, Error>
+        where
+            T: 'static + Send,
+    {
+        let (task, handle) = with_join_handle(future);
+        self.spawn(task)?;
+
+        Ok(handle)
+    }
+
     #[inline]
     pub(crate) fn register(&self, poll: &mut mio::Poll) -> std::io::Result<()> {
         self.waker.register(poll)
@@ -692,13 +1091,14 @@ impl Deferral {
     #[inline]
     fn take_pending(&mut self) -> (
         Vec<Box<dyn Send + Sync + for<'a> FnOnce(&'a mut Connection) -> Result<(), Error>>>,
-        Vec<BoxFuture<'static, ()>>
+        Vec<BoxFuture<'static, ()>>,
+        Option<Error>,
     ) {
         // Clear the waker readiness state prior to removing pending items.
         if let Err(err) = self.waker.clear() {
             warn!("Deferral failed to clear waker: {:?}", err)
         }
 
-        (std::mem::take(&mut self.defers), std::mem::take(&mut self.futures))
+        (std::mem::take(&mut self.defers), std::mem::take(&mut self.futures), self.failure.take())
     }
 }
\ No newline at end of file
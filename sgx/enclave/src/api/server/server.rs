@@ -1,60 +1,53 @@
-use alloc::sync::Arc;
-use core::sync::atomic::{AtomicUsize, Ordering};
-use core::time::Duration;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use mio::event::Event;
-use mio::net::TcpListener;
+use mio::net::{TcpListener, TcpStream};
 use mio::Token;
 use net2::TcpBuilder;
 use net2::unix::UnixTcpBuilderExt;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::SgxMutex;
 use std::time::Instant;
 
 use crate::api::reactor::exec::ExecReactor;
 use crate::api::reactor::httpc::HttpcReactor;
+use crate::api::reactor::timer;
+use crate::api::results::{Error, ErrorKind};
 use crate::api::server::config::Config;
 use crate::api::server::connection::Connection;
 
 lazy_static!(
     pub static ref SERVER_ID_SEQ: AtomicUsize = AtomicUsize::new(0);
+    // Configs of the running servers (one per API thread), for TLS reloads.
+    static ref SERVER_CONFIGS: SgxMutex<Vec<Weak<Config>>> = SgxMutex::new(Vec::new());
 );
 
-const LISTENER_TOKEN: Token = Token(0);
-
-// 50 Kb
-const MAX_BYTES_RECEIVED: usize = 50 * 1024;
-const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
-// Currently the exec deadlines cannot be surfaced to the future
-// their main purpose is to release some system resources.
-const EXEC_TIMEOUT: Duration = Duration::from_secs(7200);
-
-// Per connection.
-const DEFERRAL_BACKLOG: usize = 100;
-const FUTURE_BACKLOG: usize = 100;
-
-const TCP_BACKLOG: i32 = 250;
-
-const MIO_EVENTS_CAPACITY: usize = 2048;
-const MIO_TIMEOUT_POLL: Duration = Duration::from_millis(1000);
+// Set once to stop every server, see `request_shutdown`.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
 
+// Listeners take the tokens below this.
 const MIO_SERVER_OFFSET: usize = 10;
 const MIO_EXEC_OFFSET: usize = MIO_SERVER_OFFSET + u32::MAX as usize;
 const MIO_HTTPC_OFFSET: usize = MIO_EXEC_OFFSET + u32::MAX as usize;
 
 pub(crate) struct Server {
     id: usize,
-    server: TcpListener,
+    listeners: Vec<TcpListener>,
     connections: HashMap<usize, Connection>,
     config: Arc<Config>,
     exec: Arc<SgxMutex<ExecReactor>>,
     httpc: Arc<SgxMutex<HttpcReactor>>,
     next_id: usize,
     last_timeout: Instant,
+    // Set once shutdown starts, busy connections are force closed after it.
+    drain_deadline: Option<Instant>,
 }
 
 impl Server {
-    fn new(server: TcpListener, config: Arc<Config>) -> Self {
+    fn new(listeners: Vec<TcpListener>, config: Arc<Config>) -> Self {
         let exec = Arc::new(
             SgxMutex::new(ExecReactor::new(MIO_EXEC_OFFSET, config.clone())));
         let httpc = Arc::new(
@@ -63,13 +56,14 @@ impl Server {
 
         Self {
             id: SERVER_ID_SEQ.fetch_add(1, Ordering::SeqCst),
-            server,
+            listeners,
             connections: HashMap::new(),
             config: config.clone(),
             exec,
             httpc,
             next_id: MIO_SERVER_OFFSET,
             last_timeout: Instant::now(),
+            drain_deadline: None,
         }
     }
 
@@ -83,21 +77,50 @@ impl Server {
             }
         }
 
-        poll.register(&self.server,
-                      LISTENER_TOKEN,
-                      mio::Ready::readable(),
-                      mio::PollOpt::level()).unwrap();
+        for (idx, listener) in self.listeners.iter().enumerate() {
+            poll.register(listener,
+                          Token(idx),
+                          mio::Ready::readable(),
+                          mio::PollOpt::level())?;
+        }
 
         Ok(())
     }
 
-    fn accept(&mut self, poll: &mut mio::Poll) {
-        match self.server.accept() {
+    fn accept(&mut self, poll: &mut mio::Poll, listener: usize) {
+        let accepted = match self.listeners.get(listener) {
+            Some(listener) => listener.accept(),
+            None => {
+                warn!("[{}] accept on unknown listener: {}", self.id, listener);
+                return;
+            }
+        };
+
+        match accepted {
             Ok((socket, addr)) => {
+                if let Some(max_connections) = self.config.max_connections() {
+                    if self.connections.len() >= max_connections {
+                        // Dropping the socket closes it, the client can retry.
+                        warn!("[{}] rejected connection: {} (at max connections: {})",
+                              self.id, addr, max_connections);
+                        return;
+                    }
+                }
+
                 debug!("[{}] accepted connection: {}", self.id, addr);
 
-                let tls_conn = rustls::ServerConnection::new(
-                    Arc::clone(&self.config.tls_config())).unwrap();
+                if let Err(err) = self.apply_socket_options(&socket) {
+                    warn!("[{}] failed to set socket options for {}: {:?}", self.id, addr, err);
+                }
+
+                // Read per accept so a reloaded certificate applies to new connections.
+                let tls_conn = match rustls::ServerConnection::new(self.config.tls_config()) {
+                    Ok(tls_conn) => tls_conn,
+                    Err(err) => {
+                        warn!("[{}] failed to create TLS session for {}: {:?}", self.id, addr, err);
+                        return;
+                    }
+                };
 
                 let conn_id = self.next_id;
 
@@ -108,11 +131,15 @@ impl Server {
                 }
 
                 self.connections.insert(conn_id, Connection::new(conn_id,
-                                                                 socket, tls_conn,
+                                                                 socket, addr, tls_conn,
                                                                  self.config.clone(),
                                                                  self.exec.clone(),
                                                                  self.httpc.clone()));
-                self.connections.get_mut(&conn_id).unwrap().register(poll);
+                let conn = self.connections.get_mut(&conn_id).unwrap();
+                conn.register(poll);
+                if conn.is_closed() {
+                    self.connections.remove(&conn_id);
+                }
             }
             Err(e) => {
                 warn!("encountered error while accepting connection; err={:?}", e);
@@ -120,6 +147,20 @@ impl Server {
         }
     }
 
+    #[inline]
+    fn apply_socket_options(&self, socket: &TcpStream) -> std::io::Result<()> {
+        socket.set_nodelay(self.config.tcp_nodelay())?;
+
+        if let Some(size) = self.config.recv_buffer_size() {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.config.send_buffer_size() {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+
     fn handle_event(&mut self, poll: &mut mio::Poll, event: &Event) {
         let token = event.token();
         let token_us: usize = usize::from(token);
@@ -156,6 +197,8 @@ impl Server {
                     self.connections.remove(&conn_id);
                 }
             }
+        } else if token_us < self.listeners.len() {
+            self.accept(poll, token_us);
         } else {
             warn!("unhandled token: {}", token_us);
         }
@@ -163,13 +206,15 @@ impl Server {
 
     pub fn check_timeouts(&mut self, poll: &mut mio::Poll) {
         let now = Instant::now();
-        if now.saturating_duration_since(self.last_timeout).lt(&MIO_TIMEOUT_POLL) {
+        if now.saturating_duration_since(self.last_timeout).lt(&self.config.poll_timeout()) {
             return;
         }
 
         for (_, conn) in self.connections.iter_mut() {
             conn.check_timeout(poll, &now);
         }
+        // Timed out connections would otherwise hold a slot against max_connections.
+        self.connections.retain(|_, conn| !conn.is_closed());
 
         match self.httpc.lock() {
             Ok(mut httpc) => httpc.check_timeouts(poll),
@@ -187,54 +232,187 @@ impl Server {
 
         self.last_timeout = now;
     }
+
+    #[inline]
+    fn is_draining(&self) -> bool {
+        self.drain_deadline.is_some()
+    }
+
+    /// Stops accepting, existing connections get until the drain timeout to
+    /// finish what they're doing.
+    fn start_drain(&mut self, poll: &mut mio::Poll) {
+        for listener in self.listeners.iter() {
+            if let Err(err) = poll.deregister(listener) {
+                warn!("[{}] failed to deregister listener during server->start_drain: {:?}", self.id, err);
+            }
+        }
+
+        self.drain_deadline = Some(Instant::now() + self.config.drain_timeout());
+
+        info!("[{}] draining {} connection(s)", self.id, self.connections.len());
+    }
+
+    /// Closes connections as they go idle, or all of them once the deadline
+    /// has passed. True when none are left.
+    fn drain(&mut self, poll: &mut mio::Poll) -> bool {
+        let expired = match self.drain_deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => return false,
+        };
+
+        let id = self.id;
+        for (_, conn) in self.connections.iter_mut() {
+            if !conn.is_busy() {
+                conn.shutdown(poll);
+            } else if expired {
+                warn!("[{}] drain timed out, closing busy connection", id);
+                conn.shutdown(poll);
+            }
+        }
+        self.connections.retain(|_, conn| !conn.is_closed());
+
+        self.connections.is_empty()
+    }
+}
+
+/// Asks every running server to stop, see `Config::set_drain_timeout`.
+pub(crate) fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn register_config(config: &Arc<Config>) {
+    match SERVER_CONFIGS.lock() {
+        Ok(mut configs) => {
+            configs.retain(|c| c.strong_count() > 0);
+            configs.push(Arc::downgrade(config));
+        }
+        Err(err) => {
+            warn!("failed to acquire lock on 'SERVER_CONFIGS' during server->register_config: {:?}", err);
+        }
+    }
+}
+
+/// Reloads the TLS certificate/key on every running server.
+pub(crate) fn reload_tls(cert_path: &str, key_path: &str) -> Result<(), Error> {
+    let configs: Vec<Arc<Config>> = match SERVER_CONFIGS.lock() {
+        Ok(configs) => configs.iter().filter_map(|c| c.upgrade()).collect(),
+        Err(err) => {
+            return Err(Error::new(
+                format!("failed to acquire lock on 'SERVER_CONFIGS' during server->reload_tls: {:?}", err)));
+        }
+    };
+
+    for config in configs.iter() {
+        config.reload_tls(cert_path, key_path)?;
+    }
+
+    info!("reloaded TLS config for {} server(s) ({}, {})", configs.len(), cert_path, key_path);
+
+    Ok(())
 }
 
 #[inline]
-fn create_net_listener(addr: &str) -> std::net::TcpListener {
-    let listener = TcpBuilder::new_v4().unwrap()
-        .reuse_address(true).unwrap()
-        .reuse_port(true).unwrap()
-        .bind(&addr).unwrap()
-        .listen(TCP_BACKLOG).unwrap();
-    listener.set_nonblocking(true).unwrap();
-    listener
+fn create_net_listener(addr: &SocketAddr, backlog: i32) -> std::io::Result<std::net::TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            // Keep v6 listeners from also claiming the v4 port.
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    let listener = builder
+        .reuse_address(true)?
+        .reuse_port(true)?
+        .bind(addr)?
+        .listen(backlog)?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
 }
 
-pub(crate) fn start_api_server(addr: &str) {
-    let config = Arc::new(Config::new(
-        MAX_BYTES_RECEIVED,
-        REQUEST_TIMEOUT,
-        EXEC_TIMEOUT,
-    Some(DEFERRAL_BACKLOG),
-    Some(FUTURE_BACKLOG)));
+#[allow(dead_code)]
+pub(crate) fn start_api_server(addr: &str, cert_path: &str, key_path: &str) -> Result<(), Error> {
+    start_api_servers(&[addr], cert_path, key_path)
+}
+
+/// Runs the API server on this thread, accepting on every address in `addrs`.
+pub(crate) fn start_api_servers(addrs: &[&str], cert_path: &str, key_path: &str) -> Result<(), Error> {
+    if addrs.is_empty() || addrs.len() > MIO_SERVER_OFFSET {
+        return Err(Error::new_with_kind(
+            ErrorKind::ServerFault,
+            format!("expected between 1 and {} listen addresses, got {}",
+                    MIO_SERVER_OFFSET, addrs.len())));
+    }
+
+    let config = Config::from_env(cert_path, key_path)?;
+    config.validate()?;
+
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs.iter() {
+        let parsed: SocketAddr = addr.parse()
+            .map_err(|e| listen_err(addr, format!("{}", e)))?;
+        let listener = create_net_listener(&parsed, config.tcp_backlog())
+            .map_err(|e| listen_err(addr, format!("{}", e)))?;
 
-    let listener = TcpListener::from_std(
-        create_net_listener(&addr)).unwrap();
+        listeners.push(TcpListener::from_std(listener)
+            .map_err(|e| listen_err(addr, format!("{}", e)))?);
+    }
 
-    let mut poll = mio::Poll::new().unwrap();
-    let mut server = Server::new(listener, config);
+    let poll_timeout = config.poll_timeout();
     let mut events = mio::Events::with_capacity(
-        MIO_EVENTS_CAPACITY);
+        config.events_capacity());
+
+    let config = Arc::new(config);
+    register_config(&config);
+
+    let mut poll = mio::Poll::new()
+        .map_err(|e| server_err(format!("failed to create poll: {}", e)))?;
+    let mut server = Server::new(listeners, config);
 
-    server.register(&mut poll).unwrap();
+    server.register(&mut poll)
+        .map_err(|e| server_err(format!("failed to register listeners: {}", e)))?;
 
-    info!("🚀 [{}] starting API server ({})", server.id, &addr);
+    info!("🚀 [{}] starting API server ({})", server.id, addrs.join(", "));
 
     loop {
-        poll.poll(&mut events, Some(MIO_TIMEOUT_POLL))
+        // Wake up in time for the next sleeping task.
+        let timeout = match timer::next_deadline() {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now())
+                .min(poll_timeout),
+            None => poll_timeout,
+        };
+        poll.poll(&mut events, Some(timeout))
             .unwrap();
 
+        timer::fire_expired(&Instant::now());
+
         server.check_timeouts(&mut poll);
 
         for event in events.iter() {
-            match event.token() {
-                LISTENER_TOKEN => {
-                    server.accept(&mut poll);
-                }
-                _ => {
-                    server.handle_event(&mut poll, &event)
-                }
-            }
+            server.handle_event(&mut poll, &event)
+        }
+
+        if !server.is_draining() && SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            server.start_drain(&mut poll);
+        }
+        if server.is_draining() && server.drain(&mut poll) {
+            info!("🛑 [{}] stopped API server", server.id);
+
+            return Ok(());
         }
     }
-}
\ No newline at end of file
+}
+
+#[inline]
+fn server_err(msg: String) -> Error {
+    Error::new_with_kind(ErrorKind::ServerFault, msg)
+}
+
+#[inline]
+fn listen_err(addr: &str, msg: String) -> Error {
+    Error::new_with_kind(
+        ErrorKind::ServerFault,
+        format!("failed to listen on '{}': {}", addr, msg))
+}
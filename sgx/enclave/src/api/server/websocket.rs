@@ -1,13 +1,18 @@
 use alloc::boxed::Box;
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use futures::future::BoxFuture;
 use mio::net::TcpStream;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::SgxMutex;
 use tungstenite::Message;
-use tungstenite::protocol::{Role, WebSocketConfig, WebSocketContext};
+use tungstenite::protocol::{CloseFrame, Role, WebSocketConfig, WebSocketContext};
+use tungstenite::protocol::frame::coding::CloseCode;
 use tungstenite::Error as TungsteniteError;
 use tungstenite::error::ProtocolError;
 
@@ -17,6 +22,147 @@ use crate::api::server::connection::Deferral;
 
 pub(crate) type SubscriptionHandler = Arc<dyn Send + Sync + Fn(Arc<SgxMutex<Context>>, Arc<Message>) -> BoxFuture<'static, ()>>;
 pub(crate) type SubscriptionHandlerFn = fn(Arc<SgxMutex<Context>>, Arc<Message>) -> BoxFuture<'static, ()>;
+pub(crate) type SubscriptionId = usize;
+
+lazy_static! {
+    // Every active web socket across all servers, for `broadcast`.
+    static ref WS_REGISTRY: SgxMutex<HashMap<usize, Arc<SgxMutex<WebSocket>>>> =
+        SgxMutex::new(HashMap::new());
+
+    // Open web sockets per client address, see `acquire_websocket_slot`.
+    static ref WS_PER_IP: SgxMutex<HashMap<IpAddr, usize>> =
+        SgxMutex::new(HashMap::new());
+}
+
+// Messages held for a socket that isn't active yet.
+const WS_MAX_PENDING_MESSAGES: usize = 128;
+
+// Connection tokens are only unique per server, registry ids are global.
+static WS_REGISTRY_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+// Open web sockets, see `acquire_websocket_slot`.
+static WS_ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Adds `ws` to the broadcast registry, the returned id is passed to
+/// `unregister_websocket` once the connection is gone.
+pub(crate) fn register_websocket(ws: Arc<SgxMutex<WebSocket>>) -> Option<usize> {
+    let id = WS_REGISTRY_SEQ.fetch_add(1, Ordering::Relaxed);
+
+    match WS_REGISTRY.lock() {
+        Ok(mut registry) => {
+            registry.insert(id, ws);
+            Some(id)
+        }
+        Err(err) => {
+            warn!("failed to acquire lock on 'registry' during register_websocket: {:?}", err);
+            None
+        }
+    }
+}
+
+/// Counts a web socket from `ip` against the limits, failing with `WSLimit`
+/// (counting nothing) when either is reached. Give it back with
+/// `release_websocket_slot`.
+pub(crate) fn acquire_websocket_slot(
+    ip: IpAddr,
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+) -> Result<(), Error> {
+    // Both counts change under this lock so concurrent upgrades can't overshoot.
+    let mut per_ip = match WS_PER_IP.lock() {
+        Ok(per_ip) => per_ip,
+        Err(err) => {
+            return Err(Error::new_with_kind(
+                ErrorKind::WSFault,
+                format!("failed to acquire lock on 'per_ip' during acquire_websocket_slot: {:?}", err),
+            ));
+        }
+    };
+
+    if let Some(max_total) = max_total {
+        if WS_ACTIVE.load(Ordering::Relaxed) >= max_total {
+            return Err(ws_limit_err(format!("too many web sockets (max: {})", max_total)));
+        }
+    }
+    // Checked before the entry is made, a refused address mustn't leave a
+    // zero count behind (`release_websocket_slot` only removes its own).
+    if let Some(max_per_ip) = max_per_ip {
+        if per_ip.get(&ip).copied().unwrap_or(0) >= max_per_ip {
+            return Err(ws_limit_err(format!("too many web sockets from {} (max: {})", ip, max_per_ip)));
+        }
+    }
+
+    *per_ip.entry(ip).or_insert(0) += 1;
+    WS_ACTIVE.fetch_add(1, Ordering::Relaxed);
+
+    Ok(())
+}
+
+pub(crate) fn release_websocket_slot(ip: IpAddr) {
+    match WS_PER_IP.lock() {
+        Ok(mut per_ip) => {
+            if let Some(count) = per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+                WS_ACTIVE.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        Err(err) => {
+            warn!("failed to acquire lock on 'per_ip' during release_websocket_slot: {:?}", err);
+        }
+    }
+}
+
+pub(crate) fn unregister_websocket(id: usize) {
+    match WS_REGISTRY.lock() {
+        Ok(mut registry) => {
+            registry.remove(&id);
+        }
+        Err(err) => {
+            warn!("failed to acquire lock on 'registry' during unregister_websocket: {:?}", err);
+        }
+    }
+}
+
+/// Sends `msg` to every web socket (on any server) with a subscription to
+/// `topic` (`None` for plain subscribers), returns how many it was queued on.
+/// A socket too far behind to queue it on is closed with policy violation
+/// (1008), see `WebSocket::send`.
+pub(crate) fn broadcast(topic: Option<String>, msg: Message) -> Result<usize, Error> {
+    // Take a snapshot so the registry isn't held while each socket is locked,
+    // a connection may be registering (holding its own lock) meanwhile.
+    let sockets: Vec<Arc<SgxMutex<WebSocket>>> = match WS_REGISTRY.lock() {
+        Ok(registry) => registry.values().cloned().collect(),
+        Err(err) => {
+            return Err(Error::new_with_kind(
+                ErrorKind::WSFault,
+                format!("failed to acquire lock on 'registry' during broadcast: {:?}", err),
+            ));
+        }
+    };
+
+    let mut sent = 0;
+    for ws in sockets {
+        match ws.lock() {
+            Ok(mut ws) => {
+                if !ws.is_subscribed(&topic) {
+                    continue;
+                }
+                match ws.send(msg.clone()) {
+                    Ok(_) => sent += 1,
+                    Err(err) => warn!("failed to queue broadcast message: {}", err),
+                }
+            }
+            Err(err) => {
+                warn!("failed to acquire lock on 'ws' during broadcast: {:?}", err);
+            }
+        }
+    }
+
+    Ok(sent)
+}
 
 macro_rules! map_tungstenite_err(($fmt:literal, $err:expr) => {
     match $err {
@@ -24,6 +170,9 @@ macro_rules! map_tungstenite_err(($fmt:literal, $err:expr) => {
         | TungsteniteError::AlreadyClosed
         | TungsteniteError::Protocol(ProtocolError::SendAfterClosing)
         | TungsteniteError::Protocol(ProtocolError::ReceivedAfterClosing) => Error::new_ws_closed(),
+        TungsteniteError::Utf8 => {
+            Error::new_with_kind(ErrorKind::WSInvalidPayload, format!($fmt, $err))
+        }
         _ => {
             Error::new_with_kind(ErrorKind::WSFault, format!($fmt, $err))
         }
@@ -32,7 +181,8 @@ macro_rules! map_tungstenite_err(($fmt:literal, $err:expr) => {
 
 pub(crate) struct WebSocket {
     deferral: Arc<SgxMutex<Deferral>>,
-    subscriptions: Vec<SubscriptionHandler>,
+    subscriptions: BTreeMap<SubscriptionId, Subscription>,
+    next_subscription_id: SubscriptionId,
     context: Option<Arc<SgxMutex<Context>>>,
     ws_context: WebSocketContext,
     pending: Option<Vec<Message>>,
@@ -44,7 +194,8 @@ impl WebSocket {
     pub(crate) fn new(deferral: Arc<SgxMutex<Deferral>>) -> Self {
         Self {
             deferral,
-            subscriptions: Vec::new(),
+            subscriptions: BTreeMap::new(),
+            next_subscription_id: 0,
             context: None,
             ws_context: WebSocketContext::new(
                 Role::Server, Some(WebSocketConfig::default()),
@@ -55,10 +206,26 @@ impl WebSocket {
     }
 
     #[inline]
-    pub(crate) fn subscribe(&mut self, handler: SubscriptionHandler) -> Result<(), Error> {
-        self.subscriptions.push(handler);
+    pub(crate) fn subscribe(
+        &mut self,
+        topic: Option<String>,
+        handler: SubscriptionHandler,
+    ) -> Result<SubscriptionId, Error> {
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.subscriptions.insert(id, Subscription { topic, handler });
 
-        Ok(())
+        Ok(id)
+    }
+
+    #[inline]
+    pub(crate) fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.subscriptions.remove(&id).is_some()
+    }
+
+    #[inline]
+    pub(crate) fn is_subscribed(&self, topic: &Option<String>) -> bool {
+        self.subscriptions.values().any(|sub| sub.topic.eq(topic))
     }
 
     #[inline]
@@ -68,16 +235,31 @@ impl WebSocket {
                 self.pending = Some(Vec::new());
             }
 
-            self.pending.as_mut().unwrap().push(msg);
+            let pending = self.pending.as_mut().unwrap();
+            if pending.len() < WS_MAX_PENDING_MESSAGES {
+                pending.push(msg);
 
-            return Ok(());
+                return Ok(());
+            }
         }
 
         return match self.deferral.as_ref().lock() {
             Ok(mut deferral) => {
-                deferral.defer(Box::new(move |conn| {
-                    conn.ws_send(msg)
-                }))
+                let res = if self.ready {
+                    deferral.defer(Box::new(move |conn| {
+                        conn.ws_send(msg)
+                    })).map_err(|err| format!("failed to queue send: {}", err))
+                } else {
+                    Err(format!("too many messages pending activation (max: {})",
+                                WS_MAX_PENDING_MESSAGES))
+                };
+
+                // The client would never know it missed `msg`, have the
+                // connection close it (policy violation) instead.
+                res.map_err(|message| {
+                    deferral.fail(backpressure_err(message.clone()));
+                    backpressure_err(message)
+                })
             }
             Err(err) => {
                 Err(Error::new_with_kind(
@@ -103,6 +285,21 @@ impl WebSocket {
         }
     }
 
+    /// Sends a close frame with `code`, e.g. policy violation when the client
+    /// isn't keeping up with what's sent to it.
+    #[inline]
+    pub(crate) fn close_with(
+        &mut self,
+        tls_stream: &mut rustls::Stream<rustls::ServerConnection, TcpStream>,
+        code: CloseCode,
+        reason: &str,
+    ) -> Result<(), Error> {
+        self.send_with_tls_stream(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string().into(),
+        })), tls_stream)
+    }
+
     #[inline]
     pub fn activate(
         &mut self,
@@ -129,11 +326,15 @@ impl WebSocket {
         return match self.ws_context.read_message(tls_stream) {
             Ok(msg) => {
                 return match msg {
+                    // tungstenite has already checked text is UTF-8, see
+                    // `map_tungstenite_err` for when it isn't.
                     Message::Text(_)
                     | Message::Binary(_)
                     | Message::Pong(_) => {
+                        let topic = extract_topic(&msg);
                         self._broadcast_msg_to_subscribers(
                             self.context.as_ref().unwrap().clone(),
+                            topic,
                             Arc::new(msg)
                         )
                     }
@@ -158,18 +359,23 @@ impl WebSocket {
     fn _broadcast_msg_to_subscribers(
         &self,
         ctx: Arc<SgxMutex<Context>>,
+        topic: Option<String>,
         msg: Arc<Message>
     ) -> Result<(), Error> {
         return match self.deferral.lock() {
             Ok(mut deferral) => {
-                for sub in self.subscriptions.iter() {
-                    let sub = Arc::clone(sub);
+                // Messages with a topic only go to that topic's subscribers,
+                // the rest go to the plain (topic-less) subscribers.
+                for sub in self.subscriptions.values()
+                    .filter(|sub| sub.topic.eq(&topic)) {
+                    let sub = Arc::clone(&sub.handler);
                     let ctx = Arc::clone(&ctx);
                     let msg = Arc::clone(&msg);
 
                     deferral.spawn(async move {
                         sub(ctx, msg).await
-                    })?;
+                    }).map_err(|err| backpressure_err(
+                        format!("failed to queue subscriber: {}", err)))?;
                 }
 
                 Ok(())
@@ -183,4 +389,38 @@ impl WebSocket {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+struct Subscription {
+    topic: Option<String>,
+    handler: SubscriptionHandler,
+}
+
+#[derive(Deserialize)]
+struct TopicEnvelope {
+    topic: Option<String>,
+}
+
+#[inline]
+fn extract_topic(msg: &Message) -> Option<String> {
+    let data: &[u8] = match msg {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(data) => data.as_slice(),
+        _ => return None,
+    };
+    if !data.starts_with(b"{") {
+        return None;
+    }
+
+    serde_json::from_slice::<TopicEnvelope>(data).ok()?.topic
+}
+
+#[inline]
+fn ws_limit_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::WSLimit, message)
+}
+
+#[inline]
+fn backpressure_err(message: String) -> Error {
+    Error::new_with_kind(ErrorKind::WSBackpressure, message)
+}
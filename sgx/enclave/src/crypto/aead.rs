@@ -0,0 +1,138 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use sgx_trts::trts::rsgx_read_rand;
+use std::sync::SgxMutex;
+
+use crate::crypto::{load_or_generate_secret, SECRET_LEN};
+use crate::crypto::nonce::{NONCE_LEN, NonceSequence};
+
+/// Bytes added to every value, the version, salt and nonce prefix and the
+/// Poly1305 tag.
+pub(crate) const AEAD_OVERHEAD: usize = 1 + SALT_LEN + NONCE_LEN + TAG_LEN;
+
+// Leads values sealed under a per-boot subkey. Values written before it
+// start with their nonce, whose first byte was always 0.
+const VERSION_SUBKEY: u8 = 1;
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+static SUBKEY_INFO: &[u8] = b"index data subkey";
+
+static DATA_KEY_PATH: &str = "./data.key";
+
+lazy_static! {
+    static ref DATA_SECRET: Result<[u8; SECRET_LEN], String> =
+        load_or_generate_secret(DATA_KEY_PATH);
+    static ref BOOT_KEY: Result<SgxMutex<BootKey>, String> = BootKey::generate().map(SgxMutex::new);
+}
+
+/// Subkey values are sealed with until the enclave restarts. Its nonces are
+/// only counted in memory, the random salt makes the subkey (and so every
+/// key/nonce pair) new each boot whatever the host does to files on disk.
+struct BootKey {
+    salt: [u8; SALT_LEN],
+    key: LessSafeKey,
+    nonces: NonceSequence,
+}
+
+impl BootKey {
+    fn generate() -> Result<Self, String> {
+        let mut salt = [0u8; SALT_LEN];
+        rsgx_read_rand(&mut salt)
+            .map_err(|e| format!("failed to generate data key salt: {:?}", e))?;
+
+        Ok(Self {
+            salt,
+            key: derive_subkey(&salt)?,
+            nonces: NonceSequence::new(),
+        })
+    }
+}
+
+/// Encrypts a value for storage, `aad` (the stored key) binds the value to
+/// where it's stored. Output is `version || salt || nonce || ciphertext || tag`.
+pub(crate) fn encrypt(aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let boot_key = BOOT_KEY.as_ref().map_err(|e| e.clone())?;
+    let mut boot_key = match boot_key.lock() {
+        Ok(boot_key) => boot_key,
+        Err(err) => {
+            warn!("failed to acquire lock on 'boot_key' during aead->encrypt: {:?}", err);
+            return Err("data key unavailable".to_string());
+        }
+    };
+    let nonce = boot_key.nonces.advance()?;
+
+    let mut out = Vec::with_capacity(plaintext.len() + AEAD_OVERHEAD);
+    out.push(VERSION_SUBKEY);
+    out.extend_from_slice(&boot_key.salt);
+    out.extend_from_slice(&nonce);
+
+    let mut buffer = plaintext.to_vec();
+    boot_key.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce),
+                                          Aad::from(aad), &mut buffer)
+        .map_err(|_| "failed to encrypt value".to_string())?;
+    out.extend_from_slice(buffer.as_slice());
+
+    Ok(out)
+}
+
+/// Reverses `encrypt`, fails if the value was tampered with or moved.
+pub(crate) fn decrypt(aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    match data.first() {
+        Some(&VERSION_SUBKEY) => {
+            if data.len() < AEAD_OVERHEAD {
+                return Err(format!("encrypted value too short ({} bytes)", data.len()));
+            }
+            let (salt, sealed) = data[1..].split_at(SALT_LEN);
+
+            open(&derive_subkey(salt)?, aad, sealed)
+        }
+        _ => {
+            if data.len() < NONCE_LEN + TAG_LEN {
+                return Err(format!("encrypted value too short ({} bytes)", data.len()));
+            }
+
+            open(&legacy_key()?, aad, data)
+        }
+    }
+}
+
+// private
+
+/// Opens `nonce || ciphertext || tag`.
+fn open(key: &LessSafeKey, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| "invalid nonce on encrypted value".to_string())?;
+
+    let mut buffer = ciphertext.to_vec();
+    let len = key.open_in_place(nonce, Aad::from(aad), &mut buffer)
+        .map_err(|_| "failed to decrypt value".to_string())?
+        .len();
+    buffer.truncate(len);
+
+    Ok(buffer)
+}
+
+/// HKDF-SHA256 of the sealed data secret under `salt`.
+fn derive_subkey(salt: &[u8]) -> Result<LessSafeKey, String> {
+    let secret = DATA_SECRET.as_ref().map_err(|e| e.clone())?;
+    let okm = hkdf::Salt::new(hkdf::HKDF_SHA256, salt)
+        .extract(secret)
+        .expand(&[SUBKEY_INFO], &CHACHA20_POLY1305)
+        .map_err(|_| "failed to derive data key".to_string())?;
+
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+/// Key values written before per-boot subkeys were sealed with, only ever
+/// used to open them.
+fn legacy_key() -> Result<LessSafeKey, String> {
+    let secret = DATA_SECRET.as_ref().map_err(|e| e.clone())?;
+    let unbound = UnboundKey::new(&CHACHA20_POLY1305, secret)
+        .map_err(|_| "failed to make data key".to_string())?;
+
+    Ok(LessSafeKey::new(unbound))
+}
@@ -0,0 +1,43 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ring::digest::{digest, SHA256};
+use sgx_types::*;
+
+/// Longest nonce accepted. It's hashed into `report_data` so any length would
+/// fit, this only bounds the work per request.
+pub(crate) const MAX_NONCE_LEN: usize = 1024;
+
+/// Creates an SGX report with SHA-256 of `nonce` (zero padded) as its
+/// `report_data`, so the report can't be replayed for another challenge. A
+/// verifier checks the first 32 bytes against its own hash of the nonce.
+///
+/// NOTE: the report is targeted at `target_info`, turning it into a quote a
+/// remote party can verify needs the quoting enclave's target info and an
+/// ocall to have the host fetch the quote, neither is wired up yet.
+pub(crate) fn create_report(nonce: &[u8], target_info: &sgx_target_info_t) -> Result<Vec<u8>, String> {
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(format!("nonce must be 1-{} bytes, got: {}", MAX_NONCE_LEN, nonce.len()));
+    }
+
+    let hash = digest(&SHA256, nonce);
+    let mut report_data = sgx_report_data_t::default();
+    report_data.d[..hash.as_ref().len()].copy_from_slice(hash.as_ref());
+
+    let mut report = sgx_report_t::default();
+    let result = unsafe {
+        sgx_create_report(target_info as *const _,
+                          &report_data as *const _,
+                          &mut report as *mut _)
+    };
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(format!("sgx_create_report failed: {}", result));
+    }
+
+    let bytes = unsafe {
+        core::slice::from_raw_parts(&report as *const _ as *const u8,
+                                    core::mem::size_of::<sgx_report_t>())
+    };
+
+    Ok(bytes.to_vec())
+}
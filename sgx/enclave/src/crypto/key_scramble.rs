@@ -0,0 +1,192 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use blake2::VarBlake2b;
+use blake2::digest::{Input, VariableOutput};
+use std::collections::HashMap;
+use std::sync::SgxMutex;
+
+use crate::crypto::{load_or_generate_secret, SECRET_LEN};
+
+pub(crate) const SCRAMBLE_KEY_LEN: usize = SECRET_LEN;
+pub(crate) const SCRAMBLED_LEN: usize = 32;
+pub(crate) const SCRAMBLED_TAG_LEN: usize = 8;
+
+/// Sealed (SGX protected fs) so the secret survives restarts, without it
+/// every stored key would become unreachable.
+static SCRAMBLE_KEY_PATH: &str = "./scramble.key";
+
+// Scrambled forms of recently used keys, 0 turns the cache off.
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+lazy_static! {
+    static ref SCRAMBLE_KEY: Result<ScrambleKey, String> =
+        ScrambleKey::load_or_generate(SCRAMBLE_KEY_PATH);
+
+    static ref SCRAMBLE_CACHE: SgxMutex<ScrambleCache> =
+        SgxMutex::new(ScrambleCache::new(DEFAULT_CACHE_CAPACITY));
+}
+
+/// Secret used to key the BLAKE2b hash that scrambles index keys.
+pub(crate) struct ScrambleKey {
+    secret: [u8; SCRAMBLE_KEY_LEN],
+}
+
+impl ScrambleKey {
+    #[inline]
+    pub(crate) fn new(secret: [u8; SCRAMBLE_KEY_LEN]) -> Self {
+        Self { secret }
+    }
+
+    /// Unseals the secret at `path`, see `load_or_generate_secret`.
+    pub(crate) fn load_or_generate(path: &str) -> Result<Self, String> {
+        Ok(Self::new(load_or_generate_secret(path)?))
+    }
+
+    /// Keyed BLAKE2b of `key`, the same key and secret always give the same
+    /// output.
+    pub(crate) fn scramble(&self, key: &[u8]) -> [u8; SCRAMBLED_LEN] {
+        let mut hash = VarBlake2b::new_keyed(&self.secret, SCRAMBLED_LEN);
+        hash.input(key);
+
+        let mut out = [0u8; SCRAMBLED_LEN];
+        hash.variable_result(|res| out.copy_from_slice(res));
+
+        out
+    }
+
+    /// Short keyed BLAKE2b of a namespace `tag`. The output length is one of
+    /// BLAKE2b's parameters, so this never matches the start of a `scramble`
+    /// of the same bytes.
+    pub(crate) fn scramble_tag(&self, tag: &[u8]) -> [u8; SCRAMBLED_TAG_LEN] {
+        let mut hash = VarBlake2b::new_keyed(&self.secret, SCRAMBLED_TAG_LEN);
+        hash.input(tag);
+
+        let mut out = [0u8; SCRAMBLED_TAG_LEN];
+        hash.variable_result(|res| out.copy_from_slice(res));
+
+        out
+    }
+}
+
+/// Scrambles `key` with the enclave's secret, this is the canonical form of
+/// a key as it's stored in the host database. Hot keys are served from
+/// `ScrambleCache` rather than hashed again.
+pub(crate) fn scramble(key: &[u8]) -> Result<[u8; SCRAMBLED_LEN], String> {
+    let secret = match SCRAMBLE_KEY.as_ref() {
+        Ok(secret) => secret,
+        Err(e) => return Err(e.clone())
+    };
+
+    let mut cache = match SCRAMBLE_CACHE.lock() {
+        Ok(cache) => cache,
+        Err(err) => {
+            warn!("failed to acquire lock on 'SCRAMBLE_CACHE' during scramble: {:?}", err);
+            return Ok(secret.scramble(key));
+        }
+    };
+
+    if let Some(scrambled) = cache.get(key) {
+        return Ok(scrambled);
+    }
+
+    let scrambled = secret.scramble(key);
+    cache.insert(key, scrambled);
+
+    Ok(scrambled)
+}
+
+/// Scrambles a namespace `tag` with the enclave's secret, the prefix of every
+/// key stored in that namespace.
+pub(crate) fn scramble_tag(tag: &[u8]) -> Result<[u8; SCRAMBLED_TAG_LEN], String> {
+    match SCRAMBLE_KEY.as_ref() {
+        Ok(secret) => Ok(secret.scramble_tag(tag)),
+        Err(e) => Err(e.clone())
+    }
+}
+
+/// Number of keys `scramble` remembers, 0 turns the cache off. Shrinking
+/// evicts the least recently used straight away.
+#[allow(dead_code)]
+pub(crate) fn set_scramble_cache_capacity(capacity: usize) {
+    match SCRAMBLE_CACHE.lock() {
+        Ok(mut cache) => cache.set_capacity(capacity),
+        Err(err) => {
+            warn!("failed to acquire lock on 'SCRAMBLE_CACHE' during set_scramble_cache_capacity: {:?}", err);
+        }
+    }
+}
+
+/// Plain key to scrambled key, evicting the least recently used once
+/// `capacity` is reached.
+struct ScrambleCache {
+    capacity: usize,
+    // Key to its scrambled form and when it was last used.
+    entries: HashMap<Vec<u8>, ([u8; SCRAMBLED_LEN], u64)>,
+    // Last used to key, the first entry is the next evicted.
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl ScrambleCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &[u8]) -> Option<[u8; SCRAMBLED_LEN]> {
+        let tick = self.next_tick();
+        let (scrambled, last_used) = self.entries.get_mut(key)?;
+
+        if let Some(key) = self.recency.remove(&*last_used) {
+            self.recency.insert(tick, key);
+        }
+        *last_used = tick;
+
+        Some(*scrambled)
+    }
+
+    fn insert(&mut self, key: &[u8], scrambled: [u8; SCRAMBLED_LEN]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.to_vec(), (scrambled, tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(tick, key.to_vec());
+
+        self.evict();
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    // private
+
+    #[inline]
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use alloc::string::String;
+
+use sgx_trts::trts::rsgx_read_rand;
+use std::io::{Read, Write};
+use std::sgxfs::SgxFile;
+
+use crate::external::db::db_is_new;
+
+pub(crate) mod aead;
+pub(crate) mod attest;
+pub(crate) mod key_scramble;
+pub(crate) mod nonce;
+
+pub(crate) const SECRET_LEN: usize = 32;
+
+/// Unseals the secret at `path` (SGX protected fs). A new random one is only
+/// created and sealed when the file is missing and the db is new, a missing
+/// secret for existing data is an error rather than a silent data loss.
+pub(crate) fn load_or_generate_secret(path: &str) -> Result<[u8; SECRET_LEN], String> {
+    let mut secret = [0u8; SECRET_LEN];
+
+    match SgxFile::open(path) {
+        Ok(mut file) => {
+            file.read_exact(&mut secret)
+                .map_err(|e| format!("failed to read secret {:?}: {:?}", path, e))?;
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if !db_is_new()? {
+                return Err(format!("secret {:?} is missing but the db already holds data", path));
+            }
+
+            rsgx_read_rand(&mut secret)
+                .map_err(|e| format!("failed to generate secret {:?}: {:?}", path, e))?;
+
+            let mut file = SgxFile::create(path)
+                .map_err(|e| format!("failed to create secret {:?}: {:?}", path, e))?;
+            file.write_all(&secret)
+                .and_then(|_| file.flush())
+                .map_err(|e| format!("failed to write secret {:?}: {:?}", path, e))?;
+        }
+        Err(e) => {
+            return Err(format!("failed to open secret {:?}: {:?}", path, e));
+        }
+    }
+
+    Ok(secret)
+}
@@ -0,0 +1,32 @@
+use alloc::string::{String, ToString};
+
+pub(crate) const NONCE_LEN: usize = 12;
+
+/// Counter based 96 bit nonces, unique for as long as the sequence lives.
+///
+/// Nothing is persisted, so a sequence must only ever be used with a key of
+/// its own (see `aead`, which derives a fresh subkey every boot). A persisted
+/// counter would be in the host's hands, which could delete or roll it back
+/// and force nonce reuse.
+pub(crate) struct NonceSequence {
+    next: u64,
+}
+
+impl NonceSequence {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self { next: 0 }
+    }
+
+    /// Next nonce, refusing rather than wrapping once the counter runs out.
+    pub(crate) fn advance(&mut self) -> Result<[u8; NONCE_LEN], String> {
+        let next = self.next;
+        self.next = next.checked_add(1)
+            .ok_or_else(|| "nonce sequence exhausted".to_string())?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[4..].copy_from_slice(&next.to_be_bytes());
+
+        Ok(nonce)
+    }
+}
@@ -1,19 +1,98 @@
 use sgx_types::*;
 use alloc::string::ToString;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 use std::string::String;
 use std::vec::Vec;
 
+use crate::crypto::aead::{AEAD_OVERHEAD, decrypt, encrypt};
+use crate::crypto::key_scramble::{scramble, scramble_tag, SCRAMBLED_LEN, SCRAMBLED_TAG_LEN};
 use crate::enclave_ffi_types::{EnclaveBuffer, OcallReturn};
-use crate::external::ecall::allocate::recover_buffer;
-use crate::external::ocall::db::{ocall_db_flush, ocall_db_get, ocall_db_get_fixed, ocall_db_put};
+use crate::external::ecall::allocate::{ALLOCATE_STACK_KEEP, drain_stale, recover_buffer};
+use crate::external::ocall::db::{ocall_db_cas, ocall_db_count_prefix, ocall_db_delete, ocall_db_flush, ocall_db_get, ocall_db_get_at, ocall_db_get_cancel, ocall_db_get_fixed, ocall_db_get_poll, ocall_db_get_submit, ocall_db_multi_get, ocall_db_put, ocall_db_put_sync, ocall_db_put_ttl, ocall_db_release_snapshot, ocall_db_scan_prefix, ocall_db_snapshot};
 
+// Largest plain value read or written, above it reads fail rather than have
+// the host allocate (possibly corrupt or hostile) values inside the enclave.
+const DEFAULT_MAX_VALUE_BYTES: usize = 1024 * 1024;
+// Values up to this are read straight into an enclave buffer.
+const DB_GET_FIXED_BYTES: usize = 4 * 1024;
+// Keys read by one `db_multi_get`.
+const DB_MULTI_GET_MAX_KEYS: usize = 1024;
+// Largest buffer one `db_multi_get` accepts from the host, it's allocated on
+// the enclave heap in one go.
+const DB_MULTI_GET_MAX_BYTES: usize = 16 * 1024 * 1024;
+// Entries read by one `ocall_db_scan_prefix`.
+const DB_SCAN_PAGE_ENTRIES: usize = 256;
+// Largest buffer one `ocall_db_scan_prefix` page may take, allocated on the
+// enclave heap as with `db_multi_get`.
+const DB_SCAN_PAGE_MAX_BYTES: usize = 16 * 1024 * 1024;
+// Kept by the host in the default column family, see `SCHEMA_VERSION_KEY`
+// in the app's db module.
+const SCHEMA_VERSION_KEY: &[u8] = b"__schema_version__";
+
+static MAX_VALUE_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_VALUE_BYTES);
+
+/// Set the largest (plain) value `db_put` accepts and `db_get` returns.
 #[allow(dead_code)]
-fn db_put(key: &[u8], value: &[u8]) -> Result<(), String> {
+pub(crate) fn set_max_value_bytes(max_bytes: usize) {
+    MAX_VALUE_BYTES.store(max_bytes, Ordering::Relaxed);
+}
+
+#[inline]
+fn max_stored_bytes() -> usize {
+    MAX_VALUE_BYTES.load(Ordering::Relaxed).saturating_add(AEAD_OVERHEAD)
+}
+
+#[inline]
+fn too_big_err(max_bytes: usize) -> String {
+    format!("value too big (max: {} bytes)", max_bytes.saturating_sub(AEAD_OVERHEAD))
+}
+
+/// A value read from the host, as stored.
+enum RawValue {
+    Found(Vec<u8>),
+    Missing,
+    TooBig,
+}
+
+/// Keys are scrambled (see `crypto::key_scramble`) and values encrypted (see
+/// `crypto::aead`) before they leave the enclave, callers always pass and get
+/// back plain keys and values.
+pub(crate) fn db_put(key: &[u8], value: &[u8]) -> Result<(), String> {
+    db_put_cf("", key, value)
+}
+
+#[allow(dead_code)]
+fn db_put_cf(cf: &str, key: &[u8], value: &[u8]) -> Result<(), String> {
+    db_put_with(cf, key, value, false)
+}
+
+/// As `db_put`, but only returns once the host has synced the write to disk.
+/// Much slower, for writes the index can't afford to lose on a crash.
+#[allow(dead_code)]
+pub(crate) fn db_put_durable(key: &[u8], value: &[u8]) -> Result<(), String> {
+    db_put_with("", key, value, true)
+}
+
+fn db_put_with(cf: &str, key: &[u8], value: &[u8], durable: bool) -> Result<(), String> {
+    db_put_scrambled(cf, &scramble(key)?, value, durable)
+}
+
+/// As `db_put_with`, `key` must already be scrambled.
+fn db_put_scrambled(cf: &str, key: &[u8], value: &[u8], durable: bool) -> Result<(), String> {
+    let value = encrypt(key, value)?;
+    if value.len() > max_stored_bytes() {
+        return Err(too_big_err(max_stored_bytes()));
+    }
+
     let mut ocall_return = OcallReturn::Success;
 
+    let ocall = if durable { ocall_db_put_sync } else { ocall_db_put };
     let result = unsafe {
-        ocall_db_put(
+        ocall(
             (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
             key.as_ptr(),
             key.len(),
             value.as_ptr(),
@@ -27,13 +106,196 @@ fn db_put(key: &[u8], value: &[u8]) -> Result<(), String> {
     return match ocall_return {
         OcallReturn::Success => Ok(()),
         _ => {
-            return Err(format!("ocall_db_put returned {:?}", ocall_return));
+            return Err(format!("ocall_db_put returned {:?} (durable: {})", ocall_return, durable));
+        }
+    };
+}
+
+/// As `db_put`, the key reading as absent once `ttl` has passed (TTLs under a
+/// millisecond round down to an immediate expiry).
+pub(crate) fn db_put_with_ttl(key: &[u8], value: &[u8], ttl: Duration) -> Result<(), String> {
+    let key = scramble(key)?;
+    let value = encrypt(&key, value)?;
+    if value.len() > max_stored_bytes() {
+        return Err(too_big_err(max_stored_bytes()));
+    }
+
+    let mut ocall_return = OcallReturn::Success;
+
+    let result = unsafe {
+        ocall_db_put_ttl(
+            (&mut ocall_return) as *mut _,
+            key.as_ptr(),
+            key.len(),
+            value.as_ptr(),
+            value.len(),
+            ttl.as_millis() as u64)
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+
+    return match ocall_return {
+        OcallReturn::Success => Ok(()),
+        _ => {
+            return Err(format!("ocall_db_put_ttl returned {:?}", ocall_return));
         }
     };
 }
 
+/// Fails with a "too big" error for values over `set_max_value_bytes`.
+pub(crate) fn db_get(key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    db_get_cf("", key)
+}
+
+/// Values for `keys` in the same order, read in a single ocall rather than one
+/// per key. Each value is limited as `db_get`, at most 1024 keys are read at
+/// once and the values together (as sent) must fit in 16 MiB.
 #[allow(dead_code)]
-fn db_get(key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+pub(crate) fn db_multi_get(keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, String> {
+    if keys.len() > DB_MULTI_GET_MAX_KEYS {
+        return Err(format!("too many keys for db_multi_get: {} (max: {})",
+                           keys.len(), DB_MULTI_GET_MAX_KEYS));
+    }
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut scrambled: Vec<[u8; SCRAMBLED_LEN]> = Vec::with_capacity(keys.len());
+    for key in keys.iter() {
+        scrambled.push(scramble(key)?);
+    }
+    let packed: Vec<u8> = scrambled.concat();
+    let max_bytes = max_stored_bytes();
+
+    let mut ocall_return = OcallReturn::Success;
+    let mut enclave_buffer = std::mem::MaybeUninit::<EnclaveBuffer>::uninit();
+
+    let result = unsafe {
+        ocall_db_multi_get(
+            (&mut ocall_return) as *mut _,
+            enclave_buffer.as_mut_ptr(),
+            packed.as_ptr(),
+            packed.len(),
+            SCRAMBLED_LEN,
+            max_bytes,
+            DB_MULTI_GET_MAX_BYTES,
+        )
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    let buf = match ocall_return {
+        OcallReturn::Success => {
+            unsafe {
+                match recover_buffer(enclave_buffer.assume_init()) {
+                    Ok(v) => Ok(v.unwrap_or_default()),
+                    Err(_err) => Err("Failed to recover enclave buffer")
+                }
+            }?
+        }
+        OcallReturn::TooBig => return Err(multi_get_too_big_err()),
+        _ => {
+            return Err(format!("ocall_db_multi_get returned {:?}", ocall_return));
+        }
+    };
+    // Don't trust the host to have honoured the limit.
+    if buf.len() > DB_MULTI_GET_MAX_BYTES {
+        return Err(multi_get_too_big_err());
+    }
+
+    let values = decode_multi_get(buf.as_slice(), keys.len(), max_bytes)?;
+    let mut plain = Vec::with_capacity(values.len());
+    for (key, value) in scrambled.iter().zip(values.into_iter()) {
+        plain.push(match value {
+            Some(value) => Some(decrypt(key, value)?),
+            None => None,
+        });
+    }
+
+    Ok(plain)
+}
+
+#[inline]
+fn multi_get_too_big_err() -> String {
+    format!("db_multi_get values too big (max: {} bytes per value, {} bytes in total)",
+            MAX_VALUE_BYTES.load(Ordering::Relaxed), DB_MULTI_GET_MAX_BYTES)
+}
+
+/// Splits the host's `ocall_db_multi_get` buffer, which is checked rather than
+/// trusted: exactly `count` entries, none over `max_bytes`.
+fn decode_multi_get(mut buf: &[u8], count: usize, max_bytes: usize) -> Result<Vec<Option<&[u8]>>, String> {
+    let malformed = || "malformed db_multi_get response".to_string();
+
+    let mut values = Vec::with_capacity(count);
+    while values.len() < count {
+        let (present, rest) = buf.split_first().ok_or_else(malformed)?;
+        buf = rest;
+        if *present == 0 {
+            values.push(None);
+            continue;
+        }
+
+        if buf.len() < 4 {
+            return Err(malformed());
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&buf[..4]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > max_bytes {
+            return Err(too_big_err(max_bytes));
+        }
+        if buf.len() < 4 + len {
+            return Err(malformed());
+        }
+
+        values.push(Some(&buf[4..4 + len]));
+        buf = &buf[4 + len..];
+    }
+    if !buf.is_empty() {
+        return Err(malformed());
+    }
+
+    Ok(values)
+}
+
+#[allow(dead_code)]
+fn db_get_cf(cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    db_get_scrambled_cf(cf, &scramble(key)?)
+}
+
+/// As `db_get_cf`, `key` must already be scrambled.
+fn db_get_scrambled_cf(cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    match db_get_raw_cf(cf, key)? {
+        Some(value) => Ok(Some(decrypt(key, value.as_slice())?)),
+        None => Ok(None)
+    }
+}
+
+/// Value exactly as stored, `key` must already be scrambled.
+fn db_get_raw_cf(cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let max_bytes = max_stored_bytes();
+
+    // Most values are small, try the fixed buffer before asking the host to
+    // allocate one sized to the value.
+    let fixed_bytes = DB_GET_FIXED_BYTES.min(max_bytes);
+    match db_get_fixed_raw_cf(cf, key, fixed_bytes)? {
+        RawValue::Found(value) => return Ok(Some(value)),
+        RawValue::Missing => return Ok(None),
+        RawValue::TooBig if fixed_bytes >= max_bytes => return Err(too_big_err(max_bytes)),
+        RawValue::TooBig => {}
+    }
+
+    match db_get_alloc_raw_cf(cf, key, max_bytes)? {
+        RawValue::Found(value) => Ok(Some(value)),
+        RawValue::Missing => Ok(None),
+        RawValue::TooBig => Err(too_big_err(max_bytes)),
+    }
+}
+
+fn db_get_alloc_raw_cf(cf: &str, key: &[u8], max_bytes: usize) -> Result<RawValue, String> {
     let mut ocall_return = OcallReturn::Success;
 
     let mut enclave_buffer = std::mem::MaybeUninit::<EnclaveBuffer>::uninit();
@@ -42,8 +304,11 @@ fn db_get(key: &[u8]) -> Result<Option<Vec<u8>>, String> {
         ocall_db_get(
             (&mut ocall_return) as *mut _,
             enclave_buffer.as_mut_ptr(),
+            cf.as_ptr(),
+            cf.len(),
             key.as_ptr(),
             key.len(),
+            max_bytes,
         )
     };
 
@@ -61,17 +326,273 @@ fn db_get(key: &[u8]) -> Result<Option<Vec<u8>>, String> {
                 }
             }?;
 
-            Ok(value)
+            match value {
+                // Don't trust the host to have honoured the limit.
+                Some(value) if value.len() > max_bytes => Ok(RawValue::TooBig),
+                Some(value) => Ok(RawValue::Found(value)),
+                None => Ok(RawValue::Missing),
+            }
         }
-        OcallReturn::None => Ok(None),
+        OcallReturn::None => Ok(RawValue::Missing),
+        OcallReturn::TooBig => Ok(RawValue::TooBig),
         _ => {
             return Err(format!("ocall_db_get returned {:?}", ocall_return));
         }
     };
 }
 
+/// A `db_get` running on the host's offload pool, see `db_get_submit`.
+pub(crate) struct DbGetTicket {
+    ticket: u64,
+    key: [u8; SCRAMBLED_LEN],
+    done: bool,
+}
+
+impl DbGetTicket {
+    /// `None` while the host is still reading, the value (as `db_get`) once
+    /// it's done. A ticket can't be polled again after that.
+    pub(crate) fn poll(&mut self) -> Result<Option<Option<Vec<u8>>>, String> {
+        if self.done {
+            return Err("db_get ticket already completed".to_string());
+        }
+
+        let max_bytes = max_stored_bytes();
+        let mut ocall_return = OcallReturn::Success;
+        let mut enclave_buffer = std::mem::MaybeUninit::<EnclaveBuffer>::uninit();
+
+        let result = unsafe {
+            ocall_db_get_poll(
+                (&mut ocall_return) as *mut _,
+                enclave_buffer.as_mut_ptr(),
+                self.ticket,
+                max_bytes,
+            )
+        };
+
+        if result != sgx_status_t::SGX_SUCCESS {
+            return Err(result.to_string());
+        }
+        if let OcallReturn::Pending = ocall_return {
+            return Ok(None);
+        }
+        // The host forgets the ticket once it has answered.
+        self.done = true;
+
+        return match ocall_return {
+            OcallReturn::Success => {
+                let value = unsafe {
+                    match recover_buffer(enclave_buffer.assume_init()) {
+                        Ok(v) => Ok(v),
+                        Err(_err) => Err("Failed to recover enclave buffer")
+                    }
+                }?;
+
+                match value {
+                    // Don't trust the host to have honoured the limit.
+                    Some(value) if value.len() > max_bytes => Err(too_big_err(max_bytes)),
+                    Some(value) => Ok(Some(Some(decrypt(&self.key, value.as_slice())?))),
+                    None => Ok(Some(None)),
+                }
+            }
+            OcallReturn::None => Ok(Some(None)),
+            OcallReturn::TooBig => Err(too_big_err(max_bytes)),
+            _ => {
+                return Err(format!("ocall_db_get_poll returned {:?}", ocall_return));
+            }
+        };
+    }
+}
+
+impl Drop for DbGetTicket {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut ocall_return = OcallReturn::Success;
+        let result = unsafe {
+            ocall_db_get_cancel((&mut ocall_return) as *mut _, self.ticket)
+        };
+        if result != sgx_status_t::SGX_SUCCESS {
+            warn!("ocall_db_get_cancel failed: {}", result);
+        }
+    }
+}
+
+/// Hands a `db_get` to the host's offload pool and returns straight away, so
+/// the calling thread isn't blocked on disk. Poll the ticket for the value.
+///
+/// The pool's host threads only read RocksDB, they never enter the enclave
+/// and so take no `EnclaveDoorbell` slots. The value is copied in by the poll
+/// ocall calling back into `ecall_allocate`, a recursive entry from the same
+/// thread, which the doorbell always permits.
+pub(crate) fn db_get_submit(key: &[u8]) -> Result<DbGetTicket, String> {
+    db_get_submit_cf("", key)
+}
+
+fn db_get_submit_cf(cf: &str, key: &[u8]) -> Result<DbGetTicket, String> {
+    let key = scramble(key)?;
+
+    let mut ocall_return = OcallReturn::Success;
+    let mut ticket = 0_u64;
+
+    let result = unsafe {
+        ocall_db_get_submit(
+            (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
+            key.as_ptr(),
+            key.len(),
+            (&mut ticket) as *mut _,
+        )
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    return match ocall_return {
+        OcallReturn::Success => Ok(DbGetTicket { ticket, key, done: false }),
+        _ => {
+            return Err(format!("ocall_db_get_submit returned {:?}", ocall_return));
+        }
+    };
+}
+
+/// A consistent view of the db held by the host, reads through it don't see
+/// writes made after it was taken. Released on `release` (or drop).
+pub(crate) struct DbSnapshot {
+    id: u64,
+    released: bool,
+}
+
+impl DbSnapshot {
+    /// As `db_get`, as of when the snapshot was taken.
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.get_cf("", key)
+    }
+
+    #[allow(dead_code)]
+    fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let key = scramble(key)?;
+        let max_bytes = max_stored_bytes();
+
+        let mut ocall_return = OcallReturn::Success;
+        let mut enclave_buffer = std::mem::MaybeUninit::<EnclaveBuffer>::uninit();
+
+        let result = unsafe {
+            ocall_db_get_at(
+                (&mut ocall_return) as *mut _,
+                enclave_buffer.as_mut_ptr(),
+                self.id,
+                cf.as_ptr(),
+                cf.len(),
+                key.as_ptr(),
+                key.len(),
+                max_bytes,
+            )
+        };
+
+        if result != sgx_status_t::SGX_SUCCESS {
+            return Err(result.to_string());
+        }
+        return match ocall_return {
+            OcallReturn::Success => {
+                let value = unsafe {
+                    match recover_buffer(enclave_buffer.assume_init()) {
+                        Ok(v) => Ok(v),
+                        Err(_err) => Err("Failed to recover enclave buffer")
+                    }
+                }?;
+
+                match value {
+                    // Don't trust the host to have honoured the limit.
+                    Some(value) if value.len() > max_bytes => Err(too_big_err(max_bytes)),
+                    Some(value) => Ok(Some(decrypt(&key, value.as_slice())?)),
+                    None => Ok(None),
+                }
+            }
+            OcallReturn::None => Ok(None),
+            OcallReturn::TooBig => Err(too_big_err(max_bytes)),
+            _ => {
+                return Err(format!("ocall_db_get_at returned {:?}", ocall_return));
+            }
+        };
+    }
+
+    /// Lets the host drop the snapshot, it pins old data until then.
+    pub(crate) fn release(mut self) -> Result<(), String> {
+        self.released = true;
+
+        let mut ocall_return = OcallReturn::Success;
+        let result = unsafe {
+            ocall_db_release_snapshot((&mut ocall_return) as *mut _, self.id)
+        };
+
+        if result != sgx_status_t::SGX_SUCCESS {
+            return Err(result.to_string());
+        }
+        return match ocall_return {
+            OcallReturn::Success => Ok(()),
+            _ => {
+                return Err(format!("ocall_db_release_snapshot returned {:?}", ocall_return));
+            }
+        };
+    }
+}
+
+impl Drop for DbSnapshot {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+
+        let mut ocall_return = OcallReturn::Success;
+        let result = unsafe {
+            ocall_db_release_snapshot((&mut ocall_return) as *mut _, self.id)
+        };
+        if result != sgx_status_t::SGX_SUCCESS {
+            warn!("ocall_db_release_snapshot failed: {}", result);
+        }
+    }
+}
+
+pub(crate) fn db_snapshot() -> Result<DbSnapshot, String> {
+    let mut ocall_return = OcallReturn::Success;
+    let mut id = 0_u64;
+
+    let result = unsafe {
+        ocall_db_snapshot((&mut ocall_return) as *mut _, (&mut id) as *mut _)
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    return match ocall_return {
+        OcallReturn::Success => Ok(DbSnapshot { id, released: false }),
+        _ => {
+            return Err(format!("ocall_db_snapshot returned {:?}", ocall_return));
+        }
+    };
+}
+
 #[allow(dead_code)]
 fn db_get_fixed(key: &[u8], max_bytes: usize) -> Result<Option<Vec<u8>>, String> {
+    db_get_fixed_cf("", key, max_bytes)
+}
+
+#[allow(dead_code)]
+fn db_get_fixed_cf(cf: &str, key: &[u8], max_bytes: usize) -> Result<Option<Vec<u8>>, String> {
+    let key = scramble(key)?;
+    let max_bytes = max_bytes + AEAD_OVERHEAD;
+
+    match db_get_fixed_raw_cf(cf, &key, max_bytes)? {
+        RawValue::Found(value) => Ok(Some(decrypt(&key, value.as_slice())?)),
+        RawValue::Missing => Ok(None),
+        RawValue::TooBig => Err(too_big_err(max_bytes)),
+    }
+}
+
+fn db_get_fixed_raw_cf(cf: &str, key: &[u8], max_bytes: usize) -> Result<RawValue, String> {
     let mut ocall_return = OcallReturn::Success;
     let mut value = vec![0; max_bytes];
     let mut value_len = 0 as usize;
@@ -79,6 +600,8 @@ fn db_get_fixed(key: &[u8], max_bytes: usize) -> Result<Option<Vec<u8>>, String>
     let result = unsafe {
         ocall_db_get_fixed(
             (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
             key.as_ptr(),
             key.len(),
             value.as_mut_ptr(),
@@ -92,17 +615,289 @@ fn db_get_fixed(key: &[u8], max_bytes: usize) -> Result<Option<Vec<u8>>, String>
     }
     return match ocall_return {
         OcallReturn::Success => {
+            if value_len > max_bytes {
+                return Err(format!("ocall_db_get_fixed returned an invalid length: {}", value_len));
+            }
             value.truncate(value_len);
 
-            Ok(Some(value))
+            Ok(RawValue::Found(value))
         }
-        OcallReturn::None => Ok(None),
+        OcallReturn::None => Ok(RawValue::Missing),
+        OcallReturn::TooBig => Ok(RawValue::TooBig),
         _ => {
             return Err(format!("ocall_db_get_fixed returned {:?}", ocall_return));
         }
     };
 }
 
+pub(crate) fn db_delete(key: &[u8]) -> Result<(), String> {
+    db_delete_cf("", key)
+}
+
+#[allow(dead_code)]
+fn db_delete_cf(cf: &str, key: &[u8]) -> Result<(), String> {
+    db_delete_scrambled_cf(cf, &scramble(key)?)
+}
+
+/// As `db_delete_cf`, `key` must already be scrambled.
+fn db_delete_scrambled_cf(cf: &str, key: &[u8]) -> Result<(), String> {
+    let mut ocall_return = OcallReturn::Success;
+
+    let result = unsafe {
+        ocall_db_delete(
+            (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
+            key.as_ptr(),
+            key.len(),
+        )
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    return match ocall_return {
+        OcallReturn::Success => Ok(()),
+        _ => {
+            return Err(format!("ocall_db_delete returned {:?}", ocall_return));
+        }
+    };
+}
+
+#[allow(dead_code)]
+fn db_cas(key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<bool, String> {
+    db_cas_cf("", key, expected, value)
+}
+
+#[allow(dead_code)]
+fn db_cas_cf(cf: &str, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<bool, String> {
+    let key = scramble(key)?;
+
+    // Every write uses a fresh nonce, so the host can't compare plaintexts.
+    // Check `expected` here and swap against the ciphertext actually stored.
+    let current = db_get_raw_cf(cf, &key)?;
+    let matches = match (current.as_ref(), expected) {
+        (Some(current), Some(expected)) => decrypt(&key, current.as_slice())?.as_slice().eq(expected),
+        (None, None) => true,
+        _ => false,
+    };
+    if !matches {
+        return Ok(false);
+    }
+
+    let value = encrypt(&key, value)?;
+    db_cas_raw(cf, &key, current.as_deref(), value.as_slice())
+}
+
+/// Host side compare-and-swap on stored bytes, `key` must already be scrambled.
+#[allow(dead_code)]
+fn db_cas_raw(cf: &str, key: &[u8], expected: Option<&[u8]>, value: &[u8]) -> Result<bool, String> {
+    let mut ocall_return = OcallReturn::Success;
+    let mut swapped = 0_u8;
+
+    let (expected_ptr, expected_len, expected_exists) = match expected {
+        Some(expected) => (expected.as_ptr(), expected.len(), 1_u8),
+        None => (core::ptr::null(), 0, 0_u8),
+    };
+
+    let result = unsafe {
+        ocall_db_cas(
+            (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
+            key.as_ptr(),
+            key.len(),
+            expected_ptr,
+            expected_len,
+            expected_exists,
+            value.as_ptr(),
+            value.len(),
+            (&mut swapped) as *mut _,
+        )
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    return match ocall_return {
+        OcallReturn::Success => Ok(swapped != 0),
+        _ => {
+            return Err(format!("ocall_db_cas returned {:?}", ocall_return));
+        }
+    };
+}
+
+/// Number of keys stored through `db_put` (and friends), counted by the host
+/// without fetching values. The host's schema marker isn't counted, but keys
+/// put with a TTL are until a read removes them, even once expired. See
+/// `db_count_prefix` to count a single namespace.
+pub(crate) fn db_count() -> Result<u64, String> {
+    let count = db_count_prefix_cf("", b"")?;
+    if count > 0 && db_get_raw_cf("", SCHEMA_VERSION_KEY)?.is_some() {
+        return Ok(count - 1);
+    }
+
+    Ok(count)
+}
+
+/// Number of keys stored in `ns`, counted by the host on the namespace's
+/// (scrambled) prefix without fetching values.
+#[allow(dead_code)]
+pub(crate) fn db_count_prefix(ns: &Namespace) -> Result<u64, String> {
+    db_count_prefix_cf("", &ns.prefix)
+}
+
+/// Keys starting with `prefix` as stored, i.e. already scrambled.
+fn db_count_prefix_cf(cf: &str, prefix: &[u8]) -> Result<u64, String> {
+    let mut ocall_return = OcallReturn::Success;
+    let mut count = 0_u64;
+
+    let result = unsafe {
+        ocall_db_count_prefix(
+            (&mut ocall_return) as *mut _,
+            cf.as_ptr(),
+            cf.len(),
+            prefix.as_ptr(),
+            prefix.len(),
+            (&mut count) as *mut _,
+        )
+    };
+
+    if result != sgx_status_t::SGX_SUCCESS {
+        return Err(result.to_string());
+    }
+    return match ocall_return {
+        OcallReturn::Success => Ok(count),
+        _ => {
+            return Err(format!("ocall_db_count_prefix returned {:?}", ocall_return));
+        }
+    };
+}
+
+/// Calls `f` with every default column family entry (key and value as
+/// stored) whose stored key starts with `prefix`, in key order. Read from the
+/// host a page at a time.
+fn db_scan_prefix_raw<F>(prefix: &[u8], mut f: F) -> Result<(), String>
+    where F: FnMut(&[u8], &[u8]) -> Result<(), String>
+{
+    let max_bytes = max_stored_bytes();
+    let mut from = prefix.to_vec();
+
+    loop {
+        let mut ocall_return = OcallReturn::Success;
+        let mut enclave_buffer = std::mem::MaybeUninit::<EnclaveBuffer>::uninit();
+
+        let result = unsafe {
+            ocall_db_scan_prefix(
+                (&mut ocall_return) as *mut _,
+                enclave_buffer.as_mut_ptr(),
+                prefix.as_ptr(),
+                prefix.len(),
+                from.as_ptr(),
+                from.len(),
+                DB_SCAN_PAGE_ENTRIES,
+                max_bytes,
+                DB_SCAN_PAGE_MAX_BYTES,
+            )
+        };
+
+        if result != sgx_status_t::SGX_SUCCESS {
+            return Err(result.to_string());
+        }
+        let buf = match ocall_return {
+            OcallReturn::Success => {
+                unsafe {
+                    match recover_buffer(enclave_buffer.assume_init()) {
+                        Ok(v) => Ok(v.unwrap_or_default()),
+                        Err(_err) => Err("Failed to recover enclave buffer")
+                    }
+                }?
+            }
+            OcallReturn::TooBig => return Err(too_big_err(max_bytes)),
+            _ => {
+                return Err(format!("ocall_db_scan_prefix returned {:?}", ocall_return));
+            }
+        };
+        // Don't trust the host to have honoured the limit.
+        if buf.len() > DB_SCAN_PAGE_MAX_BYTES {
+            return Err(too_big_err(DB_SCAN_PAGE_MAX_BYTES));
+        }
+
+        let (more, entries) = decode_scan(buf.as_slice(), prefix, from.as_slice(), max_bytes)?;
+        for (key, value) in entries.iter() {
+            f(key, value)?;
+        }
+
+        match entries.last() {
+            // Carry on just past the last key seen.
+            Some((last, _)) if more => {
+                from = last.to_vec();
+                from.push(0);
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Splits an `ocall_db_scan_prefix` page, which is checked rather than
+/// trusted: every key starts with `prefix`, comes after the previous one (and
+/// not before `from`), and no value is over `max_bytes`.
+fn decode_scan<'a>(buf: &'a [u8], prefix: &[u8], from: &[u8], max_bytes: usize)
+                   -> Result<(bool, Vec<(&'a [u8], &'a [u8])>), String> {
+    let malformed = || "malformed db scan response".to_string();
+
+    let (more, mut buf) = buf.split_first().ok_or_else(malformed)?;
+    let mut entries: Vec<(&[u8], &[u8])> = Vec::new();
+    while !buf.is_empty() {
+        let (key, rest) = split_len_prefixed(buf, usize::MAX).ok_or_else(malformed)?;
+        let (value, rest) = split_len_prefixed(rest, max_bytes).ok_or_else(malformed)?;
+        buf = rest;
+
+        let after = match entries.last() {
+            Some((last, _)) => key > *last,
+            None => key >= from,
+        };
+        if !key.starts_with(prefix) || !after {
+            return Err(malformed());
+        }
+
+        entries.push((key, value));
+    }
+    if entries.len() > DB_SCAN_PAGE_ENTRIES {
+        return Err(malformed());
+    }
+
+    Ok((*more != 0, entries))
+}
+
+/// Splits a u32 big endian length and that many bytes (at most `max_len`)
+/// off the front of `buf`.
+#[inline]
+fn split_len_prefixed(buf: &[u8], max_len: usize) -> Option<(&[u8], &[u8])> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&buf[..4]);
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len || buf.len() - 4 < len {
+        return None;
+    }
+
+    Some((&buf[4..4 + len], &buf[4 + len..]))
+}
+
+/// Whether nothing has been stored yet, the host's schema marker (written
+/// unscrambled when it creates the database) being the only key.
+pub(crate) fn db_is_new() -> Result<bool, String> {
+    Ok(db_count()? == 0)
+}
+
+/// Round trip to the host database, for health checks.
+pub(crate) fn db_ping() -> Result<(), String> {
+    db_get_fixed(b"__healthz", 0).map(|_| ())
+}
+
 #[allow(dead_code)]
 fn db_flush() -> Result<(), String> {
     let mut ocall_return = OcallReturn::Success;
@@ -116,10 +911,99 @@ fn db_flush() -> Result<(), String> {
     if result != sgx_status_t::SGX_SUCCESS {
         return Err(result.to_string());
     }
+    // Good time to release any buffers an ocall failed to hand back.
+    unsafe { drain_stale(ALLOCATE_STACK_KEEP) };
+
     return match ocall_return {
         OcallReturn::Success => Ok(()),
         _ => {
             return Err(format!("ocall_db_flush returned {:?}", ocall_return));
         }
     };
-}
\ No newline at end of file
+}
+/// Keeps keys for different tenants or data types apart in the one keyspace.
+///
+/// Keys are stored as the scrambled tag (see `scramble_tag`) followed by the
+/// scrambled key, so the same logical key in two namespaces is stored under
+/// unrelated keys. The host can tell which keys share a namespace, but never
+/// sees the tag or the keys themselves. The plain key is kept alongside the
+/// value (both encrypted) so `scan_prefix` can hand it back.
+#[allow(dead_code)]
+pub(crate) struct Namespace {
+    prefix: [u8; SCRAMBLED_TAG_LEN],
+}
+
+#[allow(dead_code)]
+impl Namespace {
+    pub(crate) fn new(tag: &[u8]) -> Result<Self, String> {
+        Ok(Self { prefix: scramble_tag(tag)? })
+    }
+
+    /// `key` within this namespace, as stored.
+    pub(crate) fn key(&self, key: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::with_capacity(SCRAMBLED_TAG_LEN + SCRAMBLED_LEN);
+        out.extend_from_slice(&self.prefix);
+        out.extend_from_slice(&scramble(key)?);
+        Ok(out)
+    }
+
+    pub(crate) fn put(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        db_put_scrambled("", self.key(key)?.as_slice(), encode_ns_entry(key, value).as_slice(), false)
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let entry = match db_get_scrambled_cf("", self.key(key)?.as_slice())? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let (stored_key, value) = decode_ns_entry(entry.as_slice())?;
+        if stored_key != key {
+            return Err("namespace entry stored under the wrong key".to_string());
+        }
+
+        Ok(Some(value.to_vec()))
+    }
+
+    pub(crate) fn delete(&self, key: &[u8]) -> Result<(), String> {
+        db_delete_scrambled_cf("", self.key(key)?.as_slice())
+    }
+
+    /// Entries in this namespace whose key starts with `prefix`, in key order.
+    /// The host can only narrow the scan to the namespace, every entry in it
+    /// is read and decrypted to match `prefix`.
+    pub(crate) fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut entries = Vec::new();
+        db_scan_prefix_raw(&self.prefix, |stored_key, stored| {
+            // Keys from `db_put` are shorter, one could (just) share the tag.
+            if stored_key.len() != SCRAMBLED_TAG_LEN + SCRAMBLED_LEN {
+                return Ok(());
+            }
+
+            let entry = decrypt(stored_key, stored)?;
+            let (key, value) = decode_ns_entry(entry.as_slice())?;
+            if key.starts_with(prefix) {
+                entries.push((key.to_vec(), value.to_vec()));
+            }
+            Ok(())
+        })?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(entries)
+    }
+}
+
+/// The key (u32 big endian length first) then the value.
+fn encode_ns_entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(4 + key.len() + value.len());
+    entry.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    entry.extend_from_slice(key);
+    entry.extend_from_slice(value);
+    entry
+}
+
+#[inline]
+fn decode_ns_entry(entry: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    split_len_prefixed(entry, usize::MAX)
+        .ok_or_else(|| "malformed namespace entry".to_string())
+}
@@ -1,4 +1,5 @@
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use lazy_static::lazy_static;
 use log::*;
@@ -16,6 +17,28 @@ lazy_static! {
     static ref ECALL_ALLOCATE_STACK: SgxMutex<Vec<EnclaveBuffer>> = SgxMutex::new(Vec::new());
 }
 
+// Buffers are normally recovered right after the ocall returns, so the stack
+// should never hold much more than one entry per enclave thread.
+const ALLOCATE_STACK_WARN_SIZE: usize = 256;
+// Entries kept by `drain_stale`, must stay well above the number of enclave
+// threads so buffers still in flight are never released.
+pub(crate) const ALLOCATE_STACK_KEEP: usize = 64;
+
+static ALLOCATE_STACK_WARN: AtomicUsize = AtomicUsize::new(ALLOCATE_STACK_WARN_SIZE);
+static ALLOCATE_STACK_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the stack size above which allocations are reported as leaking.
+#[allow(dead_code)]
+pub(crate) fn set_allocate_stack_warn_size(size: usize) {
+    ALLOCATE_STACK_WARN.store(size, Ordering::Relaxed);
+}
+
+/// The largest the allocate stack has been.
+#[allow(dead_code)]
+pub(crate) fn allocate_stack_high_water() -> usize {
+    ALLOCATE_STACK_HIGH_WATER.load(Ordering::Relaxed)
+}
+
 /// # Safety
 /// Always use protection
 #[no_mangle]
@@ -47,10 +70,10 @@ unsafe fn ecall_allocate_impl(buffer: *const u8, length: usize) -> EnclaveBuffer
         let enclave_buffer = EnclaveBuffer {
             ptr: heap_pointer as *mut c_void,
         };
-        ECALL_ALLOCATE_STACK
-            .lock()
-            .unwrap()
-            .push(enclave_buffer.unsafe_clone());
+        let mut alloc_stack = ECALL_ALLOCATE_STACK.lock().unwrap();
+        alloc_stack.push(enclave_buffer.unsafe_clone());
+        track_stack_size(alloc_stack.len());
+
         enclave_buffer
     });
 
@@ -90,10 +113,47 @@ pub unsafe fn recover_buffer(ptr: EnclaveBuffer) -> Result<Option<Vec<u8>>, Buff
         // This index is probably at the end of the stack, but we give it a little more flexibility
         // in case access patterns change in the future
         let index = alloc_stack.len() - index_from_the_end - 1;
-        alloc_stack.swap_remove(index);
+        // Keep the stack in allocation order, `drain_stale` frees from the front.
+        alloc_stack.remove(index);
     } else {
+        warn!("recover_buffer called with an unknown pointer (stack size: {})", alloc_stack.len());
         return Err(BufferRecoveryError);
     }
     let boxed_vector = Box::from_raw(ptr.ptr as *mut Vec<u8>);
     Ok(Some(*boxed_vector))
 }
+
+/// Release all but the `keep` most recent allocations. Anything older than
+/// that was never recovered (e.g. an ocall bailed out after allocating) and
+/// would otherwise leak for the lifetime of the enclave.
+/// # Safety
+/// `keep` must exceed the number of buffers that can be in flight at once.
+pub unsafe fn drain_stale(keep: usize) -> usize {
+    let mut alloc_stack = ECALL_ALLOCATE_STACK.lock().unwrap();
+    if alloc_stack.len() <= keep {
+        return 0;
+    }
+
+    let stale = alloc_stack.len() - keep;
+    for buffer in alloc_stack.drain(..stale) {
+        drop(Box::from_raw(buffer.ptr as *mut Vec<u8>));
+    }
+
+    warn!("released {} stale enclave buffer(s)", stale);
+
+    stale
+}
+
+#[inline]
+fn track_stack_size(len: usize) {
+    let high_water = ALLOCATE_STACK_HIGH_WATER.load(Ordering::Relaxed);
+    if len <= high_water {
+        return;
+    }
+    ALLOCATE_STACK_HIGH_WATER.store(len, Ordering::Relaxed);
+
+    // Only report each new high-water mark, not every allocation.
+    if len > ALLOCATE_STACK_WARN.load(Ordering::Relaxed) {
+        warn!("ECALL_ALLOCATE_STACK has grown to {} entries, buffers are not being recovered", len);
+    }
+}
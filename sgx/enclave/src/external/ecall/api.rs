@@ -1,11 +1,55 @@
+use alloc::vec::Vec;
+
 use sgx_trts::c_str::CStr;
 use sgx_types::*;
 
-use crate::api::server::server::start_api_server;
+use crate::api::server::server::{reload_tls, request_shutdown, start_api_servers};
 
 #[no_mangle]
-pub extern "C" fn ecall_api_server_start(addr: * const c_char) {
+pub extern "C" fn ecall_api_server_start(
+    addr: *const c_char,
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> sgx_status_t {
     let addr = unsafe { CStr::from_ptr(addr).to_str() }.unwrap();
+    let cert_path = unsafe { CStr::from_ptr(cert_path).to_str() }.unwrap();
+    let key_path = unsafe { CStr::from_ptr(key_path).to_str() }.unwrap();
+
+    // Multiple listen addresses may be given comma separated.
+    let addrs: Vec<&str> = addr.split(",")
+        .map(|a| a.trim())
+        .filter(|a| !a.is_empty())
+        .collect();
+
+    match start_api_servers(&addrs, cert_path, key_path) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(err) => {
+            error!("failed to start API server: {}", err);
+            sgx_status_t::SGX_ERROR_UNEXPECTED
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_reload_tls(
+    cert_path: *const c_char,
+    key_path: *const c_char,
+) -> sgx_status_t {
+    let cert_path = unsafe { CStr::from_ptr(cert_path).to_str() }.unwrap();
+    let key_path = unsafe { CStr::from_ptr(key_path).to_str() }.unwrap();
+
+    match reload_tls(cert_path, key_path) {
+        Ok(_) => sgx_status_t::SGX_SUCCESS,
+        Err(err) => {
+            error!("failed to reload TLS config: {}", err);
+            sgx_status_t::SGX_ERROR_UNEXPECTED
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_api_server_shutdown() -> sgx_status_t {
+    request_shutdown();
 
-    start_api_server(addr)
+    sgx_status_t::SGX_SUCCESS
 }
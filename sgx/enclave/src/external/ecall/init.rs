@@ -2,4 +2,6 @@
 pub unsafe extern "C" fn ecall_init() {
     #[cfg(not(feature = "production"))]
     pretty_env_logger::init();
+
+    crate::utils::panic_hook::register_panic_hook();
 }
\ No newline at end of file
@@ -5,12 +5,27 @@ extern "C" {
     pub fn ocall_db_get(
         retval: *mut OcallReturn,
         value: *mut EnclaveBuffer,
+        cf: *const u8,
+        cf_len: usize,
         key: *const u8,
         key_len: usize,
+        value_max_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_multi_get(
+        retval: *mut OcallReturn,
+        values: *mut EnclaveBuffer,
+        keys: *const u8,
+        keys_len: usize,
+        key_len: usize,
+        value_max_len: usize,
+        total_max_len: usize,
     ) -> sgx_status_t;
 
     pub fn ocall_db_get_fixed(
         retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
         key: *const u8,
         key_len: usize,
         value: *mut u8,
@@ -20,16 +35,116 @@ extern "C" {
 
     pub fn ocall_db_delete(
         retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
         key: *const u8,
         key_len: usize,
     ) -> sgx_status_t;
 
     pub fn ocall_db_put(
+        retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
+        key: *const u8,
+        key_len: usize,
+        value: *const u8,
+        value_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_put_sync(
+        retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
+        key: *const u8,
+        key_len: usize,
+        value: *const u8,
+        value_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_put_ttl(
         retval: *mut OcallReturn,
         key: *const u8,
         key_len: usize,
         value: *const u8,
         value_len: usize,
+        ttl_ms: u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_cas(
+        retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
+        key: *const u8,
+        key_len: usize,
+        expected: *const u8,
+        expected_len: usize,
+        expected_exists: u8,
+        value: *const u8,
+        value_len: usize,
+        swapped: *mut u8,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_count_prefix(
+        retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
+        prefix: *const u8,
+        prefix_len: usize,
+        count: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_scan_prefix(
+        retval: *mut OcallReturn,
+        entries: *mut EnclaveBuffer,
+        prefix: *const u8,
+        prefix_len: usize,
+        from: *const u8,
+        from_len: usize,
+        max_entries: usize,
+        value_max_len: usize,
+        total_max_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_get_submit(
+        retval: *mut OcallReturn,
+        cf: *const u8,
+        cf_len: usize,
+        key: *const u8,
+        key_len: usize,
+        ticket: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_get_poll(
+        retval: *mut OcallReturn,
+        value: *mut EnclaveBuffer,
+        ticket: u64,
+        value_max_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_get_cancel(
+        retval: *mut OcallReturn,
+        ticket: u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_snapshot(
+        retval: *mut OcallReturn,
+        snapshot: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_get_at(
+        retval: *mut OcallReturn,
+        value: *mut EnclaveBuffer,
+        snapshot: u64,
+        cf: *const u8,
+        cf_len: usize,
+        key: *const u8,
+        key_len: usize,
+        value_max_len: usize,
+    ) -> sgx_status_t;
+
+    pub fn ocall_db_release_snapshot(
+        retval: *mut OcallReturn,
+        snapshot: u64,
     ) -> sgx_status_t;
 
     pub fn ocall_db_flush(
@@ -47,6 +47,7 @@ extern crate mio;
 extern crate mio_httpc;
 extern crate net2;
 extern crate futures;
+extern crate flate2;
 extern crate http;
 extern crate httparse;
 extern crate httpdate;
@@ -56,9 +57,9 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate bincode;
 
-use blake2::VarBlake2b;
-use blake2::digest::{Input, VariableOutput};
+use blake2::digest::Input;
 use digest::FixedOutput;
 use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey as Key, Nonce, UnboundKey};
 use ring::hkdf;
@@ -66,7 +67,10 @@ use sgx_types::*;
 use std::vec::Vec;
 use uuid::Uuid;
 
+use crate::crypto::key_scramble::ScrambleKey;
+
 mod api;
+mod crypto;
 mod utils;
 pub mod external;
 
@@ -145,16 +149,14 @@ fn scramble_with_hkdf(keys: Vec<[u8; 32]>) {
 
 #[allow(dead_code)]
 fn scramble_with_blake2b(keys: Vec<[u8; 32]>) {
-    let my_key = b"test secret";
+    let mut secret = [0u8; 32];
+    secret[..11].copy_from_slice(b"test secret");
+    let my_key = ScrambleKey::new(secret);
 
     for k in keys.iter() {
-        let mut hash = VarBlake2b::new_keyed(my_key, 32);
-        hash.input(k);
+        let _res = my_key.scramble(k);
 
-        hash.variable_result(|_res| {
-            // TODO:
-            //print_result(k, res);
-        });
+        //print_result(k, &_res[..]);
     }
 }
 
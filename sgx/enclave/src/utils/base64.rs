@@ -0,0 +1,66 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+static BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard (padded) base64.
+pub(crate) fn encode_base64(input: &[u8]) -> String {
+    let mut out = String::with_capacity((input.len() + 2) / 3 * 4);
+    for chunk in input.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Standard (padded) base64, `None` on any invalid input.
+pub(crate) fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let input = input.as_bytes();
+    if input.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let last = i == input.len() / 4 - 1;
+        let mut buf = [0u8; 4];
+        let mut padding = 0;
+
+        for (j, b) in chunk.iter().enumerate() {
+            buf[j] = match *b {
+                b'A'..=b'Z' => b - b'A',
+                b'a'..=b'z' => b - b'a' + 26,
+                b'0'..=b'9' => b - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                // Padding only at the end of the last chunk.
+                b'=' if last && j >= 2 => {
+                    padding += 1;
+                    0
+                }
+                _ => return None,
+            };
+            if padding > 0 && *b != b'=' {
+                return None;
+            }
+        }
+
+        let n = (buf[0] as u32) << 18 | (buf[1] as u32) << 12
+            | (buf[2] as u32) << 6 | buf[3] as u32;
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..3 - padding]);
+    }
+
+    Some(out)
+}
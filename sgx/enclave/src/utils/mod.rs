@@ -1,6 +1,8 @@
+pub mod base64;
 pub mod logger;
 pub mod macros;
 pub mod oom_handler;
+pub mod panic_hook;
 pub mod pointers;
 //pub mod recursion_depth;
 mod results;
\ No newline at end of file
@@ -0,0 +1,32 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use std::panic::{self, PanicInfo};
+
+/// Logs the message and location of every panic before it unwinds.
+///
+/// `catch_unwind` (e.g. `middleware_recovery`) only sees the payload, so this is
+/// the only place the location survives. Outside of production the previous
+/// hook still runs afterwards, which prints a backtrace when enabled.
+pub fn register_panic_hook() {
+    #[cfg(not(feature = "production"))]
+    let prev = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info: &PanicInfo<'_>| {
+        let msg = match info.payload().downcast_ref::<&str>() {
+            Some(s) => *s,
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => s.as_str(),
+                None => "Box<Any>",
+            },
+        };
+
+        match info.location() {
+            Some(location) => error!("panicked at '{}', {}:{}:{}", msg,
+                                     location.file(), location.line(), location.column()),
+            None => error!("panicked at '{}'", msg),
+        }
+
+        #[cfg(not(feature = "production"))]
+        prev(info);
+    }));
+}
@@ -92,4 +92,6 @@ pub enum OcallReturn {
     Failure,
     /// A panic happened during the ocall.
     Panic,
+    /// Submitted work hasn't finished yet, poll again later.
+    Pending,
 }